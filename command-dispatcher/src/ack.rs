@@ -0,0 +1,272 @@
+use crate::events::{self, EventDetail};
+use crate::permission::intended_target;
+use crate::policy::{AckEvidence, CommandPolicies, CommandPolicy};
+use crate::registry;
+use chrono::Utc;
+use libsql::{params as libsql_params, Connection};
+use messages_prost::command as cmd;
+use tracing::{info, warn};
+
+// A row in 'Sent' waiting for evidence of receipt
+#[derive(Debug)]
+struct SentCommandRow {
+    id: i64,
+    command_type: String,
+    parameters: Option<String>,
+    sent_at: i64,
+    attempts: u32,
+}
+
+/// Moves 'Sent' commands to 'Acknowledged' when the ingestor has stored evidence that a
+/// node received them, and to 'TimedOut' once their acknowledgement window has passed. A
+/// timed out command goes back to 'Pending' for a retransmission instead when its policy
+/// allows resending and attempts remain.
+pub async fn check_acknowledgements(
+    db_conn: &Connection,
+    policies: &CommandPolicies,
+) -> Result<(), libsql::Error> {
+    let mut rows = db_conn
+        .query(
+            "SELECT id, command_type, parameters, sent_at, attempts FROM OutgoingCommand WHERE status = 'Sent' AND sent_at IS NOT NULL",
+            libsql_params![],
+        )
+        .await?;
+    let mut sent = Vec::new();
+    while let Some(row) = rows.next().await? {
+        sent.push(SentCommandRow {
+            id: row.get(0)?,
+            command_type: row.get(1)?,
+            parameters: row.get(2)?,
+            sent_at: row.get(3)?,
+            attempts: row.get(4)?,
+        });
+    }
+
+    let now = Utc::now().timestamp();
+    for command in sent {
+        let policy = policies.get(&command.command_type);
+        if policy.ack == AckEvidence::None {
+            continue;
+        }
+
+        if has_evidence(db_conn, &command, policy).await? {
+            info!(
+                "[Cmd ID: {}] Acknowledged by node. Updating status to 'Acknowledged'.",
+                command.id
+            );
+            let updated = db_conn
+                .execute(
+                    "UPDATE OutgoingCommand SET status = 'Acknowledged', acknowledged_at = ? WHERE id = ? AND status = 'Sent'",
+                    libsql_params![now, command.id],
+                )
+                .await?;
+            if updated > 0 {
+                events::record(
+                    db_conn,
+                    command.id,
+                    "Sent",
+                    "Acknowledged",
+                    EventDetail::default(),
+                )
+                .await;
+            }
+            continue;
+        }
+
+        if now - command.sent_at < policy.ack_timeout_secs {
+            continue;
+        }
+
+        if policy.resend_on_recovery && command.attempts < policy.max_attempts {
+            let err_msg = format!(
+                "No acknowledgement within {}s (attempt {}/{}); retransmitting",
+                policy.ack_timeout_secs, command.attempts, policy.max_attempts
+            );
+            warn!("[Cmd ID: {}] {}", command.id, err_msg);
+            let updated = db_conn
+                .execute(
+                    "UPDATE OutgoingCommand SET status = 'Pending', error_message = ? WHERE id = ? AND status = 'Sent'",
+                    libsql_params![err_msg.clone(), command.id],
+                )
+                .await?;
+            if updated > 0 {
+                events::record(
+                    db_conn,
+                    command.id,
+                    "Sent",
+                    "Pending",
+                    EventDetail {
+                        error: Some(&err_msg),
+                        ..EventDetail::default()
+                    },
+                )
+                .await;
+            }
+        } else {
+            let err_msg = format!(
+                "No acknowledgement within {}s after {} attempt(s)",
+                policy.ack_timeout_secs, command.attempts
+            );
+            warn!("[Cmd ID: {}] {}", command.id, err_msg);
+            let updated = db_conn
+                .execute(
+                    "UPDATE OutgoingCommand SET status = 'TimedOut', error_message = ? WHERE id = ? AND status = 'Sent'",
+                    libsql_params![err_msg.clone(), command.id],
+                )
+                .await?;
+            if updated > 0 {
+                events::record(
+                    db_conn,
+                    command.id,
+                    "Sent",
+                    "TimedOut",
+                    EventDetail {
+                        error: Some(&err_msg),
+                        ..EventDetail::default()
+                    },
+                )
+                .await;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn has_evidence(
+    db_conn: &Connection,
+    command: &SentCommandRow,
+    policy: &CommandPolicy,
+) -> Result<bool, libsql::Error> {
+    // Pongs, echoes and events only count from the node the command was addressed to, spelled as
+    // the ingestor stores it in RadioFrame.node
+    let target = intended_target(&command.command_type, command.parameters.as_deref(), policy)
+        .map(|node| format!("{:?}", node));
+    match policy.ack {
+        AckEvidence::None => Ok(false),
+        AckEvidence::Pong => {
+            let Ok(registry::ValidatedCommand {
+                data: cmd::command::Data::Ping(ping),
+                ..
+            }) = registry::validate(&command.command_type, command.parameters.as_deref())
+            else {
+                return Ok(false);
+            };
+            let Some(target) = target else {
+                return Ok(false);
+            };
+            exists(
+                db_conn,
+                "SELECT 1 FROM Pong p \
+                 JOIN Command c ON c.data_type = 'Pong' AND c.data_id = p.id \
+                 JOIN RadioFrame rf ON rf.data_type = 'Command' AND rf.data_id = c.id \
+                 WHERE p.pong_id = ? AND rf.node = ? AND rf.timestamp_epoch >= ? LIMIT 1",
+                libsql_params![ping.id, target, command.sent_at],
+            )
+            .await
+        }
+        AckEvidence::Echo | AckEvidence::EchoOrEvent => {
+            let Some(target) = target else {
+                return Ok(false);
+            };
+            if echoed(db_conn, command, &target).await? {
+                return Ok(true);
+            }
+            if policy.ack != AckEvidence::EchoOrEvent {
+                return Ok(false);
+            }
+            for event in &policy.ack_events {
+                if exists(
+                    db_conn,
+                    "SELECT 1 FROM RadioFrame rf \
+                     JOIN PhoenixEvent pe ON rf.data_type = 'PhoenixEvent' AND rf.data_id = pe.id \
+                     WHERE rf.node = ? AND LOWER(pe.event) = LOWER(?) AND rf.timestamp_epoch >= ? LIMIT 1",
+                    libsql_params![target.clone(), event.clone(), command.sent_at],
+                )
+                .await?
+                {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }
+    }
+}
+
+// Our own uplink frames are tagged GroundStation, so they never count as an echo.
+async fn echoed(
+    db_conn: &Connection,
+    command: &SentCommandRow,
+    target: &str,
+) -> Result<bool, libsql::Error> {
+    exists(
+        db_conn,
+        "SELECT 1 FROM RadioFrame rf \
+         JOIN Command c ON rf.data_type = 'Command' AND rf.data_id = c.id \
+         WHERE c.data_type = ? AND rf.node = ? AND rf.node != 'GroundStation' AND rf.timestamp_epoch >= ? LIMIT 1",
+        libsql_params![command.command_type.clone(), target, command.sent_at],
+    )
+    .await
+}
+
+async fn exists(
+    db_conn: &Connection,
+    sql: &str,
+    params: impl libsql::params::IntoParams,
+) -> Result<bool, libsql::Error> {
+    let mut rows = db_conn.query(sql, params).await?;
+    Ok(rows.next().await?.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::CommandPolicies;
+    use crate::testing::database;
+
+    async fn pong(db_conn: &Connection, id: u32, node: &str, at: i64) {
+        db_conn
+            .execute("INSERT INTO Pong (pong_id) VALUES (?)", libsql_params![id])
+            .await
+            .unwrap();
+        db_conn
+            .execute(
+                "INSERT INTO Command (data_type, data_id) VALUES ('Pong', ?)",
+                libsql_params![db_conn.last_insert_rowid()],
+            )
+            .await
+            .unwrap();
+        db_conn
+            .execute(
+                "INSERT INTO RadioFrame (timestamp, timestamp_epoch, node, data_type, data_id) \
+                 VALUES ('', ?, ?, 'Command', ?)",
+                libsql_params![at, node, db_conn.last_insert_rowid()],
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn takes_only_a_pong_from_the_pinged_node() {
+        let db_conn = database().await;
+        let policies = CommandPolicies::default();
+        let ping = SentCommandRow {
+            id: 1,
+            command_type: "Ping".to_string(),
+            parameters: Some(r#"{"id": 7, "target": "Phoenix"}"#.to_string()),
+            sent_at: 100,
+            attempts: 1,
+        };
+
+        pong(&db_conn, 7, "PressureBoard", 101).await;
+        pong(&db_conn, 8, "Phoenix", 101).await;
+        pong(&db_conn, 7, "Phoenix", 99).await;
+        assert!(!has_evidence(&db_conn, &ping, policies.get("Ping"))
+            .await
+            .unwrap());
+
+        pong(&db_conn, 7, "Phoenix", 101).await;
+        assert!(has_evidence(&db_conn, &ping, policies.get("Ping"))
+            .await
+            .unwrap());
+    }
+}
@@ -0,0 +1,440 @@
+use crate::commands::{resolve_target, OutgoingCommandRow};
+use crate::events::{self, CommandEventRow};
+use crate::interlock::ConfirmationKey;
+use crate::permission::CommandPermissions;
+use crate::policy::{AckEvidence, CommandPolicies};
+use crate::registry;
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::Utc;
+use libsql::{params as libsql_params, Connection};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path as FilePath;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tracing::{error, info};
+
+/// `source_service` of every command submitted through the API.
+const API_SOURCE: &str = "command-dispatcher-api";
+
+/// Longest a long-poll request is held open.
+const MAX_WAIT_SECS: u64 = 60;
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Statuses a command never leaves. 'Sent' is final too for types that expect no
+/// acknowledgement.
+const FINAL_STATUSES: [&str; 7] = [
+    "Acknowledged",
+    "TimedOut",
+    "Failed",
+    "Blocked",
+    "Rejected",
+    "Expired",
+    "Cancelled",
+];
+
+/// Highest and lowest `priority` a command queued through the API may carry. The built-in
+/// policies stay within it, so a client cannot jump ahead of every deployment.
+const PRIORITY_RANGE: std::ops::RangeInclusive<i64> = -100..=100;
+
+/// Operators known to the API, by the bearer token they authenticate with.
+#[derive(Debug, Default)]
+pub struct ApiOperators {
+    by_token: HashMap<String, String>,
+    /// Queue commands sent without an Authorization header, with no operator.
+    allow_anonymous: bool,
+}
+
+impl ApiOperators {
+    /// Loads a JSON file mapping tokens to operator names, e.g. `{"9f2c...": "alice"}`.
+    /// Without a file, only anonymous requests can be accepted, if `allow_anonymous` is set.
+    pub fn load(
+        path: Option<&FilePath>,
+        allow_anonymous: bool,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let Some(path) = path else {
+            return Ok(Self {
+                by_token: HashMap::new(),
+                allow_anonymous,
+            });
+        };
+        let by_token: HashMap<String, String> =
+            serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        if let Some((_, operator)) = by_token
+            .iter()
+            .find(|(token, operator)| token.is_empty() || operator.is_empty())
+        {
+            return Err(format!(
+                "Empty token or operator name in {} (operator {:?})",
+                path.display(),
+                operator
+            )
+            .into());
+        }
+        info!(
+            "Loaded {} command API operator(s) from {}",
+            by_token.len(),
+            path.display()
+        );
+        Ok(Self {
+            by_token,
+            allow_anonymous,
+        })
+    }
+
+    // The operator a request authenticates as; `None` without an Authorization header
+    fn authenticate(&self, headers: &HeaderMap) -> Result<Option<String>, ApiError> {
+        let Some(value) = headers.get(header::AUTHORIZATION) else {
+            return Ok(None);
+        };
+        value
+            .to_str()
+            .ok()
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .and_then(|token| self.by_token.get(token.trim()))
+            .map(|operator| Some(operator.clone()))
+            .ok_or(ApiError::Unauthorized)
+    }
+}
+
+#[derive(Clone)]
+struct ApiState {
+    db_conn: Connection,
+    policies: Arc<CommandPolicies>,
+    permissions: Arc<CommandPermissions>,
+    operators: Arc<ApiOperators>,
+    trigger: Arc<Notify>,
+    confirmation_key: ConfirmationKey,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct SubmitCommand {
+    command_type: String,
+    /// Same object as the `parameters` column, e.g. `{"rate": "low"}`.
+    #[serde(default)]
+    parameters: Option<serde_json::Value>,
+    #[serde(default)]
+    priority: Option<i64>,
+    /// Seconds from now after which the command expires unsent.
+    #[serde(default)]
+    expires_in_secs: Option<i64>,
+}
+
+#[derive(Serialize, Debug)]
+struct CommandStatus {
+    id: i64,
+    command_type: String,
+    status: String,
+    /// The status will not change any more.
+    #[serde(rename = "final")]
+    is_final: bool,
+    attempts: i64,
+    error_message: Option<String>,
+    created_at: i64,
+    sent_at: Option<i64>,
+    acknowledged_at: Option<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct EventsQuery {
+    /// Only events with a larger `CommandEvent.id` are returned.
+    #[serde(default)]
+    after: i64,
+    /// Hold the request until there is a newer event, the command is final, or this many
+    /// seconds pass. 0 answers at once.
+    #[serde(default)]
+    wait_secs: u64,
+}
+
+#[derive(Serialize, Debug)]
+struct EventsResponse {
+    command: CommandStatus,
+    events: Vec<CommandEventRow>,
+}
+
+enum ApiError {
+    Invalid(String),
+    Unauthorized,
+    Forbidden(String),
+    NotFound(i64),
+    Database(libsql::Error),
+}
+
+impl From<libsql::Error> for ApiError {
+    fn from(e: libsql::Error) -> Self {
+        ApiError::Database(e)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            ApiError::Invalid(message) => (StatusCode::UNPROCESSABLE_ENTITY, message),
+            ApiError::Unauthorized => (
+                StatusCode::UNAUTHORIZED,
+                "Missing, unknown or malformed bearer token".to_string(),
+            ),
+            ApiError::Forbidden(message) => (StatusCode::FORBIDDEN, message),
+            ApiError::NotFound(id) => (StatusCode::NOT_FOUND, format!("No command {}", id)),
+            ApiError::Database(e) => {
+                error!("Command API database error: {:?}", e);
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Database unavailable".to_string(),
+                )
+            }
+        };
+        (status, Json(serde_json::json!({ "error": message }))).into_response()
+    }
+}
+
+/// Serves the command API on `addr`: `POST /commands` validates and queues a command,
+/// `POST /commands/:id/confirm` confirms it as the second operator, `GET /commands/:id`
+/// returns its status and `GET /commands/:id/events` long-polls its status transitions.
+pub async fn run_api_server(
+    addr: SocketAddr,
+    db_conn: Connection,
+    policies: Arc<CommandPolicies>,
+    permissions: Arc<CommandPermissions>,
+    operators: ApiOperators,
+    trigger: Arc<Notify>,
+    confirmation_key: ConfirmationKey,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let state = ApiState {
+        db_conn,
+        policies,
+        permissions,
+        operators: Arc::new(operators),
+        trigger,
+        confirmation_key,
+    };
+    let app = Router::new()
+        .route("/commands", post(submit_command))
+        .route("/commands/:id", get(get_command))
+        .route("/commands/:id/confirm", post(confirm_command))
+        .route("/commands/:id/events", get(get_command_events))
+        .with_state(state);
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("Command API listening on http://{}/commands", addr);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+// Runs the same parameter, target and permission checks as the dispatcher before queueing,
+// so a bad command is refused here instead of ending up 'Failed' or 'Rejected'. The source
+// is always the API and the operator is the one the bearer token belongs to, so a client
+// cannot pass as another service or operator.
+async fn submit_command(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(request): Json<SubmitCommand>,
+) -> Result<(StatusCode, Json<CommandStatus>), ApiError> {
+    let operator = state.operators.authenticate(&headers)?;
+    if operator.is_none() && !state.operators.allow_anonymous {
+        return Err(ApiError::Unauthorized);
+    }
+    if let Some(priority) = request.priority.filter(|p| !PRIORITY_RANGE.contains(p)) {
+        return Err(ApiError::Invalid(format!(
+            "priority {} is outside {}..={}",
+            priority,
+            PRIORITY_RANGE.start(),
+            PRIORITY_RANGE.end()
+        )));
+    }
+    if let Some(secs) = request.expires_in_secs.filter(|secs| *secs <= 0) {
+        return Err(ApiError::Invalid(format!(
+            "expires_in_secs must be positive, got {}",
+            secs
+        )));
+    }
+    let parameters = match &request.parameters {
+        None | Some(serde_json::Value::Null) => None,
+        Some(value) => Some(value.to_string()),
+    };
+    let validated = registry::validate(&request.command_type, parameters.as_deref())
+        .map_err(|e| ApiError::Invalid(e.to_string()))?;
+    let policy = state.policies.get(&request.command_type);
+    let target = resolve_target(
+        validated.target,
+        policy.default_target.as_deref(),
+        validated.implied_target,
+    )
+    .map_err(ApiError::Invalid)?;
+
+    let candidate = OutgoingCommandRow {
+        id: 0,
+        command_type: request.command_type.clone(),
+        parameters: parameters.clone(),
+        source_service: API_SOURCE.to_string(),
+        operator: operator.clone(),
+        confirmation_token: None,
+    };
+    state
+        .permissions
+        .check(&candidate, Some(target))
+        .map_err(ApiError::Forbidden)?;
+
+    let now = Utc::now().timestamp();
+    let mut rows = state
+        .db_conn
+        .query(
+            "INSERT INTO OutgoingCommand (command_type, parameters, status, created_at, attempts, source_service, operator, priority, expires_at) \
+             VALUES (?, ?, 'Pending', ?, 0, ?, ?, ?, ?) RETURNING id",
+            libsql_params![
+                request.command_type,
+                parameters,
+                now,
+                API_SOURCE,
+                operator,
+                request.priority,
+                request.expires_in_secs.map(|secs| now.saturating_add(secs))
+            ],
+        )
+        .await?;
+    let id: i64 = match rows.next().await? {
+        Some(row) => row.get(0)?,
+        None => return Err(ApiError::Database(libsql::Error::QueryReturnedNoRows)),
+    };
+    drop(rows);
+    info!(
+        "[Cmd ID: {}] Queued {} for {:?} via the command API",
+        id, candidate.command_type, target
+    );
+    state.trigger.notify_one();
+
+    let status = command_status(&state.db_conn, &state.policies, id)
+        .await?
+        .ok_or(ApiError::NotFound(id))?;
+    Ok((StatusCode::CREATED, Json(status)))
+}
+
+// Records the authenticated operator's confirmation of a command awaiting one. The dispatcher
+// checks the signature, so a confirmation inserted into the table directly does not count.
+async fn confirm_command(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+) -> Result<(StatusCode, Json<CommandStatus>), ApiError> {
+    let Some(operator) = state.operators.authenticate(&headers)? else {
+        return Err(ApiError::Unauthorized);
+    };
+    let mut rows = state
+        .db_conn
+        .query(
+            "SELECT status, operator, confirmation_token FROM OutgoingCommand WHERE id = ?",
+            libsql_params![id],
+        )
+        .await?;
+    let Some(row) = rows.next().await? else {
+        return Err(ApiError::NotFound(id));
+    };
+    let status: String = row.get(0)?;
+    let requested_by: Option<String> = row.get(1)?;
+    let request_token: Option<String> = row.get(2)?;
+    drop(rows);
+    let Some(request_token) = request_token.filter(|_| status == "AwaitingConfirmation") else {
+        return Err(ApiError::Invalid(format!(
+            "Command {} is {}, not awaiting confirmation",
+            id, status
+        )));
+    };
+    if requested_by.as_deref() == Some(operator.as_str()) {
+        return Err(ApiError::Forbidden(
+            "A command must be confirmed by a different operator than the one who queued it"
+                .to_string(),
+        ));
+    }
+
+    state
+        .db_conn
+        .execute(
+            "INSERT INTO CommandConfirmation (command_id, token, operator, created_at) VALUES (?, ?, ?, ?)",
+            libsql_params![
+                id,
+                state.confirmation_key.sign(id, &request_token, &operator),
+                operator.clone(),
+                Utc::now().timestamp()
+            ],
+        )
+        .await?;
+    info!(
+        "[Cmd ID: {}] Confirmed by {} via the command API",
+        id, operator
+    );
+    state.trigger.notify_one();
+
+    let status = command_status(&state.db_conn, &state.policies, id)
+        .await?
+        .ok_or(ApiError::NotFound(id))?;
+    Ok((StatusCode::ACCEPTED, Json(status)))
+}
+
+async fn get_command(
+    State(state): State<ApiState>,
+    Path(id): Path<i64>,
+) -> Result<Json<CommandStatus>, ApiError> {
+    command_status(&state.db_conn, &state.policies, id)
+        .await?
+        .map(Json)
+        .ok_or(ApiError::NotFound(id))
+}
+
+async fn get_command_events(
+    State(state): State<ApiState>,
+    Path(id): Path<i64>,
+    Query(query): Query<EventsQuery>,
+) -> Result<Json<EventsResponse>, ApiError> {
+    let deadline =
+        tokio::time::Instant::now() + Duration::from_secs(query.wait_secs.min(MAX_WAIT_SECS));
+    loop {
+        let command = command_status(&state.db_conn, &state.policies, id)
+            .await?
+            .ok_or(ApiError::NotFound(id))?;
+        let events = events::command_history(&state.db_conn, id, query.after).await?;
+        if !events.is_empty() || command.is_final || tokio::time::Instant::now() >= deadline {
+            return Ok(Json(EventsResponse { command, events }));
+        }
+        tokio::time::sleep(WAIT_POLL_INTERVAL).await;
+    }
+}
+
+async fn command_status(
+    db_conn: &Connection,
+    policies: &CommandPolicies,
+    id: i64,
+) -> Result<Option<CommandStatus>, libsql::Error> {
+    let mut rows = db_conn
+        .query(
+            "SELECT id, command_type, status, attempts, error_message, created_at, sent_at, acknowledged_at \
+             FROM OutgoingCommand WHERE id = ?",
+            libsql_params![id],
+        )
+        .await?;
+    let Some(row) = rows.next().await? else {
+        return Ok(None);
+    };
+    let command_type: String = row.get(1)?;
+    let status: String = row.get(2)?;
+    let is_final = FINAL_STATUSES.contains(&status.as_str())
+        || (status == "Sent" && policies.get(&command_type).ack == AckEvidence::None);
+    Ok(Some(CommandStatus {
+        id: row.get(0)?,
+        command_type,
+        is_final,
+        status,
+        attempts: row.get(3)?,
+        error_message: row.get(4)?,
+        created_at: row.get(5)?,
+        sent_at: row.get(6)?,
+        acknowledged_at: row.get(7)?,
+    }))
+}
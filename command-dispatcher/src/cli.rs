@@ -1,4 +1,6 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use std::net::SocketAddr;
+use std::path::PathBuf;
 
 #[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
@@ -12,10 +14,132 @@ pub struct Args {
     #[arg(
         long,
         default_value = "tcpout:127.0.0.1:5656",
-        help = "Gateway MAVLink connection string (e.g., tcpout:localhost:5656, udpin:0.0.0.0:14550)"
+        value_delimiter = ',',
+        help = "Gateway MAVLink connection strings in order of preference (e.g., tcpout:localhost:5656,tcpout:backup:5656)"
     )]
-    pub gateway_connection_string: String,
+    pub gateway_connection_string: Vec<String>,
+
+    #[arg(
+        long,
+        default_value_t = 30,
+        help = "Seconds between attempts to move back to a more preferred gateway (0 disables)"
+    )]
+    pub gateway_failback_secs: u64,
+
+    #[arg(
+        long,
+        default_value_t = 255,
+        help = "MAVLink system id in the header of every uplink frame"
+    )]
+    pub mavlink_system_id: u8,
+
+    #[arg(
+        long,
+        default_value_t = 190,
+        help = "MAVLink component id in the header of every uplink frame"
+    )]
+    pub mavlink_component_id: u8,
 
     #[arg(long, default_value_t = 5)]
     pub poll_interval_secs: u64,
+
+    #[arg(
+        long,
+        default_value_t = 250,
+        help = "Interval of the cheap check for 'Pending' commands between full polls (0 disables)"
+    )]
+    pub fast_poll_interval_ms: u64,
+
+    #[arg(
+        long,
+        help = "Address for the local HTTP endpoint (POST /trigger) that wakes the dispatcher, e.g. 127.0.0.1:5657"
+    )]
+    pub trigger_listen_addr: Option<SocketAddr>,
+
+    #[arg(
+        long,
+        help = "Address for the HTTP command API (POST /commands, GET /commands/:id), e.g. 127.0.0.1:5658"
+    )]
+    pub api_listen_addr: Option<SocketAddr>,
+
+    #[arg(
+        long,
+        help = "JSON file mapping command API bearer tokens to operator names, e.g. {\"9f2c...\": \"alice\"}"
+    )]
+    pub api_operators_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Accept command API submissions without a bearer token, queued with no operator"
+    )]
+    pub api_allow_anonymous: bool,
+
+    #[arg(
+        long,
+        help = "JSON file overriding per-command-type retry and acknowledgement policies"
+    )]
+    pub command_policy_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "JSON file of rules mapping source services and operators to the command types and targets they may send"
+    )]
+    pub command_permissions_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        default_value_t = 900,
+        help = "Seconds after which the SystemConfig 'command_armed' switch must be set again"
+    )]
+    pub arm_timeout_secs: i64,
+
+    #[arg(
+        long,
+        help = "Hold uplink frames for gaps between downlink bursts, learned from the gateway connection"
+    )]
+    pub half_duplex: bool,
+
+    #[arg(
+        long,
+        help = "Queue RadioRateChange commands automatically from RadioMetrics link quality"
+    )]
+    pub rate_adaptation: bool,
+
+    #[arg(long, help = "JSON file overriding the rate adaptation thresholds")]
+    pub rate_adaptation_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "Nodes the link monitor pings to measure round-trip time, loss and jitter (e.g. phoenix,pressure_board)"
+    )]
+    pub link_monitor_nodes: Vec<String>,
+
+    #[arg(long, default_value_t = 5, help = "Seconds between link monitor Pings")]
+    pub link_monitor_interval_secs: u64,
+
+    #[arg(
+        long,
+        default_value_t = 10,
+        help = "Seconds after sending before a Ping without a Pong counts as lost"
+    )]
+    pub link_monitor_timeout_secs: u64,
+
+    #[arg(
+        long,
+        default_value_t = 20,
+        help = "Number of recent pings per node the link statistics are computed over"
+    )]
+    pub link_monitor_window: i64,
+
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum CliCommand {
+    /// Print the JSON Schema of every command type's parameters and exit
+    Schema,
+    /// Print every recorded transition of a command from CommandEvent and exit
+    History { command_id: i64 },
 }
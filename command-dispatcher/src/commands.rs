@@ -1,15 +1,22 @@
+use crate::error::DispatchError;
+use crate::events::{self, EventDetail};
+use crate::gateway::GatewayLink;
+use crate::policy::CommandPolicies;
+use crate::registry::{self, parse_node};
+use crate::transmit::TransmitWindow;
 use chrono::Utc;
 use libsql::{params as libsql_params, Connection};
-use mavlink::{uorocketry::MavMessage, MavConnection, MavHeader};
+use mavlink::uorocketry::MavMessage;
 use messages_prost::command as cmd;
 use messages_prost::common::Node;
 use messages_prost::radio::radio_frame::Payload;
 use messages_prost::radio::RadioFrame;
 use prost::Message as _;
-use serde::Deserialize;
-use serde_json;
-use std::time::Instant;
-use tracing::{error, info};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+/// Size of the fixed `message` buffer in a MAVLink `POSTCARD_MESSAGE`.
+const POSTCARD_PAYLOAD_LEN: usize = 255;
 
 // Struct to represent a row from the OutgoingCommand table
 #[derive(Debug)]
@@ -18,179 +25,209 @@ pub struct OutgoingCommandRow {
     pub command_type: String,
     pub parameters: Option<String>,
     pub source_service: String,
+    pub operator: Option<String>,
+    pub confirmation_token: Option<String>,
 }
 
-// Parameter structs for deserialization
-#[derive(Deserialize, Debug)]
-struct DeployDrogueParams {
-    val: bool,
-}
-#[derive(Deserialize, Debug)]
-struct DeployMainParams {
-    val: bool,
-}
-#[derive(Deserialize, Debug)]
-struct PowerDownParams {
-    board: String,
-}
-#[derive(Deserialize, Debug)]
-struct RadioRateChangeParams {
-    rate: String,
-}
-#[derive(Deserialize, Debug)]
-struct PingParams {
-    id: u32,
-}
-
-fn parse_board(s: &str) -> Node {
-    match s {
-        "PressureBoard" => Node::PressureBoard,
-        "StrainBoard" => Node::StrainBoard,
-        "TemperatureBoard" => Node::TemperatureBoard,
-        "GroundStation" => Node::GroundStation,
-        "Phoenix" => Node::Phoenix,
-        _ => Node::Unspecified,
+/// Picks the node a command is addressed to: the `target` parameter if present, else the
+/// command type's configured default, else `fallback` (e.g. the board of a PowerDown).
+pub fn resolve_target(
+    requested: Option<Node>,
+    default_target: Option<&str>,
+    fallback: Option<Node>,
+) -> Result<Node, String> {
+    let node = match (requested, default_target) {
+        (Some(node), _) => node,
+        (None, Some(name)) => {
+            parse_node(name).ok_or_else(|| format!("Unknown default target node: {}", name))?
+        }
+        (None, None) => fallback.unwrap_or(Node::Unspecified),
+    };
+    if node == Node::Unspecified {
+        return Err("Target node resolves to Unspecified; refusing to send".to_string());
     }
+    Ok(node)
 }
 
-fn parse_radio_rate(s: &str) -> i32 {
-    match s.to_lowercase().as_str() {
-        "low" | "slow" | "0" => cmd::RadioRate::RateLow as i32,
-        "medium" | "1" => cmd::RadioRate::RateMedium as i32,
-        "high" | "fast" | "2" => cmd::RadioRate::RateHigh as i32,
-        _ => cmd::RadioRate::RateLow as i32,
-    }
+/// Wraps `command` in a ground station `RadioFrame`, length-delimited as the ingestor
+/// decodes POSTCARD payloads.
+pub fn encode_frame(command: cmd::Command, millis_since_start: u64) -> Vec<u8> {
+    let frame = RadioFrame {
+        node: Node::GroundStation as i32,
+        payload: Some(Payload::Command(command)),
+        millis_since_start,
+    };
+    RadioFrame::encode_length_delimited_to_vec(&frame)
 }
 
 pub async fn process_single_command(
     db_conn: &Connection,
-    gateway_conn: &mut Box<dyn MavConnection<MavMessage> + Sync + Send>,
+    gateway: &GatewayLink,
     command_row: OutgoingCommandRow,
+    policies: &CommandPolicies,
     start_instant: Instant,
-) -> Result<(), (i64, Box<dyn std::error::Error>)> {
+) -> Result<(), (i64, DispatchError)> {
     let cmd_id = command_row.id;
     info!(
-        "[Cmd ID: {}] Processing command of type '{}' from '{}'. Params: {:?}",
-        cmd_id, command_row.command_type, command_row.source_service, command_row.parameters
+        "[Cmd ID: {}] Processing command of type '{}' from '{}' (operator: {:?}). Params: {:?}",
+        cmd_id,
+        command_row.command_type,
+        command_row.source_service,
+        command_row.operator,
+        command_row.parameters
     );
 
+    // Claim the row only if it is still 'Pending' and in date, so an operator cancellation
+    // or expiry that landed after the fetch wins. A confirmation covers one send only: the
+    // token is cleared, so a retransmission has to be confirmed again.
     let queued_at_ts = Utc::now().timestamp();
-    db_conn.execute(
-        "UPDATE OutgoingCommand SET status = 'Sending', queued_at = ?, attempts = attempts + 1 WHERE id = ?",
-        libsql_params![queued_at_ts, cmd_id],
+    let mut claimed = db_conn.query(
+        "UPDATE OutgoingCommand SET status = 'Sending', queued_at = ?, attempts = attempts + 1, confirmation_token = NULL \
+         WHERE id = ? AND status = 'Pending' AND (expires_at IS NULL OR expires_at > ?) RETURNING attempts",
+        libsql_params![queued_at_ts, cmd_id, queued_at_ts],
     ).await.map_err(|e| (cmd_id, e.into()))?;
+    let attempts: u32 = match claimed.next().await.map_err(|e| (cmd_id, e.into()))? {
+        Some(row) => row.get(0).map_err(|e| (cmd_id, e.into()))?,
+        None => {
+            info!(
+                "[Cmd ID: {}] No longer 'Pending' (cancelled or expired). Skipping.",
+                cmd_id
+            );
+            return Ok(());
+        }
+    };
+    drop(claimed);
     info!("[Cmd ID: {}] Marked as 'Sending'.", cmd_id);
+    events::record(
+        db_conn,
+        cmd_id,
+        "Pending",
+        "Sending",
+        EventDetail::default(),
+    )
+    .await;
 
-    let default_target = Node::PressureBoard as i32;
-
-    let command_payload: cmd::Command = match command_row.command_type.as_str() {
-        "DeployDrogue" => {
-            let params: DeployDrogueParams =
-                serde_json::from_str(command_row.parameters.as_deref().unwrap_or("{}"))
-                    .map_err(|e| (cmd_id, Box::new(e) as Box<dyn std::error::Error>))?;
-            cmd::Command {
-                node: default_target,
-                data: Some(cmd::command::Data::DeployDrogue(cmd::DeployDrogue {
-                    val: params.val,
-                })),
-            }
-        }
-        "DeployMain" => {
-            let params: DeployMainParams =
-                serde_json::from_str(command_row.parameters.as_deref().unwrap_or("{}"))
-                    .map_err(|e| (cmd_id, Box::new(e) as Box<dyn std::error::Error>))?;
-            cmd::Command {
-                node: default_target,
-                data: Some(cmd::command::Data::DeployMain(cmd::DeployMain {
-                    val: params.val,
-                })),
-            }
-        }
-        "PowerDown" => {
-            let params_str = command_row.parameters.as_deref().ok_or_else(|| {
-                (
-                    cmd_id,
-                    Box::new(std::io::Error::new(
-                        std::io::ErrorKind::InvalidInput,
-                        "Missing parameters for PowerDown",
-                    )) as Box<dyn std::error::Error>,
-                )
-            })?;
-            let params: PowerDownParams = serde_json::from_str(params_str)
-                .map_err(|e| (cmd_id, Box::new(e) as Box<dyn std::error::Error>))?;
-            let board = parse_board(&params.board) as i32;
-            cmd::Command {
-                node: board,
-                data: Some(cmd::command::Data::PowerDown(cmd::PowerDown { board })),
-            }
-        }
-        "PowerUpCamera" => cmd::Command {
-            node: default_target,
-            data: Some(cmd::command::Data::PowerUpCamera(cmd::PowerUpCamera {})),
-        },
-        "PowerDownCamera" => cmd::Command {
-            node: default_target,
-            data: Some(cmd::command::Data::PowerDownCamera(cmd::PowerDownCamera {})),
-        },
-        "RadioRateChange" => {
-            let params_str = command_row.parameters.as_deref().ok_or_else(|| {
-                (
-                    cmd_id,
-                    Box::new(std::io::Error::new(
-                        std::io::ErrorKind::InvalidInput,
-                        "Missing parameters for RadioRateChange",
-                    )) as Box<dyn std::error::Error>,
-                )
-            })?;
-            let params: RadioRateChangeParams = serde_json::from_str(params_str)
-                .map_err(|e| (cmd_id, Box::new(e) as Box<dyn std::error::Error>))?;
-            cmd::Command {
-                node: default_target,
-                data: Some(cmd::command::Data::RadioRateChange(cmd::RadioRateChange {
-                    rate: parse_radio_rate(&params.rate),
-                })),
-            }
-        }
-        "Ping" => {
-            let id = command_row
-                .parameters
-                .as_deref()
-                .and_then(|s| serde_json::from_str::<PingParams>(s).ok())
-                .map(|p| p.id)
-                .unwrap_or(0);
-            cmd::Command {
-                node: default_target,
-                data: Some(cmd::command::Data::Ping(cmd::Ping { id })),
-            }
-        }
-        _ => {
-            let err_msg = format!("Unknown command type: {}", command_row.command_type);
+    let validated = match registry::validate(
+        &command_row.command_type,
+        command_row.parameters.as_deref(),
+    ) {
+        Ok(validated) => validated,
+        Err(validation_err) => {
+            let err_msg = validation_err.to_string();
             error!("[Cmd ID: {}] {}", cmd_id, err_msg);
             db_conn.execute(
                 "UPDATE OutgoingCommand SET status = 'Failed', sent_at = ?, error_message = ? WHERE id = ?",
                 libsql_params![Utc::now().timestamp(), err_msg.clone(), cmd_id],
             ).await.map_err(|e| (cmd_id, e.into()))?;
-            return Err((cmd_id, err_msg.into()));
+            events::record(
+                db_conn,
+                cmd_id,
+                "Sending",
+                "Failed",
+                EventDetail {
+                    error: Some(&err_msg),
+                    ..EventDetail::default()
+                },
+            )
+            .await;
+            return Err((cmd_id, DispatchError::Invalid(err_msg)));
         }
     };
 
-    let millis_since_start = start_instant.elapsed().as_millis() as u64;
-    let frame = RadioFrame {
-        node: Node::GroundStation as i32,
-        payload: Some(Payload::Command(command_payload)),
-        millis_since_start,
+    let policy = policies.get(&command_row.command_type);
+    let target = match resolve_target(
+        validated.target,
+        policy.default_target.as_deref(),
+        validated.implied_target,
+    ) {
+        Ok(node) => node,
+        Err(err_msg) => {
+            error!("[Cmd ID: {}] {}", cmd_id, err_msg);
+            db_conn.execute(
+                "UPDATE OutgoingCommand SET status = 'Failed', sent_at = ?, error_message = ? WHERE id = ?",
+                libsql_params![Utc::now().timestamp(), err_msg.clone(), cmd_id],
+            ).await.map_err(|e| (cmd_id, e.into()))?;
+            events::record(
+                db_conn,
+                cmd_id,
+                "Sending",
+                "Failed",
+                EventDetail {
+                    error: Some(&err_msg),
+                    ..EventDetail::default()
+                },
+            )
+            .await;
+            return Err((cmd_id, DispatchError::Invalid(err_msg)));
+        }
+    };
+    info!("[Cmd ID: {}] Target node: {:?}", cmd_id, target);
+    let command_payload = cmd::Command {
+        node: target as i32,
+        data: Some(validated.data),
     };
-    let bytes = RadioFrame::encode_length_delimited_to_vec(&frame);
+    let bytes = encode_frame(command_payload, start_instant.elapsed().as_millis() as u64);
+
+    if bytes.len() > POSTCARD_PAYLOAD_LEN {
+        let err_msg = format!(
+            "Encoded RadioFrame is {} bytes, exceeds the {}-byte POSTCARD payload",
+            bytes.len(),
+            POSTCARD_PAYLOAD_LEN
+        );
+        error!("[Cmd ID: {}] {}", cmd_id, err_msg);
+        db_conn.execute(
+            "UPDATE OutgoingCommand SET status = 'Failed', sent_at = ?, error_message = ? WHERE id = ?",
+            libsql_params![Utc::now().timestamp(), err_msg.clone(), cmd_id],
+        ).await.map_err(|e| (cmd_id, e.into()))?;
+        events::record(
+            db_conn,
+            cmd_id,
+            "Sending",
+            "Failed",
+            EventDetail {
+                error: Some(&err_msg),
+                payload: Some(&bytes),
+                ..EventDetail::default()
+            },
+        )
+        .await;
+        return Err((cmd_id, DispatchError::Invalid(err_msg)));
+    }
 
-    let mut fixed_payload = [0u8; 255];
-    let len = bytes.len().min(255);
-    fixed_payload[..len].copy_from_slice(&bytes[..len]);
+    let mut fixed_payload = [0u8; POSTCARD_PAYLOAD_LEN];
+    fixed_payload[..bytes.len()].copy_from_slice(&bytes);
     let send_msg = MavMessage::POSTCARD_MESSAGE(mavlink::uorocketry::POSTCARD_MESSAGE_DATA {
         message: fixed_payload,
     });
 
-    info!("[Cmd ID: {}] Sending MAVLink message...", cmd_id);
-    match gateway_conn.send(&MavHeader::default(), &send_msg) {
+    let header = gateway.next_header();
+    let sent_detail = || EventDetail {
+        payload: Some(&bytes),
+        gateway_connection_id: Some(&gateway.id),
+        mavlink_sequence: Some(header.sequence),
+        ..EventDetail::default()
+    };
+    if let Some(scheduler) = &gateway.scheduler {
+        let deadline = Duration::from_millis(policy.max_transmit_delay_ms);
+        match scheduler.wait_for_window(deadline).await {
+            TransmitWindow::Clear { waited } if !waited.is_zero() => info!(
+                "[Cmd ID: {}] Held {}ms for a gap in the downlink.",
+                cmd_id,
+                waited.as_millis()
+            ),
+            TransmitWindow::Clear { .. } => {}
+            TransmitWindow::Deadline { waited } => warn!(
+                "[Cmd ID: {}] No downlink gap within {}ms; sending anyway.",
+                cmd_id,
+                waited.as_millis()
+            ),
+        }
+    }
+    info!(
+        "[Cmd ID: {}] Sending MAVLink message (sequence {})...",
+        cmd_id, header.sequence
+    );
+    match gateway.conn.send(&header, &send_msg) {
         Ok(_) => {
             info!(
                 "[Cmd ID: {}] Successfully sent. Updating status to 'Sent'.",
@@ -198,21 +235,55 @@ pub async fn process_single_command(
             );
             db_conn
                 .execute(
-                    "UPDATE OutgoingCommand SET status = 'Sent', sent_at = ? WHERE id = ?",
-                    libsql_params![Utc::now().timestamp(), cmd_id],
+                    "UPDATE OutgoingCommand SET status = 'Sent', sent_at = ?, mavlink_sequence = ? WHERE id = ?",
+                    libsql_params![Utc::now().timestamp(), i64::from(header.sequence), cmd_id],
                 )
                 .await
                 .map_err(|e| (cmd_id, e.into()))?;
+            events::record(db_conn, cmd_id, "Sending", "Sent", sent_detail()).await;
             Ok(())
         }
         Err(mav_err) => {
-            let err_msg = format!("Failed to send MAVLink message: {}", mav_err);
+            // The write failed, so the frame most likely never left; resend it only where
+            // the type allows a possible duplicate, as after a restart.
+            let (status, outcome) = if policy.resend_on_recovery && attempts < policy.max_attempts {
+                ("Pending", "re-queued")
+            } else {
+                ("Failed", "not resent")
+            };
+            let err_msg = format!(
+                "Failed to send MAVLink message (attempt {}/{}, {}): {}",
+                attempts, policy.max_attempts, outcome, mav_err
+            );
             error!("[Cmd ID: {}] {}", cmd_id, err_msg);
-            db_conn.execute(
-                "UPDATE OutgoingCommand SET status = 'Failed', sent_at = ?, error_message = ? WHERE id = ?",
-                libsql_params![Utc::now().timestamp(), err_msg.clone(), cmd_id],
-            ).await.map_err(|e_db| (cmd_id, e_db.into()))?;
-            Err((cmd_id, mav_err.into()))
+            // A database error here must not hide the transport failure; the row is then
+            // left in 'Sending' and reconciled when the gateway reconnects.
+            match db_conn
+                .execute(
+                    "UPDATE OutgoingCommand SET status = ?, sent_at = ?, error_message = ? WHERE id = ?",
+                    libsql_params![status, Utc::now().timestamp(), err_msg.clone(), cmd_id],
+                )
+                .await
+            {
+                Ok(_) => {
+                    events::record(
+                        db_conn,
+                        cmd_id,
+                        "Sending",
+                        status,
+                        EventDetail {
+                            error: Some(&err_msg),
+                            ..sent_detail()
+                        },
+                    )
+                    .await
+                }
+                Err(e_db) => error!(
+                    "[Cmd ID: {}] Failed to record the send failure: {:?}",
+                    cmd_id, e_db
+                ),
+            }
+            Err((cmd_id, DispatchError::Transport(mav_err)))
         }
     }
 }
@@ -1,102 +1,151 @@
+use crate::ack::check_acknowledgements;
 use crate::cli::Args;
-use crate::commands::{process_single_command, OutgoingCommandRow};
-use libsql::{params as libsql_params, Connection};
-use mavlink::{connect, uorocketry::MavMessage, MavConnection};
+use crate::commands::process_single_command;
+use crate::error::DispatchError;
+use crate::gateway::{self, GatewayLink};
+use crate::interlock::{self, ConfirmationKey, InterlockDecision};
+use crate::permission::{self, CommandPermissions};
+use crate::policy::CommandPolicies;
+use crate::queue::{expire_overdue_commands, fetch_pending};
+use crate::rate::RateController;
+use crate::recovery::recover_stuck_commands;
+use crate::sequence::advance_sequences;
+use crate::transmit::TransmitScheduler;
+use crate::trigger::wait_for_work;
+use libsql::Connection;
+use mavlink::{uorocketry::MavMessage, MavConnection};
+use std::collections::HashSet;
+use std::sync::atomic::AtomicU8;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
+use tokio::sync::Notify;
 use tracing::{error, info, warn};
 
+#[allow(clippy::too_many_arguments)]
 pub async fn run_dispatcher(
     db_conn: Connection,
     args: Args,
+    policies: Arc<CommandPolicies>,
+    permissions: Arc<CommandPermissions>,
+    mut rate_controller: Option<RateController>,
+    trigger: Arc<Notify>,
+    confirmation_key: ConfirmationKey,
     start_instant: Instant,
 ) -> Result<(), Box<dyn std::error::Error>> {
     info!(
-        "Dispatcher loop starting. Poll Interval: {}s. Gateway: {}",
-        args.poll_interval_secs, args.gateway_connection_string
+        "Dispatcher loop starting. Poll Interval: {}s (fast poll {}ms). Gateways in order of preference: {}",
+        args.poll_interval_secs,
+        args.fast_poll_interval_ms,
+        args.gateway_connection_string.join(", ")
     );
 
-    let mut gateway_conn_opt: Option<Box<dyn MavConnection<MavMessage> + Sync + Send>> = None;
-    let gateway_connection_string = args.gateway_connection_string.clone();
-    // let host_string = hostname::get()
-    //     .unwrap_or_default()
-    //     .to_string_lossy()
-    //     .to_string();
-    // let instance_id_string = format!(
-    //     "{}@{}-{}",
-    //     crate::SERVICE_ID,
-    //     host_string,
-    //     std::process::id()
-    // );
+    let mut gateway_opt: Option<GatewayLink> = None;
+    let host_string = hostname::get()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    let instance_id_string = format!(
+        "{}@{}-{}",
+        crate::SERVICE_ID,
+        host_string,
+        std::process::id()
+    );
+    // Counts successful connects; identifies each connection in CommandEvent
+    let mut gateway_connections: u64 = 0;
+    // Set when rows may have been left in 'Sending'; cleared once they are reconciled
+    let mut recovery_reason: Option<&str> = None;
+
+    let gateways = &args.gateway_connection_string;
+    let failback_interval = Duration::from_secs(args.gateway_failback_secs);
+    // 'Pending' rows this cycle left as they were after an error. The fast poll ignores
+    // them so they are retried at the poll interval instead of every fast poll.
+    let mut skipped: HashSet<i64> = HashSet::new();
+    let mut last_failback_attempt = Instant::now();
 
     loop {
-        if gateway_conn_opt.is_none() {
-            info!(
-                "Attempting to connect to gateway: {}",
-                gateway_connection_string
-            );
-            match connect::<MavMessage>(&gateway_connection_string) {
-                Ok(conn) => {
-                    info!("Successfully connected to gateway.");
-                    gateway_conn_opt = Some(conn);
-                    // TODO: Consider logic to reset status of commands stuck in 'Sending' from a previous session/connection drop.
+        if gateway_opt.is_none() {
+            match gateway::connect_first(gateways) {
+                Some((preference, conn)) => {
+                    recovery_reason = Some(if gateway_connections > 0 {
+                        "gateway reconnect"
+                    } else {
+                        "dispatcher restart"
+                    });
+                    gateway_connections += 1;
+                    if preference > 0 {
+                        warn!(
+                            "Preferred gateway {} unavailable; sending through {}.",
+                            gateways[0], gateways[preference]
+                        );
+                    }
+                    gateway_opt = Some(gateway_link(
+                        &args,
+                        conn,
+                        preference,
+                        format!("{}#{}", instance_id_string, gateway_connections),
+                    ));
+                    last_failback_attempt = Instant::now();
                 }
-                Err(error) => {
-                    error!(
-                        "Failed to connect to gateway: {}. Retrying in 5 seconds...",
-                        error
-                    );
-                    gateway_conn_opt = None;
+                None => {
+                    error!("No gateway reachable. Retrying in 5 seconds...");
                     tokio::time::sleep(Duration::from_secs(5)).await;
                     continue;
                 }
             }
+        } else if let Some(current) = gateway_opt.as_ref().map(|link| link.preference) {
+            // Move back to a more preferred gateway once it is reachable again. Nothing is
+            // in flight between cycles, so the switch needs no recovery.
+            if current > 0
+                && !failback_interval.is_zero()
+                && last_failback_attempt.elapsed() >= failback_interval
+            {
+                last_failback_attempt = Instant::now();
+                if let Some((preference, conn)) = gateway::connect_first(&gateways[..current]) {
+                    gateway_connections += 1;
+                    info!(
+                        "Failing back from gateway {} to {}.",
+                        gateways[current], gateways[preference]
+                    );
+                    gateway_opt = Some(gateway_link(
+                        &args,
+                        conn,
+                        preference,
+                        format!("{}#{}", instance_id_string, gateway_connections),
+                    ));
+                }
+            }
         }
 
-        if let Some(ref mut current_gateway_conn) = gateway_conn_opt {
-            info!("Fetching pending commands...");
-            let stmt = match db_conn.prepare(
-                "SELECT id, command_type, parameters, source_service FROM OutgoingCommand WHERE status = 'Pending' ORDER BY created_at ASC LIMIT 10"
-            ).await {
-                Ok(s) => s,
-                Err(e) => {
-                    error!("Failed to prepare DB statement: {:?}. Retrying poll cycle.", e);
-                    tokio::time::sleep(Duration::from_secs(args.poll_interval_secs)).await;
-                    continue;
-                }
-            };
+        if let Some(reason) = recovery_reason {
+            match recover_stuck_commands(&db_conn, &policies, reason).await {
+                Ok(()) => recovery_reason = None,
+                Err(e) => error!("Failed to recover commands stuck in 'Sending': {:?}", e),
+            }
+        }
 
-            let rows_result = stmt.query(libsql_params![]).await;
-            let mut commands: Vec<OutgoingCommandRow> = Vec::new();
-
-            match rows_result {
-                Ok(mut rows) => loop {
-                    match rows.next().await {
-                        Ok(Some(row)) => {
-                            commands.push(OutgoingCommandRow {
-                                id: row.get(0)?,
-                                command_type: row.get(1)?,
-                                parameters: row.get(2)?,
-                                source_service: row.get(3)?,
-                            });
-                        }
-                        Ok(None) => {
-                            break;
-                        }
-                        Err(e) => {
-                            error!("Error fetching a row from database: {:?}. Processing partial batch if any.", e);
-                            break;
-                        }
-                    }
-                },
+        let mut gateway_lost = false;
+        if let Some(gateway) = &gateway_opt {
+            if let Err(e) = advance_sequences(&db_conn).await {
+                error!("Failed to advance command sequences: {:?}", e);
+            }
+
+            if let Err(e) = expire_overdue_commands(&db_conn, &policies).await {
+                error!("Failed to expire overdue commands: {:?}", e);
+            }
+
+            info!("Fetching pending commands...");
+            skipped.clear();
+            let commands = match fetch_pending(&db_conn, &policies, 10).await {
+                Ok(commands) => commands,
                 Err(e) => {
                     error!(
-                        "Failed to execute query for pending commands: {:?}. Retrying poll cycle.",
+                        "Failed to fetch pending commands: {:?}. Retrying poll cycle.",
                         e
                     );
                     tokio::time::sleep(Duration::from_secs(args.poll_interval_secs)).await;
                     continue;
                 }
-            }
+            };
 
             if commands.is_empty() {
                 // info!("No pending commands found."); // Keep commented out info for potential debugging
@@ -106,41 +155,169 @@ pub async fn run_dispatcher(
                     commands.len()
                 );
                 for command_row in commands {
+                    let policy = policies.get(&command_row.command_type);
+                    let target = permission::intended_target(
+                        &command_row.command_type,
+                        command_row.parameters.as_deref(),
+                        policy,
+                    );
+                    if let Err(reason) = permissions.check(&command_row, target) {
+                        if let Err(e) = permission::reject(&db_conn, command_row.id, &reason).await
+                        {
+                            error!(
+                                "[Cmd ID: {}] Failed to record permission rejection: {:?}",
+                                command_row.id, e
+                            );
+                            skipped.insert(command_row.id);
+                        }
+                        continue;
+                    }
+
+                    let decision = match interlock::evaluate(
+                        &db_conn,
+                        &command_row,
+                        policy,
+                        args.arm_timeout_secs,
+                        &confirmation_key,
+                    )
+                    .await
+                    {
+                        Ok(decision) => decision,
+                        Err(e) => {
+                            error!(
+                                "[Cmd ID: {}] Failed to evaluate safety interlock: {:?}. Skipping this cycle.",
+                                command_row.id, e
+                            );
+                            skipped.insert(command_row.id);
+                            continue;
+                        }
+                    };
+                    let interlock_result = match decision {
+                        InterlockDecision::Allow => Ok(()),
+                        InterlockDecision::AwaitConfirmation => {
+                            interlock::request_confirmation(&db_conn, &command_row, policy).await
+                        }
+                        InterlockDecision::Block(ref reason) => {
+                            interlock::block(&db_conn, command_row.id, "Pending", reason).await
+                        }
+                    };
+                    if let Err(e) = interlock_result {
+                        error!(
+                            "[Cmd ID: {}] Failed to record interlock decision: {:?}",
+                            command_row.id, e
+                        );
+                        skipped.insert(command_row.id);
+                    }
+                    if decision != InterlockDecision::Allow {
+                        continue;
+                    }
+
                     let process_result = process_single_command(
                         &db_conn,
-                        current_gateway_conn,
+                        gateway,
                         command_row,
+                        &policies,
                         start_instant,
                     )
                     .await;
 
-                    if let Err(e) = process_result {
-                        let (cmd_id, err_details) = e;
-                        error!(
-                            "Error processing command (ID: {}): {:?}",
-                            cmd_id, err_details
-                        );
-                        let error_string = err_details.to_string().to_lowercase();
-                        if error_string.contains("connection")
-                            || error_string.contains("broken pipe")
-                            || error_string.contains("os error 104")
-                            || error_string.contains("os error 32")
-                            || error_string.contains("failed to send")
-                        {
+                    match process_result {
+                        Ok(()) => {}
+                        // Already marked 'Failed' and logged; the next command is unaffected.
+                        Err((_, DispatchError::Invalid(_))) => {}
+                        Err((cmd_id, DispatchError::Database(e))) => {
+                            error!(
+                                "[Cmd ID: {}] Database error while dispatching: {:?}. Stopping this batch.",
+                                cmd_id, e
+                            );
+                            skipped.insert(cmd_id);
+                            recovery_reason = Some("database error");
+                            break;
+                        }
+                        Err((cmd_id, DispatchError::Transport(e))) => {
                             warn!(
-                                "Gateway connection likely lost (error: {}). Resetting connection.",
-                                err_details
+                                "[Cmd ID: {}] Gateway write failed: {}. Resetting connection.",
+                                cmd_id, e
                             );
+                            gateway_lost = true;
                             break;
                         }
                     }
                 }
             }
         }
+        if gateway_lost {
+            gateway_opt = None;
+        }
+
+        if let Err(e) =
+            interlock::process_awaiting_confirmation(&db_conn, &policies, &confirmation_key).await
+        {
+            error!("Failed to process commands awaiting confirmation: {:?}", e);
+        }
+
+        if let Err(e) = check_acknowledgements(&db_conn, &policies).await {
+            error!("Failed to check command acknowledgements: {:?}", e);
+        }
+
+        if let Some(controller) = rate_controller.as_mut() {
+            if let Err(e) = controller.tick(&db_conn).await {
+                error!("Radio rate adaptation failed: {:?}", e);
+            }
+        }
 
-        // Always sleep a bit so we don't busy loop even when not connected
-        tokio::time::sleep(Duration::from_secs(args.poll_interval_secs)).await;
+        // A step whose command went out or was acknowledged this cycle is done; go straight
+        // on to the next one instead of waiting for the poll
+        if gateway_opt.is_some() {
+            match advance_sequences(&db_conn).await {
+                Ok(true) => trigger.notify_one(),
+                Ok(false) => {}
+                Err(e) => error!("Failed to advance command sequences: {:?}", e),
+            }
+        }
+
+        // Wake early for a trigger or a newly queued command, never busy loop
+        wait_for_work(
+            &db_conn,
+            &trigger,
+            &skipped,
+            Duration::from_secs(args.poll_interval_secs),
+            Duration::from_millis(args.fast_poll_interval_ms),
+        )
+        .await;
     }
     // Unreachable code
     // Ok(())
 }
+
+fn gateway_link(
+    args: &Args,
+    conn: Box<dyn MavConnection<MavMessage> + Sync + Send>,
+    preference: usize,
+    connection_id: String,
+) -> GatewayLink {
+    let connection_string = &args.gateway_connection_string[preference];
+    let scheduler = if args.half_duplex {
+        match TransmitScheduler::start(connection_string) {
+            Ok(scheduler) => Some(scheduler),
+            Err(e) => {
+                warn!(
+                    "Cannot listen to gateway {} for transmit scheduling ({}); sending immediately.",
+                    connection_string, e
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+    GatewayLink {
+        id: format!("{}@{}", connection_id, connection_string),
+        preference,
+        scheduler,
+        conn,
+        system_id: args.mavlink_system_id,
+        component_id: args.mavlink_component_id,
+        sequence: AtomicU8::new(0),
+    }
+}
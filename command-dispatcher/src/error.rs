@@ -0,0 +1,43 @@
+use mavlink::error::MessageWriteError;
+use std::fmt;
+
+/// Why `process_single_command` did not send a command. The variant decides what the
+/// dispatch loop does next.
+#[derive(Debug)]
+pub enum DispatchError {
+    /// The command cannot be sent as queued (parameters, target, frame size). The row is
+    /// already 'Failed'; the loop moves on to the next command.
+    Invalid(String),
+    /// A database call failed. The row may be left in 'Sending'; the loop stops the batch
+    /// and reconciles stuck rows on the next cycle.
+    Database(libsql::Error),
+    /// Writing to the gateway failed. The row has been re-queued or failed per policy; the
+    /// loop drops the connection and reconnects.
+    Transport(MessageWriteError),
+}
+
+impl fmt::Display for DispatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DispatchError::Invalid(reason) => write!(f, "Invalid command: {}", reason),
+            DispatchError::Database(e) => write!(f, "Database error: {}", e),
+            DispatchError::Transport(e) => write!(f, "Gateway transport error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DispatchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DispatchError::Invalid(_) => None,
+            DispatchError::Database(e) => Some(e),
+            DispatchError::Transport(e) => Some(e),
+        }
+    }
+}
+
+impl From<libsql::Error> for DispatchError {
+    fn from(e: libsql::Error) -> Self {
+        DispatchError::Database(e)
+    }
+}
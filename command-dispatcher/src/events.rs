@@ -0,0 +1,156 @@
+use chrono::Utc;
+use libsql::{params as libsql_params, Connection};
+use serde::Serialize;
+use tracing::error;
+
+/// Extra facts recorded with a status transition.
+#[derive(Debug, Default)]
+pub struct EventDetail<'a> {
+    pub error: Option<&'a str>,
+    /// The exact bytes handed to the gateway.
+    pub payload: Option<&'a [u8]>,
+    pub gateway_connection_id: Option<&'a str>,
+    pub mavlink_sequence: Option<u8>,
+}
+
+/// Appends a row to `CommandEvent` for a command's transition from `from_status` to
+/// `to_status`. The command's attempt count, operator and source service are copied from
+/// `OutgoingCommand` as they are at this moment. A failed write is logged, never returned:
+/// the audit trail must not change what the dispatcher does.
+pub async fn record(
+    db_conn: &Connection,
+    command_id: i64,
+    from_status: &str,
+    to_status: &str,
+    detail: EventDetail<'_>,
+) {
+    let result = db_conn
+        .execute(
+            "INSERT INTO CommandEvent (command_id, at_ms, from_status, to_status, attempt, operator, source_service, \
+             gateway_connection_id, mavlink_sequence, payload, error_message, recorded_by) \
+             SELECT id, ?, ?, ?, attempts, operator, source_service, ?, ?, ?, ?, ? FROM OutgoingCommand WHERE id = ?",
+            libsql_params![
+                Utc::now().timestamp_millis(),
+                from_status,
+                to_status,
+                detail.gateway_connection_id,
+                detail.mavlink_sequence.map(i64::from),
+                detail.payload.map(<[u8]>::to_vec),
+                detail.error,
+                crate::SERVICE_ID,
+                command_id
+            ],
+        )
+        .await;
+    if let Err(e) = result {
+        error!(
+            "[Cmd ID: {}] Failed to record '{}' -> '{}' in CommandEvent: {:?}",
+            command_id, from_status, to_status, e
+        );
+    }
+}
+
+// One row of a command's history
+#[derive(Serialize, Debug)]
+pub struct CommandEventRow {
+    pub id: i64,
+    pub at_ms: i64,
+    pub from_status: Option<String>,
+    pub to_status: String,
+    pub attempt: Option<i64>,
+    pub operator: Option<String>,
+    pub source_service: Option<String>,
+    pub gateway_connection_id: Option<String>,
+    pub mavlink_sequence: Option<i64>,
+    /// Upper-case hex of the bytes sent.
+    pub payload_hex: Option<String>,
+    pub error_message: Option<String>,
+    pub recorded_by: Option<String>,
+}
+
+/// Recorded transitions of a command with an event id above `after_id` (0 for all), oldest
+/// first.
+pub async fn command_history(
+    db_conn: &Connection,
+    command_id: i64,
+    after_id: i64,
+) -> Result<Vec<CommandEventRow>, libsql::Error> {
+    let mut rows = db_conn
+        .query(
+            "SELECT id, at_ms, from_status, to_status, attempt, operator, source_service, gateway_connection_id, \
+             mavlink_sequence, hex(payload), error_message, recorded_by \
+             FROM CommandEvent WHERE command_id = ? AND id > ? ORDER BY at_ms ASC, id ASC",
+            libsql_params![command_id, after_id],
+        )
+        .await?;
+    let mut history = Vec::new();
+    while let Some(row) = rows.next().await? {
+        let payload_hex: Option<String> = row.get(9)?;
+        history.push(CommandEventRow {
+            id: row.get(0)?,
+            at_ms: row.get(1)?,
+            from_status: row.get(2)?,
+            to_status: row.get(3)?,
+            attempt: row.get(4)?,
+            operator: row.get(5)?,
+            source_service: row.get(6)?,
+            gateway_connection_id: row.get(7)?,
+            mavlink_sequence: row.get(8)?,
+            // hex() of NULL is an empty string
+            payload_hex: payload_hex.filter(|hex| !hex.is_empty()),
+            error_message: row.get(10)?,
+            recorded_by: row.get(11)?,
+        });
+    }
+    Ok(history)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::database;
+
+    #[tokio::test]
+    async fn trail_starts_at_queueing_and_cannot_be_rewritten() {
+        let db = database().await;
+        db.execute(
+            "INSERT INTO OutgoingCommand (id, command_type, status, created_at, source_service, operator) \
+             VALUES (3, 'Ping', 'Pending', 0, 'rgs-web-commands', 'alice')",
+            libsql_params![],
+        )
+        .await
+        .unwrap();
+        db.execute(
+            "UPDATE OutgoingCommand SET status = 'Sending' WHERE id = 3",
+            libsql_params![],
+        )
+        .await
+        .unwrap();
+        record(&db, 3, "Pending", "Sending", EventDetail::default()).await;
+
+        let history = command_history(&db, 3, 0).await.unwrap();
+        let transitions: Vec<_> = history
+            .iter()
+            .map(|e| (e.from_status.as_deref(), e.to_status.as_str()))
+            .collect();
+        assert_eq!(
+            transitions,
+            [(None, "Pending"), (Some("Pending"), "Sending")]
+        );
+        assert_eq!(history[0].operator.as_deref(), Some("alice"));
+        assert_eq!(history[0].recorded_by.as_deref(), Some("rgs-web-commands"));
+
+        assert!(db
+            .execute(
+                "UPDATE CommandEvent SET error_message = 'x'",
+                libsql_params![]
+            )
+            .await
+            .is_err());
+        assert!(db
+            .execute("DELETE FROM CommandEvent", libsql_params![])
+            .await
+            .is_err());
+        assert_eq!(command_history(&db, 3, 0).await.unwrap().len(), 2);
+    }
+}
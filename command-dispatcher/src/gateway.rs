@@ -0,0 +1,54 @@
+use crate::transmit::TransmitScheduler;
+use mavlink::{connect, uorocketry::MavMessage, MavConnection, MavHeader};
+use std::sync::atomic::{AtomicU8, Ordering};
+use tracing::{info, warn};
+
+/// An open gateway connection and what the dispatcher tracks about it.
+pub struct GatewayLink {
+    pub conn: Box<dyn MavConnection<MavMessage> + Sync + Send>,
+    /// Identifies the connection in `CommandEvent`, e.g.
+    /// 'command-dispatcher@host-42#3@tcpout:192.168.8.178:5656'.
+    pub id: String,
+    /// Position of the gateway in the preference order, 0 being the preferred one.
+    pub preference: usize,
+    /// Present when half-duplex transmit scheduling is enabled and the gateway could be
+    /// listened to.
+    pub scheduler: Option<TransmitScheduler>,
+    pub system_id: u8,
+    pub component_id: u8,
+    /// Sequence number of the next frame; starts at 0 on every connection.
+    pub sequence: AtomicU8,
+}
+
+impl GatewayLink {
+    /// Header for the next frame sent on this connection. Every call uses up a sequence
+    /// number, so the receiver sees a gap for a frame that was lost on the way.
+    pub fn next_header(&self) -> MavHeader {
+        MavHeader {
+            system_id: self.system_id,
+            component_id: self.component_id,
+            sequence: self.sequence.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
+
+/// Opens a connection to the first gateway in `connection_strings` that accepts one, and
+/// returns its position in the list.
+pub fn connect_first(
+    connection_strings: &[String],
+) -> Option<(usize, Box<dyn MavConnection<MavMessage> + Sync + Send>)> {
+    for (preference, connection_string) in connection_strings.iter().enumerate() {
+        info!("Attempting to connect to gateway: {}", connection_string);
+        match connect::<MavMessage>(connection_string) {
+            Ok(conn) => {
+                info!("Successfully connected to gateway {}.", connection_string);
+                return Some((preference, conn));
+            }
+            Err(error) => warn!(
+                "Failed to connect to gateway {}: {}",
+                connection_string, error
+            ),
+        }
+    }
+    None
+}
@@ -0,0 +1,407 @@
+use crate::commands::OutgoingCommandRow;
+use crate::events::{self, EventDetail};
+use crate::policy::{CommandPolicies, CommandPolicy};
+use chrono::Utc;
+use libsql::{params as libsql_params, Connection};
+use rand::Rng;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use tracing::{info, warn};
+
+/// `SystemConfig` key holding the arming switch ('true' / 'false').
+pub const ARMED_CONFIG_KEY: &str = "command_armed";
+
+/// Signs the confirmations the command API records for an authenticated operator, so a
+/// `CommandConfirmation` row written any other way confirms nothing. The key only lives in
+/// this process; a confirmation pending across a restart has to be given again.
+#[derive(Clone, Default)]
+pub struct ConfirmationKey(RandomState);
+
+impl ConfirmationKey {
+    /// The `CommandConfirmation.token` for `operator` confirming the request identified by
+    /// the command's `confirmation_token`.
+    pub fn sign(&self, command_id: i64, request_token: &str, operator: &str) -> String {
+        format!(
+            "{:016x}",
+            self.0.hash_one((command_id, request_token, operator))
+        )
+    }
+}
+
+/// Outcome of the safety checks for a 'Pending' command.
+#[derive(Debug, PartialEq)]
+pub enum InterlockDecision {
+    Allow,
+    /// Hold the command until a second operator confirms it.
+    AwaitConfirmation,
+    Block(String),
+}
+
+/// Arming is only honoured for `arm_timeout_secs` after it was last set, so a forgotten
+/// switch does not stay live for the whole day.
+async fn is_armed(db_conn: &Connection, arm_timeout_secs: i64) -> Result<bool, libsql::Error> {
+    let mut rows = db_conn
+        .query(
+            "SELECT value, updated_at FROM SystemConfig WHERE key = ?",
+            libsql_params![ARMED_CONFIG_KEY],
+        )
+        .await?;
+    let Some(row) = rows.next().await? else {
+        return Ok(false);
+    };
+    let value: String = row.get(0)?;
+    let updated_at: i64 = row.get(1)?;
+    let switched_on = matches!(value.trim().to_lowercase().as_str(), "true" | "1" | "armed");
+    Ok(switched_on && Utc::now().timestamp() - updated_at <= arm_timeout_secs)
+}
+
+/// Latest PhoenixState stored by the ingestor and when it was received.
+pub async fn latest_phoenix_state(
+    db_conn: &Connection,
+) -> Result<Option<(String, i64)>, libsql::Error> {
+    let mut rows = db_conn
+        .query(
+            "SELECT ps.state, rf.timestamp_epoch FROM RadioFrame rf \
+             JOIN PhoenixState ps ON rf.data_type = 'PhoenixState' AND rf.data_id = ps.id \
+             ORDER BY rf.id DESC LIMIT 1",
+            libsql_params![],
+        )
+        .await?;
+    match rows.next().await? {
+        Some(row) => Ok(Some((row.get(0)?, row.get(1)?))),
+        None => Ok(None),
+    }
+}
+
+async fn is_confirmed(
+    db_conn: &Connection,
+    command: &OutgoingCommandRow,
+    window_secs: i64,
+    key: &ConfirmationKey,
+) -> Result<bool, libsql::Error> {
+    let Some(request_token) = command.confirmation_token.clone() else {
+        return Ok(false);
+    };
+    // The confirming operator must differ from the named one who queued the command, answer
+    // within the window of the current request, not an earlier one, and have been
+    // authenticated by the command API, which signed the confirmation.
+    let mut rows = db_conn
+        .query(
+            "SELECT cc.token, cc.operator FROM CommandConfirmation cc JOIN OutgoingCommand oc ON oc.id = cc.command_id \
+             WHERE cc.command_id = ? AND oc.operator IS NOT NULL AND oc.operator != '' \
+             AND cc.operator != '' AND cc.operator != oc.operator \
+             AND cc.created_at >= COALESCE(oc.confirmation_requested_at, 0) \
+             AND cc.created_at <= COALESCE(oc.confirmation_requested_at, 0) + ?",
+            libsql_params![command.id, window_secs],
+        )
+        .await?;
+    while let Some(row) = rows.next().await? {
+        let token: String = row.get(0)?;
+        let operator: String = row.get(1)?;
+        if token == key.sign(command.id, &request_token, &operator) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Runs the arming, telemetry and confirmation checks required by the command type's policy.
+pub async fn evaluate(
+    db_conn: &Connection,
+    command: &OutgoingCommandRow,
+    policy: &CommandPolicy,
+    arm_timeout_secs: i64,
+    key: &ConfirmationKey,
+) -> Result<InterlockDecision, libsql::Error> {
+    if policy.require_armed && !is_armed(db_conn, arm_timeout_secs).await? {
+        return Ok(InterlockDecision::Block(format!(
+            "{} requires the system to be armed (SystemConfig '{}')",
+            command.command_type, ARMED_CONFIG_KEY
+        )));
+    }
+
+    if let Some(allowed) = &policy.allowed_phoenix_states {
+        match latest_phoenix_state(db_conn).await? {
+            None => {
+                return Ok(InterlockDecision::Block(format!(
+                    "{} requires PhoenixState telemetry, none received",
+                    command.command_type
+                )))
+            }
+            Some((_, received_at))
+                if Utc::now().timestamp() - received_at > policy.max_state_age_secs =>
+            {
+                return Ok(InterlockDecision::Block(format!(
+                    "{} requires PhoenixState telemetry newer than {}s",
+                    command.command_type, policy.max_state_age_secs
+                )))
+            }
+            Some((state, _)) if !allowed.iter().any(|a| a.eq_ignore_ascii_case(&state)) => {
+                return Ok(InterlockDecision::Block(format!(
+                    "{} not allowed in PhoenixState {} (allowed: {})",
+                    command.command_type,
+                    state,
+                    allowed.join(", ")
+                )))
+            }
+            Some(_) => {}
+        }
+    }
+
+    if policy.require_confirmation && command.operator.as_deref().is_none_or(str::is_empty) {
+        // Without a name there is no telling the second operator from the first
+        return Ok(InterlockDecision::Block(format!(
+            "{} requires confirmation by a second operator, but was queued without an operator",
+            command.command_type
+        )));
+    }
+    if policy.require_confirmation
+        && !is_confirmed(db_conn, command, policy.confirmation_window_secs, key).await?
+    {
+        return Ok(InterlockDecision::AwaitConfirmation);
+    }
+
+    Ok(InterlockDecision::Allow)
+}
+
+/// Moves a command to 'AwaitingConfirmation' with a fresh token identifying this request, so
+/// only confirmations given for it count.
+pub async fn request_confirmation(
+    db_conn: &Connection,
+    command: &OutgoingCommandRow,
+    policy: &CommandPolicy,
+) -> Result<(), libsql::Error> {
+    let token = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+    info!(
+        "[Cmd ID: {}] {} requires confirmation by a second operator within {}s.",
+        command.id, command.command_type, policy.confirmation_window_secs
+    );
+    let updated = db_conn
+        .execute(
+            "UPDATE OutgoingCommand SET status = 'AwaitingConfirmation', confirmation_token = ?, confirmation_requested_at = ? WHERE id = ? AND status = 'Pending'",
+            libsql_params![token, Utc::now().timestamp(), command.id],
+        )
+        .await?;
+    if updated > 0 {
+        events::record(
+            db_conn,
+            command.id,
+            "Pending",
+            "AwaitingConfirmation",
+            EventDetail::default(),
+        )
+        .await;
+    }
+    Ok(())
+}
+
+/// Marks a command that is still in `from_status` as 'Blocked'.
+pub async fn block(
+    db_conn: &Connection,
+    cmd_id: i64,
+    from_status: &str,
+    reason: &str,
+) -> Result<(), libsql::Error> {
+    warn!("[Cmd ID: {}] Blocked: {}", cmd_id, reason);
+    let updated = db_conn
+        .execute(
+            "UPDATE OutgoingCommand SET status = 'Blocked', error_message = ? WHERE id = ? AND status = ?",
+            libsql_params![reason, cmd_id, from_status],
+        )
+        .await?;
+    if updated > 0 {
+        events::record(
+            db_conn,
+            cmd_id,
+            from_status,
+            "Blocked",
+            EventDetail {
+                error: Some(reason),
+                ..EventDetail::default()
+            },
+        )
+        .await;
+    }
+    Ok(())
+}
+
+/// Releases confirmed 'AwaitingConfirmation' commands back to 'Pending' and blocks those
+/// whose confirmation window has passed.
+pub async fn process_awaiting_confirmation(
+    db_conn: &Connection,
+    policies: &CommandPolicies,
+    key: &ConfirmationKey,
+) -> Result<(), libsql::Error> {
+    let mut rows = db_conn
+        .query(
+            "SELECT id, command_type, parameters, source_service, operator, confirmation_token, confirmation_requested_at \
+             FROM OutgoingCommand WHERE status = 'AwaitingConfirmation'",
+            libsql_params![],
+        )
+        .await?;
+    let mut awaiting = Vec::new();
+    while let Some(row) = rows.next().await? {
+        let requested_at: Option<i64> = row.get(6)?;
+        awaiting.push((
+            OutgoingCommandRow {
+                id: row.get(0)?,
+                command_type: row.get(1)?,
+                parameters: row.get(2)?,
+                source_service: row.get(3)?,
+                operator: row.get(4)?,
+                confirmation_token: row.get(5)?,
+            },
+            requested_at.unwrap_or(0),
+        ));
+    }
+
+    let now = Utc::now().timestamp();
+    for (command, requested_at) in awaiting {
+        let policy = policies.get(&command.command_type);
+        if is_confirmed(db_conn, &command, policy.confirmation_window_secs, key).await? {
+            info!(
+                "[Cmd ID: {}] Confirmed by a second operator. Returning to 'Pending'.",
+                command.id
+            );
+            let updated = db_conn
+                .execute(
+                    "UPDATE OutgoingCommand SET status = 'Pending' WHERE id = ? AND status = 'AwaitingConfirmation'",
+                    libsql_params![command.id],
+                )
+                .await?;
+            if updated > 0 {
+                events::record(
+                    db_conn,
+                    command.id,
+                    "AwaitingConfirmation",
+                    "Pending",
+                    EventDetail::default(),
+                )
+                .await;
+            }
+        } else if now - requested_at > policy.confirmation_window_secs {
+            block(
+                db_conn,
+                command.id,
+                "AwaitingConfirmation",
+                &format!(
+                    "No confirmation from a second operator within {}s",
+                    policy.confirmation_window_secs
+                ),
+            )
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::database;
+
+    fn confirmed_policy() -> CommandPolicy {
+        CommandPolicy {
+            require_confirmation: true,
+            ..CommandPolicy::default()
+        }
+    }
+
+    async fn awaiting(db_conn: &Connection, operator: Option<&str>) -> OutgoingCommandRow {
+        db_conn
+            .execute(
+                "INSERT INTO OutgoingCommand (command_type, status, created_at, source_service, operator, confirmation_token, confirmation_requested_at) \
+                 VALUES ('PowerDown', 'AwaitingConfirmation', ?, 'ops', ?, '123456', ?)",
+                libsql_params![Utc::now().timestamp(), operator, Utc::now().timestamp()],
+            )
+            .await
+            .unwrap();
+        OutgoingCommandRow {
+            id: db_conn.last_insert_rowid(),
+            command_type: "PowerDown".to_string(),
+            parameters: None,
+            source_service: "ops".to_string(),
+            operator: operator.map(str::to_string),
+            confirmation_token: Some("123456".to_string()),
+        }
+    }
+
+    async fn confirm(db_conn: &Connection, command_id: i64, token: &str, operator: &str) {
+        db_conn
+            .execute(
+                "INSERT INTO CommandConfirmation (command_id, token, operator) VALUES (?, ?, ?)",
+                libsql_params![command_id, token, operator],
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn blocks_a_command_queued_without_an_operator() {
+        let db_conn = database().await;
+        let key = ConfirmationKey::default();
+        let command = awaiting(&db_conn, None).await;
+        confirm(
+            &db_conn,
+            command.id,
+            &key.sign(command.id, "123456", "bob"),
+            "bob",
+        )
+        .await;
+
+        let decision = evaluate(&db_conn, &command, &confirmed_policy(), 0, &key)
+            .await
+            .unwrap();
+        assert!(matches!(decision, InterlockDecision::Block(_)));
+    }
+
+    #[tokio::test]
+    async fn ignores_confirmations_not_signed_by_the_api() {
+        let db_conn = database().await;
+        let key = ConfirmationKey::default();
+        let command = awaiting(&db_conn, Some("alice")).await;
+        confirm(&db_conn, command.id, "123456", "bob").await;
+        confirm(
+            &db_conn,
+            command.id,
+            &ConfirmationKey::default().sign(command.id, "123456", "bob"),
+            "bob",
+        )
+        .await;
+
+        let decision = evaluate(&db_conn, &command, &confirmed_policy(), 0, &key)
+            .await
+            .unwrap();
+        assert_eq!(decision, InterlockDecision::AwaitConfirmation);
+    }
+
+    #[tokio::test]
+    async fn requires_a_second_operator() {
+        let db_conn = database().await;
+        let key = ConfirmationKey::default();
+        let command = awaiting(&db_conn, Some("alice")).await;
+        confirm(
+            &db_conn,
+            command.id,
+            &key.sign(command.id, "123456", "alice"),
+            "alice",
+        )
+        .await;
+        let decision = evaluate(&db_conn, &command, &confirmed_policy(), 0, &key)
+            .await
+            .unwrap();
+        assert_eq!(decision, InterlockDecision::AwaitConfirmation);
+
+        confirm(
+            &db_conn,
+            command.id,
+            &key.sign(command.id, "123456", "bob"),
+            "bob",
+        )
+        .await;
+        let decision = evaluate(&db_conn, &command, &confirmed_policy(), 0, &key)
+            .await
+            .unwrap();
+        assert_eq!(decision, InterlockDecision::Allow);
+    }
+}
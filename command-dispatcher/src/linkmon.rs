@@ -0,0 +1,299 @@
+use chrono::Utc;
+use libsql::{params as libsql_params, Connection};
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+
+/// `source_service` of the `Ping` commands queued by the link monitor.
+const LINK_MONITOR_SOURCE: &str = "command-dispatcher-link-monitor";
+
+/// Ping ids start here so they never collide with the small ids counted up by the heartbeat
+/// service's own Pings.
+const PING_ID_BASE: i64 = 0x8000_0000;
+
+#[derive(Debug, Clone)]
+pub struct LinkMonitorConfig {
+    /// Node names as stored in `RadioFrame.node`, e.g. `Phoenix`.
+    pub nodes: Vec<String>,
+    pub interval: Duration,
+    /// A sent Ping without a Pong after this long is lost.
+    pub timeout: Duration,
+    /// Resolved pings per node the statistics are computed over.
+    pub window: i64,
+}
+
+// A Ping still waiting for its Pong
+struct WaitingPing {
+    id: i64,
+    node: String,
+    ping_id: i64,
+    command_id: i64,
+    created_at_ms: i64,
+    command_status: String,
+}
+
+/// Pings every configured node each `interval`, matches the Pongs the ingestor saves back to
+/// them by id and sending node, and writes per-node round-trip time, loss ratio and jitter to
+/// `LinkStats`. Never returns; database errors are logged and retried on the next tick.
+pub async fn run_link_monitor(db_conn: Connection, config: LinkMonitorConfig) {
+    info!(
+        "Link monitor pinging {:?} every {:?} (timeout {:?}, window {} pings)",
+        config.nodes, config.interval, config.timeout, config.window
+    );
+    let mut next_ping_id = None;
+    let mut ticker = tokio::time::interval(config.interval);
+    loop {
+        ticker.tick().await;
+
+        if let Err(e) = resolve_waiting(&db_conn, config.timeout).await {
+            error!("Link monitor failed to match Pongs: {:?}", e);
+        }
+
+        let mut ping_id = match next_ping_id {
+            Some(id) => id,
+            None => match first_ping_id(&db_conn).await {
+                Ok(id) => id,
+                Err(e) => {
+                    error!("Link monitor failed to read the last ping id: {:?}", e);
+                    continue;
+                }
+            },
+        };
+        for node in &config.nodes {
+            match issue_ping(&db_conn, node, ping_id, config.timeout).await {
+                Ok(command_id) => {
+                    debug!(
+                        "[Cmd ID: {}] Link monitor pinged {} with id {}",
+                        command_id, node, ping_id
+                    );
+                    ping_id = if ping_id >= i64::from(u32::MAX) {
+                        PING_ID_BASE
+                    } else {
+                        ping_id + 1
+                    };
+                }
+                Err(e) => error!("Link monitor failed to ping {}: {:?}", node, e),
+            }
+        }
+        next_ping_id = Some(ping_id);
+
+        for node in &config.nodes {
+            if let Err(e) = record_stats(&db_conn, node, config.window).await {
+                error!("Link monitor failed to update stats for {}: {:?}", node, e);
+            }
+        }
+    }
+}
+
+async fn first_ping_id(db_conn: &Connection) -> Result<i64, libsql::Error> {
+    let mut rows = db_conn
+        .query(
+            "SELECT ping_id FROM LinkPing ORDER BY id DESC LIMIT 1",
+            libsql_params![],
+        )
+        .await?;
+    let last: Option<i64> = match rows.next().await? {
+        Some(row) => row.get(0)?,
+        None => None,
+    };
+    Ok(match last {
+        Some(id) if (PING_ID_BASE..i64::from(u32::MAX)).contains(&id) => id + 1,
+        _ => PING_ID_BASE,
+    })
+}
+
+async fn issue_ping(
+    db_conn: &Connection,
+    node: &str,
+    ping_id: i64,
+    timeout: Duration,
+) -> Result<i64, libsql::Error> {
+    let now_ms = Utc::now().timestamp_millis();
+    let now = now_ms / 1000;
+    // A Ping that cannot go out within the timeout would only be counted as lost
+    let expires_at = now + timeout.as_secs().max(1) as i64;
+    let mut rows = db_conn
+        .query(
+            "INSERT INTO OutgoingCommand (command_type, parameters, status, created_at, attempts, source_service, expires_at) \
+             VALUES ('Ping', ?, 'Pending', ?, 0, ?, ?) RETURNING id",
+            libsql_params![
+                serde_json::json!({ "id": ping_id, "target": node }).to_string(),
+                now,
+                LINK_MONITOR_SOURCE,
+                expires_at
+            ],
+        )
+        .await?;
+    let command_id: i64 = match rows.next().await? {
+        Some(row) => row.get(0)?,
+        None => return Err(libsql::Error::QueryReturnedNoRows),
+    };
+    drop(rows);
+    db_conn
+        .execute(
+            "INSERT INTO LinkPing (node, ping_id, command_id, created_at_ms, status) VALUES (?, ?, ?, ?, 'Waiting')",
+            libsql_params![node, ping_id, command_id, now_ms],
+        )
+        .await?;
+    Ok(command_id)
+}
+
+async fn resolve_waiting(db_conn: &Connection, timeout: Duration) -> Result<(), libsql::Error> {
+    let mut rows = db_conn
+        .query(
+            "SELECT lp.id, lp.node, lp.ping_id, lp.command_id, lp.created_at_ms, oc.status \
+             FROM LinkPing lp JOIN OutgoingCommand oc ON oc.id = lp.command_id \
+             WHERE lp.status = 'Waiting'",
+            libsql_params![],
+        )
+        .await?;
+    let mut waiting = Vec::new();
+    while let Some(row) = rows.next().await? {
+        waiting.push(WaitingPing {
+            id: row.get(0)?,
+            node: row.get(1)?,
+            ping_id: row.get(2)?,
+            command_id: row.get(3)?,
+            created_at_ms: row.get(4)?,
+            command_status: row.get(5)?,
+        });
+    }
+    drop(rows);
+
+    let timeout_ms = timeout.as_millis() as i64;
+    for ping in waiting {
+        let now_ms = Utc::now().timestamp_millis();
+        let Some(sent_at_ms) = sent_at_ms(db_conn, ping.command_id).await? else {
+            let still_queued = matches!(
+                ping.command_status.as_str(),
+                "Pending" | "AwaitingConfirmation" | "Sending"
+            );
+            if !still_queued || now_ms - ping.created_at_ms > 2 * timeout_ms {
+                debug!(
+                    "[Cmd ID: {}] Ping to {} never sent ({})",
+                    ping.command_id, ping.node, ping.command_status
+                );
+                db_conn
+                    .execute(
+                        "UPDATE LinkPing SET status = 'NotSent' WHERE id = ?",
+                        libsql_params![ping.id],
+                    )
+                    .await?;
+            }
+            continue;
+        };
+
+        match pong_at_ms(db_conn, ping.ping_id, &ping.node, sent_at_ms).await? {
+            Some(pong_at_ms) => {
+                // The fallback second-resolution receive time can precede the send
+                let rtt_ms = (pong_at_ms - sent_at_ms).max(0);
+                db_conn
+                    .execute(
+                        "UPDATE LinkPing SET status = 'Answered', sent_at_ms = ?, pong_at_ms = ?, rtt_ms = ? WHERE id = ?",
+                        libsql_params![sent_at_ms, pong_at_ms, rtt_ms, ping.id],
+                    )
+                    .await?;
+            }
+            None if now_ms - sent_at_ms > timeout_ms => {
+                warn!(
+                    "[Cmd ID: {}] No Pong from {} for ping id {} within {:?}",
+                    ping.command_id, ping.node, ping.ping_id, timeout
+                );
+                db_conn
+                    .execute(
+                        "UPDATE LinkPing SET status = 'Lost', sent_at_ms = ? WHERE id = ?",
+                        libsql_params![sent_at_ms, ping.id],
+                    )
+                    .await?;
+            }
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+// When the Ping was handed to the gateway, from the audit trail
+async fn sent_at_ms(db_conn: &Connection, command_id: i64) -> Result<Option<i64>, libsql::Error> {
+    let mut rows = db_conn
+        .query(
+            "SELECT MIN(at_ms) FROM CommandEvent WHERE command_id = ? AND to_status = 'Sent'",
+            libsql_params![command_id],
+        )
+        .await?;
+    match rows.next().await? {
+        Some(row) => row.get(0),
+        None => Ok(None),
+    }
+}
+
+// Receive time of the first Pong with this id from this node after the Ping was sent. Frames
+// saved by an ingestor without `received_at_ms` fall back to whole seconds.
+async fn pong_at_ms(
+    db_conn: &Connection,
+    ping_id: i64,
+    node: &str,
+    sent_at_ms: i64,
+) -> Result<Option<i64>, libsql::Error> {
+    let mut rows = db_conn
+        .query(
+            "SELECT COALESCE(rf.received_at_ms, rf.timestamp_epoch * 1000) FROM Pong p \
+             JOIN Command c ON c.data_type = 'Pong' AND c.data_id = p.id \
+             JOIN RadioFrame rf ON rf.data_type = 'Command' AND rf.data_id = c.id \
+             WHERE p.pong_id = ? AND rf.node = ? AND rf.timestamp_epoch >= ? \
+             ORDER BY rf.id ASC LIMIT 1",
+            libsql_params![ping_id, node, sent_at_ms / 1000],
+        )
+        .await?;
+    match rows.next().await? {
+        Some(row) => row.get(0),
+        None => Ok(None),
+    }
+}
+
+async fn record_stats(db_conn: &Connection, node: &str, window: i64) -> Result<(), libsql::Error> {
+    let mut rows = db_conn
+        .query(
+            "SELECT rtt_ms FROM LinkPing WHERE node = ? AND status IN ('Answered', 'Lost') \
+             ORDER BY id DESC LIMIT ?",
+            libsql_params![node, window],
+        )
+        .await?;
+    // Round-trip time per ping, None for a lost one
+    let mut results: Vec<Option<i64>> = Vec::new();
+    while let Some(row) = rows.next().await? {
+        results.push(row.get(0)?);
+    }
+    drop(rows);
+    if results.is_empty() {
+        return Ok(());
+    }
+    results.reverse();
+
+    let pings = results.len() as i64;
+    let rtts: Vec<i64> = results.iter().flatten().copied().collect();
+    let lost = pings - rtts.len() as i64;
+    let rtt_avg = (!rtts.is_empty()).then(|| rtts.iter().sum::<i64>() as f64 / rtts.len() as f64);
+    let jitter = (rtts.len() > 1).then(|| {
+        let diffs: i64 = rtts.windows(2).map(|w| (w[1] - w[0]).abs()).sum();
+        diffs as f64 / (rtts.len() - 1) as f64
+    });
+
+    db_conn
+        .execute(
+            "INSERT INTO LinkStats (node, computed_at, pings, lost, loss_ratio, rtt_ms_last, rtt_ms_avg, rtt_ms_min, rtt_ms_max, jitter_ms) \
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            libsql_params![
+                node,
+                Utc::now().timestamp(),
+                pings,
+                lost,
+                lost as f64 / pings as f64,
+                rtts.last().copied(),
+                rtt_avg,
+                rtts.iter().min().copied(),
+                rtts.iter().max().copied(),
+                jitter
+            ],
+        )
+        .await?;
+    Ok(())
+}
@@ -1,13 +1,39 @@
 mod cli;
-use cli::Args;
+use cli::{Args, CliCommand};
 mod health;
 use health::run_service_status_task;
 mod dispatcher;
 use dispatcher::run_dispatcher;
+mod ack;
+mod api;
 mod commands;
+mod error;
+mod events;
+mod gateway;
+mod interlock;
+mod linkmon;
+mod permission;
+mod policy;
+mod queue;
+mod rate;
+mod recovery;
+mod registry;
+mod sequence;
+#[cfg(test)]
+mod testing;
+mod transmit;
+mod trigger;
+use api::ApiOperators;
+use interlock::ConfirmationKey;
+use linkmon::LinkMonitorConfig;
+use permission::CommandPermissions;
+use policy::CommandPolicies;
+use rate::{RateAdaptationConfig, RateController};
 
 use clap::Parser; // For Args::parse()
 use libsql::Builder;
+use std::sync::Arc;
+use tokio::sync::Notify;
 use tracing::{error, info}; // Only error and info are used in main
                             // Tokio is brought in by #[tokio::main]
 
@@ -16,6 +42,21 @@ pub const SERVICE_ID: &str = "command-dispatcher";
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
+    if let Some(CliCommand::Schema) = args.command {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&registry::all_schemas())?
+        );
+        return Ok(());
+    }
+    if let Some(CliCommand::History { command_id }) = args.command {
+        let db = Builder::new_remote(args.libsql_url.clone(), args.libsql_auth_token.clone())
+            .build()
+            .await?;
+        let history = events::command_history(&db.connect()?, command_id, 0).await?;
+        println!("{}", serde_json::to_string_pretty(&history)?);
+        return Ok(());
+    }
     let start_instant = std::time::Instant::now();
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::INFO)
@@ -46,9 +87,101 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     });
 
+    let policies = Arc::new(CommandPolicies::load(args.command_policy_file.as_deref())?);
+    let permissions = Arc::new(CommandPermissions::load(
+        args.command_permissions_file.as_deref(),
+    )?);
+    let rate_controller = if args.rate_adaptation {
+        let config = RateAdaptationConfig::load(args.rate_adaptation_file.as_deref())?;
+        info!("Radio rate adaptation enabled: {:?}", config);
+        Some(RateController::new(config))
+    } else {
+        None
+    };
+
+    let link_monitor = if args.link_monitor_nodes.is_empty() {
+        None
+    } else {
+        let mut nodes = Vec::new();
+        for name in &args.link_monitor_nodes {
+            match registry::parse_node(name) {
+                // Spelled as the ingestor stores it in RadioFrame.node
+                Some(node) => nodes.push(format!("{:?}", node)),
+                None => return Err(format!("Unknown link monitor node '{}'", name).into()),
+            }
+        }
+        Some(LinkMonitorConfig {
+            nodes,
+            interval: std::time::Duration::from_secs(args.link_monitor_interval_secs.max(1)),
+            timeout: std::time::Duration::from_secs(args.link_monitor_timeout_secs),
+            window: args.link_monitor_window.max(1),
+        })
+    };
+
+    match registry::publish_schemas(&db_connection).await {
+        Ok(()) => info!(
+            "Published command parameter schema to SystemConfig '{}'.",
+            registry::SCHEMA_CONFIG_KEY
+        ),
+        Err(e) => error!("Failed to publish command parameter schema: {:?}", e),
+    }
+
+    let trigger = Arc::new(Notify::new());
+    let confirmation_key = ConfirmationKey::default();
+    if let Some(addr) = args.trigger_listen_addr {
+        let trigger_for_server = trigger.clone();
+        tokio::spawn(async move {
+            if let Err(e) = trigger::run_trigger_server(addr, trigger_for_server).await {
+                error!("Dispatch trigger endpoint exited with error: {:?}", e);
+            }
+        });
+    }
+
+    if let Some(addr) = args.api_listen_addr {
+        let operators =
+            ApiOperators::load(args.api_operators_file.as_deref(), args.api_allow_anonymous)?;
+        let (db_for_api, policies_for_api, permissions_for_api, trigger_for_api, key_for_api) = (
+            db_connection.clone(),
+            policies.clone(),
+            permissions.clone(),
+            trigger.clone(),
+            confirmation_key.clone(),
+        );
+        tokio::spawn(async move {
+            if let Err(e) = api::run_api_server(
+                addr,
+                db_for_api,
+                policies_for_api,
+                permissions_for_api,
+                operators,
+                trigger_for_api,
+                key_for_api,
+            )
+            .await
+            {
+                error!("Command API exited with error: {:?}", e);
+            }
+        });
+    }
+
+    if let Some(config) = link_monitor {
+        tokio::spawn(linkmon::run_link_monitor(db_connection.clone(), config));
+    }
+
     info!("Command dispatcher initialized. Starting dispatch loop...");
 
-    if let Err(e) = run_dispatcher(db_connection, args, start_instant).await {
+    if let Err(e) = run_dispatcher(
+        db_connection,
+        args,
+        policies,
+        permissions,
+        rate_controller,
+        trigger,
+        confirmation_key,
+        start_instant,
+    )
+    .await
+    {
         error!("Dispatcher loop exited with critical error: {:?}", e);
         service_status_handle.abort();
         return Err(e);
@@ -0,0 +1,200 @@
+use crate::commands::{resolve_target, OutgoingCommandRow};
+use crate::events::{self, EventDetail};
+use crate::policy::CommandPolicy;
+use crate::registry::{self, parse_node};
+use libsql::{params as libsql_params, Connection};
+use messages_prost::common::Node;
+use serde::Deserialize;
+use std::path::Path;
+use tracing::{info, warn};
+
+/// Matches any source service, operator or command type.
+const ANY: &str = "*";
+
+/// What one source service, optionally narrowed to one operator, may send.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PermissionRule {
+    /// `source_service` the rule applies to, or `*`.
+    pub source_service: String,
+    /// `operator` the rule applies to; any operator when absent or `*`.
+    #[serde(default)]
+    pub operator: Option<String>,
+    /// Command types this source may send, `*` for all.
+    pub command_types: Vec<String>,
+    /// Nodes these commands may be addressed to; any node when absent.
+    #[serde(default)]
+    pub targets: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PermissionsFile {
+    rules: Vec<PermissionRule>,
+}
+
+/// Which command types and target nodes each source service and operator may send. Rules are
+/// tried in order and the first one matching the command's source and operator decides; a
+/// command no rule matches is rejected.
+#[derive(Debug)]
+pub struct CommandPermissions {
+    rules: Vec<(PermissionRule, Option<Vec<Node>>)>,
+}
+
+impl Default for CommandPermissions {
+    fn default() -> Self {
+        let rule = |source: &str, command_types: &[&str]| PermissionRule {
+            source_service: source.to_string(),
+            operator: None,
+            command_types: command_types.iter().map(|t| t.to_string()).collect(),
+            targets: None,
+        };
+        // Sources that put operators' commands in the queue. Pyro and PowerDown from these
+        // still pass the interlock.
+        let operator_types = &[
+            "Ping",
+            "Online",
+            "PowerUpCamera",
+            "PowerDownCamera",
+            "RadioRateChange",
+            "PowerDown",
+            "DeployDrogue",
+            "DeployMain",
+        ];
+        let rules = vec![
+            // Automated sources only ever need their own command type
+            rule("heartbeat", &["Ping"]),
+            rule("command-dispatcher-link-monitor", &["Ping"]),
+            rule("command-dispatcher-rate-adaptation", &["RadioRateChange"]),
+            rule("rgs-web-commands", operator_types),
+            rule("command-dispatcher-api", operator_types),
+            rule("command-dispatcher-sequence", operator_types),
+        ];
+        Self {
+            rules: rules.into_iter().map(|r| (r, None)).collect(),
+        }
+    }
+}
+
+impl CommandPermissions {
+    /// Loads the built-in rules, or the rules of a JSON file in their place, e.g.
+    /// `{"rules": [{"source_service": "heartbeat", "command_types": ["Ping"]}]}`.
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let file: PermissionsFile = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        let mut rules = Vec::with_capacity(file.rules.len());
+        for rule in file.rules {
+            for command_type in &rule.command_types {
+                if command_type != ANY && registry::find(command_type).is_none() {
+                    return Err(format!(
+                        "Unknown command type '{}' in permission rule for '{}'",
+                        command_type, rule.source_service
+                    )
+                    .into());
+                }
+            }
+            let targets = match &rule.targets {
+                Some(names) => {
+                    let mut nodes = Vec::with_capacity(names.len());
+                    for name in names {
+                        nodes.push(parse_node(name).ok_or_else(|| {
+                            format!(
+                                "Unknown target '{}' in permission rule for '{}'",
+                                name, rule.source_service
+                            )
+                        })?);
+                    }
+                    Some(nodes)
+                }
+                None => None,
+            };
+            rules.push((rule, targets));
+        }
+        info!(
+            "Loaded {} command permission rule(s) from {}",
+            rules.len(),
+            path.display()
+        );
+        Ok(Self { rules })
+    }
+
+    /// Checks a command against the first rule for its source and operator. `target` is the
+    /// node the command resolves to, `None` when its parameters do not parse.
+    pub fn check(&self, command: &OutgoingCommandRow, target: Option<Node>) -> Result<(), String> {
+        let rule = self.rules.iter().find(|(rule, _)| {
+            (rule.source_service == ANY || rule.source_service == command.source_service)
+                && match rule.operator.as_deref() {
+                    None | Some(ANY) => true,
+                    Some(operator) => command.operator.as_deref() == Some(operator),
+                }
+        });
+        let Some((rule, targets)) = rule else {
+            return Err(format!(
+                "No permission rule for source '{}' (operator {:?})",
+                command.source_service, command.operator
+            ));
+        };
+        if !rule
+            .command_types
+            .iter()
+            .any(|t| t == ANY || *t == command.command_type)
+        {
+            return Err(format!(
+                "Source '{}' (operator {:?}) may not send {}",
+                command.source_service, command.operator, command.command_type
+            ));
+        }
+        if let (Some(targets), Some(target)) = (targets, target) {
+            if !targets.contains(&target) {
+                return Err(format!(
+                    "Source '{}' (operator {:?}) may not send {} to {:?}",
+                    command.source_service, command.operator, command.command_type, target
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Node a command is or would be sent to, if its parameters are valid. Invalid ones are
+/// left for the dispatch step to fail with the validation error.
+pub fn intended_target(
+    command_type: &str,
+    parameters: Option<&str>,
+    policy: &CommandPolicy,
+) -> Option<Node> {
+    let validated = registry::validate(command_type, parameters).ok()?;
+    resolve_target(
+        validated.target,
+        policy.default_target.as_deref(),
+        validated.implied_target,
+    )
+    .ok()
+}
+
+/// Marks a command that is still 'Pending' as 'Rejected'.
+pub async fn reject(db_conn: &Connection, cmd_id: i64, reason: &str) -> Result<(), libsql::Error> {
+    warn!("[Cmd ID: {}] Rejected: {}", cmd_id, reason);
+    let updated = db_conn
+        .execute(
+            "UPDATE OutgoingCommand SET status = 'Rejected', error_message = ? WHERE id = ? AND status = 'Pending'",
+            libsql_params![reason, cmd_id],
+        )
+        .await?;
+    if updated > 0 {
+        events::record(
+            db_conn,
+            cmd_id,
+            "Pending",
+            "Rejected",
+            EventDetail {
+                error: Some(reason),
+                ..EventDetail::default()
+            },
+        )
+        .await;
+    }
+    Ok(())
+}
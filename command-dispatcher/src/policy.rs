@@ -0,0 +1,227 @@
+use crate::registry::parse_node;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use tracing::info;
+
+/// What the dispatcher accepts as proof that a node received a command.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AckEvidence {
+    /// No evidence is expected; the command stays 'Sent'.
+    None,
+    /// A `Pong` with the same id as the `Ping`.
+    Pong,
+    /// The same command type echoed back by the node it was sent to.
+    Echo,
+    /// An echo, or one of the policy's `ack_events` reported by the node it was sent to.
+    EchoOrEvent,
+}
+
+// Per-command-type settings. Fields missing from the policy file keep their defaults.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CommandPolicy {
+    /// Sends allowed per command, counting retransmissions after a write failure or a
+    /// missing acknowledgement.
+    pub max_attempts: u32,
+    pub ack_timeout_secs: i64,
+    pub ack: AckEvidence,
+    /// `PhoenixEvent` names (case-insensitive) that acknowledge the command with
+    /// `ack: echo_or_event`.
+    pub ack_events: Vec<String>,
+    /// Whether a command that may already have reached the radio may be sent again: a row
+    /// found stuck in 'Sending' after a restart or gateway drop, or a 'Sent' one whose
+    /// acknowledgement timed out. This must stay off for anything that is unsafe to fire
+    /// twice.
+    pub resend_on_recovery: bool,
+    /// Node a command is addressed to when its parameters carry no `target`. `None` means
+    /// the command's own implied node (the board of a PowerDown).
+    pub default_target: Option<String>,
+    /// Block the command unless SystemConfig 'command_armed' is on.
+    pub require_armed: bool,
+    /// Hold the command until a second operator confirms it.
+    pub require_confirmation: bool,
+    pub confirmation_window_secs: i64,
+    /// Only send while the latest PhoenixState is one of these (case-insensitive).
+    pub allowed_phoenix_states: Option<Vec<String>>,
+    /// How old the PhoenixState used for `allowed_phoenix_states` may be.
+    pub max_state_age_secs: i64,
+    /// Higher goes first. Used when the row's own `priority` is NULL.
+    pub priority: i64,
+    /// Expire a 'Pending' command this long after it was created when the row has no
+    /// `expires_at` of its own. `None` lets it wait indefinitely.
+    pub max_queue_age_secs: Option<i64>,
+    /// With half-duplex transmit scheduling, the longest a frame is held waiting for a gap
+    /// in the downlink before it is sent anyway.
+    pub max_transmit_delay_ms: u64,
+}
+
+impl Default for CommandPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            ack_timeout_secs: 5,
+            ack: AckEvidence::None,
+            ack_events: Vec::new(),
+            resend_on_recovery: true,
+            default_target: Some("PressureBoard".to_string()),
+            require_armed: false,
+            require_confirmation: false,
+            confirmation_window_secs: 60,
+            allowed_phoenix_states: None,
+            max_state_age_secs: 10,
+            priority: 0,
+            max_queue_age_secs: None,
+            max_transmit_delay_ms: 500,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CommandPolicies {
+    by_type: HashMap<String, CommandPolicy>,
+    fallback: CommandPolicy,
+}
+
+impl Default for CommandPolicies {
+    fn default() -> Self {
+        let mut by_type = HashMap::new();
+        // Commands that set a state the node reports back; resending them is harmless.
+        for idempotent in ["Online", "PowerUpCamera", "PowerDownCamera"] {
+            by_type.insert(
+                idempotent.to_string(),
+                CommandPolicy {
+                    ack: AckEvidence::Echo,
+                    ..CommandPolicy::default()
+                },
+            );
+        }
+        // After a rate change the two ends may not hear each other until both have switched,
+        // so an echo cannot be relied on.
+        by_type.insert(
+            "RadioRateChange".to_string(),
+            CommandPolicy {
+                max_attempts: 1,
+                ack: AckEvidence::None,
+                ..CommandPolicy::default()
+            },
+        );
+        // Answers a node's Ping, which expects nothing back.
+        by_type.insert(
+            "Pong".to_string(),
+            CommandPolicy {
+                max_attempts: 1,
+                ack: AckEvidence::None,
+                ..CommandPolicy::default()
+            },
+        );
+        by_type.insert(
+            "Ping".to_string(),
+            CommandPolicy {
+                max_attempts: 1,
+                ack_timeout_secs: 10,
+                ack: AckEvidence::Pong,
+                // Heartbeats must never hold up anything else, and a stale one is useless.
+                priority: -10,
+                max_queue_age_secs: Some(30),
+                max_transmit_delay_ms: 2000,
+                ..CommandPolicy::default()
+            },
+        );
+        by_type.insert(
+            "PowerDown".to_string(),
+            CommandPolicy {
+                // A board that powered down cannot answer.
+                max_attempts: 1,
+                ack: AckEvidence::None,
+                default_target: None,
+                require_armed: true,
+                require_confirmation: true,
+                priority: 50,
+                // A PowerDown that may already have gone out needs a fresh decision.
+                resend_on_recovery: false,
+                ..CommandPolicy::default()
+            },
+        );
+        for (pyro, event) in [
+            ("DeployDrogue", "DrogueDeployed"),
+            ("DeployMain", "MainDeployed"),
+        ] {
+            by_type.insert(
+                pyro.to_string(),
+                CommandPolicy {
+                    // A deploy goes out once; a missing ack is for the operators to judge.
+                    max_attempts: 1,
+                    ack: AckEvidence::EchoOrEvent,
+                    ack_events: vec![event.to_string()],
+                    resend_on_recovery: false,
+                    default_target: Some("Phoenix".to_string()),
+                    require_armed: true,
+                    require_confirmation: true,
+                    priority: 100,
+                    max_queue_age_secs: Some(120),
+                    max_transmit_delay_ms: 100,
+                    ..CommandPolicy::default()
+                },
+            );
+        }
+        Self {
+            by_type,
+            fallback: CommandPolicy::default(),
+        }
+    }
+}
+
+impl CommandPolicies {
+    /// Loads the built-in defaults, overridden by entries of a JSON file mapping
+    /// command type to policy, e.g. `{"Ping": {"max_attempts": 2}}`.
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut policies = Self::default();
+        if let Some(path) = path {
+            let text = std::fs::read_to_string(path)?;
+            let overrides: HashMap<String, serde_json::Value> = serde_json::from_str(&text)?;
+            for (command_type, value) in overrides {
+                // Merge over the existing entry so a file only needs the fields it changes.
+                let mut merged = serde_json::to_value(policies.get(&command_type))?;
+                if let (Some(base), serde_json::Value::Object(fields)) =
+                    (merged.as_object_mut(), value)
+                {
+                    base.extend(fields);
+                }
+                let policy: CommandPolicy = serde_json::from_value(merged)?;
+                if policy.ack == AckEvidence::EchoOrEvent && policy.ack_events.is_empty() {
+                    return Err(format!(
+                        "{} acknowledges on events but lists no ack_events",
+                        command_type
+                    )
+                    .into());
+                }
+                if let Some(target) = &policy.default_target {
+                    if parse_node(target).is_none() {
+                        return Err(format!(
+                            "Unknown default_target '{}' for {}",
+                            target, command_type
+                        )
+                        .into());
+                    }
+                }
+                policies.by_type.insert(command_type, policy);
+            }
+            info!("Loaded command policies from {}", path.display());
+        }
+        Ok(policies)
+    }
+
+    pub fn get(&self, command_type: &str) -> &CommandPolicy {
+        self.by_type.get(command_type).unwrap_or(&self.fallback)
+    }
+
+    /// Command types with their own policy, followed by the policy used for everything else.
+    pub fn entries(&self) -> (impl Iterator<Item = (&str, &CommandPolicy)>, &CommandPolicy) {
+        (
+            self.by_type.iter().map(|(k, v)| (k.as_str(), v)),
+            &self.fallback,
+        )
+    }
+}
@@ -0,0 +1,111 @@
+use crate::commands::OutgoingCommandRow;
+use crate::events::{self, EventDetail};
+use crate::policy::CommandPolicies;
+use chrono::Utc;
+use libsql::{params as libsql_params, params::params_from_iter, Connection, Value};
+use tracing::warn;
+
+/// Fetches up to `limit` 'Pending' commands, highest priority first and oldest first within
+/// a priority. A NULL `priority` column falls back to the command type's policy.
+pub async fn fetch_pending(
+    db_conn: &Connection,
+    policies: &CommandPolicies,
+    limit: i64,
+) -> Result<Vec<OutgoingCommandRow>, libsql::Error> {
+    let (entries, fallback) = policies.entries();
+    let mut params: Vec<Value> = Vec::new();
+    let mut when_clauses = String::new();
+    for (command_type, policy) in entries {
+        when_clauses.push_str(" WHEN ? THEN ?");
+        params.push(command_type.into());
+        params.push(policy.priority.into());
+    }
+    let default_priority = if when_clauses.is_empty() {
+        "?".to_string()
+    } else {
+        format!("CASE command_type{} ELSE ? END", when_clauses)
+    };
+    params.push(fallback.priority.into());
+    params.push(limit.into());
+
+    let sql = format!(
+        "SELECT id, command_type, parameters, source_service, operator, confirmation_token \
+         FROM OutgoingCommand WHERE status = 'Pending' \
+         ORDER BY COALESCE(priority, {}) DESC, created_at ASC, id ASC LIMIT ?",
+        default_priority
+    );
+    let mut rows = db_conn.query(&sql, params_from_iter(params)).await?;
+    let mut commands = Vec::new();
+    while let Some(row) = rows.next().await? {
+        commands.push(OutgoingCommandRow {
+            id: row.get(0)?,
+            command_type: row.get(1)?,
+            parameters: row.get(2)?,
+            source_service: row.get(3)?,
+            operator: row.get(4)?,
+            confirmation_token: row.get(5)?,
+        });
+    }
+    Ok(commands)
+}
+
+/// Moves 'Pending' and 'AwaitingConfirmation' commands past their deadline to 'Expired'.
+/// The deadline is the row's `expires_at`, else `created_at` plus the policy's
+/// `max_queue_age_secs`.
+pub async fn expire_overdue_commands(
+    db_conn: &Connection,
+    policies: &CommandPolicies,
+) -> Result<(), libsql::Error> {
+    let mut rows = db_conn
+        .query(
+            "SELECT id, command_type, created_at, expires_at, status FROM OutgoingCommand \
+             WHERE status IN ('Pending', 'AwaitingConfirmation')",
+            libsql_params![],
+        )
+        .await?;
+    let mut overdue = Vec::new();
+    let now = Utc::now().timestamp();
+    while let Some(row) = rows.next().await? {
+        let id: i64 = row.get(0)?;
+        let command_type: String = row.get(1)?;
+        let created_at: i64 = row.get(2)?;
+        let expires_at: Option<i64> = row.get(3)?;
+        let status: String = row.get(4)?;
+        let deadline = expires_at.or_else(|| {
+            policies
+                .get(&command_type)
+                .max_queue_age_secs
+                .map(|age| created_at + age)
+        });
+        if let Some(deadline) = deadline {
+            if now >= deadline {
+                overdue.push((id, status, now - created_at));
+            }
+        }
+    }
+
+    for (id, status, waited_secs) in overdue {
+        let err_msg = format!("Expired after waiting {}s without being sent", waited_secs);
+        warn!("[Cmd ID: {}] {}", id, err_msg);
+        let updated = db_conn
+            .execute(
+                "UPDATE OutgoingCommand SET status = 'Expired', error_message = ? WHERE id = ? AND status = ?",
+                libsql_params![err_msg.clone(), id, status.clone()],
+            )
+            .await?;
+        if updated > 0 {
+            events::record(
+                db_conn,
+                id,
+                &status,
+                "Expired",
+                EventDetail {
+                    error: Some(&err_msg),
+                    ..EventDetail::default()
+                },
+            )
+            .await;
+        }
+    }
+    Ok(())
+}
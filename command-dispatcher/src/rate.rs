@@ -0,0 +1,559 @@
+use crate::registry::{self, ValidatedCommand};
+use chrono::Utc;
+use libsql::{params as libsql_params, Connection};
+use messages_prost::command as cmd;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+/// `source_service` of the `RadioRateChange` commands queued by the controller.
+const RATE_SOURCE: &str = "command-dispatcher-rate-adaptation";
+
+/// `SystemConfig` key that, while 'true', stops the controller from changing the rate.
+pub const MANUAL_LOCK_CONFIG_KEY: &str = "radio_rate_manual_lock";
+
+// Thresholds of the rate controller. Fields missing from the config file keep their defaults.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RateAdaptationConfig {
+    /// How often link quality is evaluated.
+    pub evaluation_interval_secs: u64,
+    /// Link quality is averaged over this many seconds of `RadioMetrics` and `RadioFrame`.
+    pub window_secs: i64,
+    /// Step down one rate when the loss ratio rises above this...
+    pub step_down_loss_ratio: f64,
+    /// ...or the average RSSI falls below this.
+    pub step_down_rssi: f64,
+    /// Step up one rate only when the loss ratio is below this...
+    pub step_up_loss_ratio: f64,
+    /// ...and the average RSSI is above this.
+    pub step_up_rssi: f64,
+    /// Minimum time between two rate changes, whoever issued them.
+    pub min_dwell_secs: i64,
+    /// Frames plus lost packets needed in the window before the loss ratio is trusted.
+    pub min_samples: i64,
+}
+
+impl Default for RateAdaptationConfig {
+    fn default() -> Self {
+        Self {
+            evaluation_interval_secs: 5,
+            window_secs: 10,
+            step_down_loss_ratio: 0.2,
+            step_down_rssi: 60.0,
+            step_up_loss_ratio: 0.05,
+            step_up_rssi: 90.0,
+            min_dwell_secs: 30,
+            min_samples: 10,
+        }
+    }
+}
+
+impl RateAdaptationConfig {
+    /// Loads the defaults, overridden by the fields present in a JSON file.
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let config: Self = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        if config.step_up_loss_ratio >= config.step_down_loss_ratio
+            || config.step_up_rssi <= config.step_down_rssi
+        {
+            return Err(
+                "Rate adaptation step-up thresholds must be stricter than step-down thresholds"
+                    .into(),
+            );
+        }
+        info!("Loaded rate adaptation config from {}", path.display());
+        Ok(config)
+    }
+}
+
+#[derive(Debug)]
+struct LinkQuality {
+    avg_rssi: Option<f64>,
+    loss_ratio: Option<f64>,
+}
+
+/// Watches link quality and queues `RadioRateChange` commands: one rate lower when the link
+/// degrades, one rate higher when it is clearly good, never within `min_dwell_secs` of the
+/// last change, never while a change is in flight, and never while the manual lock is set.
+/// Every change it makes or holds back is written to `RadioRateDecision`.
+pub struct RateController {
+    config: RateAdaptationConfig,
+    last_evaluated: Option<Instant>,
+}
+
+impl RateController {
+    pub fn new(config: RateAdaptationConfig) -> Self {
+        Self {
+            config,
+            last_evaluated: None,
+        }
+    }
+
+    /// Evaluates the link if `evaluation_interval_secs` has passed since the last time.
+    pub async fn tick(&mut self, db_conn: &Connection) -> Result<(), libsql::Error> {
+        let interval = Duration::from_secs(self.config.evaluation_interval_secs);
+        if self
+            .last_evaluated
+            .is_some_and(|at| at.elapsed() < interval)
+        {
+            return Ok(());
+        }
+        self.last_evaluated = Some(Instant::now());
+        self.evaluate(db_conn).await
+    }
+
+    async fn evaluate(&self, db_conn: &Connection) -> Result<(), libsql::Error> {
+        let now = Utc::now().timestamp();
+        let quality = link_quality(
+            db_conn,
+            now - self.config.window_secs,
+            self.config.min_samples,
+        )
+        .await?;
+        let Some(current) = current_rate(db_conn).await? else {
+            debug!(
+                "Rate adaptation: current rate unknown, waiting for a RadioRateChange to go out"
+            );
+            return Ok(());
+        };
+
+        let degraded = quality
+            .loss_ratio
+            .is_some_and(|loss| loss > self.config.step_down_loss_ratio)
+            || quality
+                .avg_rssi
+                .is_some_and(|rssi| rssi < self.config.step_down_rssi);
+        let healthy = quality
+            .loss_ratio
+            .is_some_and(|loss| loss < self.config.step_up_loss_ratio)
+            && quality
+                .avg_rssi
+                .is_some_and(|rssi| rssi > self.config.step_up_rssi);
+
+        let current_level = current as i32;
+        let (wanted, reason) = if degraded && current_level > cmd::RadioRate::RateLow as i32 {
+            (current_level - 1, "link degraded")
+        } else if healthy && current_level < cmd::RadioRate::RateHigh as i32 {
+            (current_level + 1, "link healthy")
+        } else {
+            debug!("Rate adaptation: holding {:?} ({:?})", current, quality);
+            return Ok(());
+        };
+        let wanted = cmd::RadioRate::try_from(wanted).unwrap_or(cmd::RadioRate::RateLow);
+
+        let suppressed = if is_locked(db_conn).await? {
+            Some(format!("manual lock '{}' is set", MANUAL_LOCK_CONFIG_KEY))
+        } else if change_in_flight(db_conn).await? {
+            Some("a RadioRateChange is still in flight".to_string())
+        } else {
+            match last_change_at(db_conn).await? {
+                Some(at) if now - at < self.config.min_dwell_secs => Some(format!(
+                    "last change {}s ago, minimum dwell {}s",
+                    now - at,
+                    self.config.min_dwell_secs
+                )),
+                _ => None,
+            }
+        };
+
+        let command_id = match &suppressed {
+            Some(why) => {
+                info!(
+                    "Rate adaptation: would change {:?} -> {:?} ({}), held back: {}",
+                    current, wanted, reason, why
+                );
+                None
+            }
+            None => {
+                let id = enqueue_rate_change(db_conn, wanted, now).await?;
+                info!(
+                    "Rate adaptation: {:?} -> {:?} ({}; rssi {:?}, loss {:?}). Queued Cmd ID {:?}.",
+                    current, wanted, reason, quality.avg_rssi, quality.loss_ratio, id
+                );
+                id
+            }
+        };
+
+        db_conn
+            .execute(
+                "INSERT INTO RadioRateDecision (decided_at, avg_rssi, loss_ratio, current_rate, wanted_rate, reason, suppressed_reason, command_id) \
+                 VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+                libsql_params![
+                    now,
+                    quality.avg_rssi,
+                    quality.loss_ratio,
+                    rate_name(current),
+                    rate_name(wanted),
+                    reason,
+                    suppressed,
+                    command_id
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+async fn link_quality(
+    db_conn: &Connection,
+    since: i64,
+    min_samples: i64,
+) -> Result<LinkQuality, libsql::Error> {
+    // With several gateways, the link is as good as the best of them.
+    let mut rows = db_conn
+        .query(
+            "SELECT MAX(avg_rssi), COALESCE(MIN(lost), 0) FROM ( \
+             SELECT AVG(rssi) AS avg_rssi, COALESCE(SUM(packets_lost), 0) AS lost \
+             FROM RadioMetrics WHERE timestamp >= ? GROUP BY gateway)",
+            libsql_params![since],
+        )
+        .await?;
+    let (avg_rssi, lost): (Option<f64>, i64) = match rows.next().await? {
+        Some(row) => (row.get(0)?, row.get(1)?),
+        None => (None, 0),
+    };
+    drop(rows);
+
+    // Our own uplink frames are stored too; only count what came down the link.
+    let mut rows = db_conn
+        .query(
+            "SELECT COUNT(*) FROM RadioFrame WHERE timestamp_epoch >= ? AND node != 'GroundStation'",
+            libsql_params![since],
+        )
+        .await?;
+    let received: i64 = match rows.next().await? {
+        Some(row) => row.get(0)?,
+        None => 0,
+    };
+
+    let samples = received + lost;
+    Ok(LinkQuality {
+        avg_rssi,
+        loss_ratio: (samples >= min_samples).then(|| lost as f64 / samples as f64),
+    })
+}
+
+// Rate of the latest RadioRateChange that went out, from any source. `RadioRateChange` expects
+// no acknowledgement by default, so 'Sent' counts as applied. Unknown when none went out, or
+// when the latest one timed out waiting for an acknowledgement and may not have arrived.
+async fn current_rate(db_conn: &Connection) -> Result<Option<cmd::RadioRate>, libsql::Error> {
+    let mut rows = db_conn
+        .query(
+            "SELECT parameters, status FROM OutgoingCommand WHERE command_type = 'RadioRateChange' \
+             AND status IN ('Sent', 'Acknowledged', 'TimedOut') ORDER BY sent_at DESC, id DESC LIMIT 1",
+            libsql_params![],
+        )
+        .await?;
+    let Some(row) = rows.next().await? else {
+        return Ok(None);
+    };
+    let parameters: Option<String> = row.get(0)?;
+    let status: String = row.get(1)?;
+    if status == "TimedOut" {
+        return Ok(None);
+    }
+    Ok(
+        match registry::validate("RadioRateChange", parameters.as_deref()) {
+            Ok(ValidatedCommand {
+                data: cmd::command::Data::RadioRateChange(change),
+                ..
+            }) => cmd::RadioRate::try_from(change.rate).ok(),
+            _ => None,
+        },
+    )
+}
+
+async fn is_locked(db_conn: &Connection) -> Result<bool, libsql::Error> {
+    let mut rows = db_conn
+        .query(
+            "SELECT value FROM SystemConfig WHERE key = ?",
+            libsql_params![MANUAL_LOCK_CONFIG_KEY],
+        )
+        .await?;
+    let Some(row) = rows.next().await? else {
+        return Ok(false);
+    };
+    let value: String = row.get(0)?;
+    Ok(matches!(
+        value.trim().to_lowercase().as_str(),
+        "true" | "1" | "locked"
+    ))
+}
+
+// Not yet sent. A 'Sent' change counts as applied, like in `current_rate`.
+async fn change_in_flight(db_conn: &Connection) -> Result<bool, libsql::Error> {
+    let mut rows = db_conn
+        .query(
+            "SELECT 1 FROM OutgoingCommand WHERE command_type = 'RadioRateChange' \
+             AND status IN ('Pending', 'AwaitingConfirmation', 'Sending') LIMIT 1",
+            libsql_params![],
+        )
+        .await?;
+    Ok(rows.next().await?.is_some())
+}
+
+async fn last_change_at(db_conn: &Connection) -> Result<Option<i64>, libsql::Error> {
+    let mut rows = db_conn
+        .query(
+            "SELECT MAX(created_at) FROM OutgoingCommand WHERE command_type = 'RadioRateChange'",
+            libsql_params![],
+        )
+        .await?;
+    match rows.next().await? {
+        Some(row) => row.get(0),
+        None => Ok(None),
+    }
+}
+
+async fn enqueue_rate_change(
+    db_conn: &Connection,
+    rate: cmd::RadioRate,
+    now: i64,
+) -> Result<Option<i64>, libsql::Error> {
+    let mut rows = db_conn
+        .query(
+            "INSERT INTO OutgoingCommand (command_type, parameters, status, created_at, attempts, source_service) \
+             VALUES ('RadioRateChange', ?, 'Pending', ?, 0, ?) RETURNING id",
+            libsql_params![
+                serde_json::json!({ "rate": rate_name(rate) }).to_string(),
+                now,
+                RATE_SOURCE
+            ],
+        )
+        .await?;
+    match rows.next().await? {
+        Some(row) => Ok(Some(row.get(0)?)),
+        None => Ok(None),
+    }
+}
+
+// Parameter spelling of a rate, as in the command's JSON Schema
+fn rate_name(rate: cmd::RadioRate) -> &'static str {
+    match rate {
+        cmd::RadioRate::RateLow => "low",
+        cmd::RadioRate::RateMedium => "medium",
+        cmd::RadioRate::RateHigh => "high",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::database;
+
+    const GOOD_RSSI: i64 = 120;
+    const MIDDLING_RSSI: i64 = 75;
+    const BAD_RSSI: i64 = 40;
+
+    async fn rate_change(db_conn: &Connection, rate: &str, status: &str, at: i64) {
+        db_conn
+            .execute(
+                "INSERT INTO OutgoingCommand (command_type, parameters, status, created_at, sent_at, source_service) \
+                 VALUES ('RadioRateChange', ?, ?, ?, ?, 'ops')",
+                libsql_params![
+                    serde_json::json!({ "rate": rate }).to_string(),
+                    status,
+                    at,
+                    at
+                ],
+            )
+            .await
+            .unwrap();
+    }
+
+    // `frames` received and `lost` lost over the last few seconds, at `rssi`
+    async fn link(db_conn: &Connection, rssi: i64, frames: i64, lost: i64) {
+        let now = Utc::now().timestamp();
+        db_conn
+            .execute(
+                "INSERT INTO RadioMetrics (timestamp, rssi, packets_lost, gateway) VALUES (?, ?, ?, 'tcpout:gw:5656')",
+                libsql_params![now - 1, rssi, lost],
+            )
+            .await
+            .unwrap();
+        for _ in 0..frames {
+            db_conn
+                .execute(
+                    "INSERT INTO RadioFrame (timestamp, timestamp_epoch, node, data_type, data_id) \
+                     VALUES ('', ?, 'Phoenix', 'Log', 0)",
+                    libsql_params![now - 1],
+                )
+                .await
+                .unwrap();
+        }
+    }
+
+    // The rate of every change the controller queued, oldest first
+    async fn queued(db_conn: &Connection) -> Vec<String> {
+        let mut rows = db_conn
+            .query(
+                "SELECT parameters FROM OutgoingCommand WHERE source_service = ? ORDER BY id",
+                libsql_params![RATE_SOURCE],
+            )
+            .await
+            .unwrap();
+        let mut rates = Vec::new();
+        while let Some(row) = rows.next().await.unwrap() {
+            let parameters: String = row.get(0).unwrap();
+            let parameters: serde_json::Value = serde_json::from_str(&parameters).unwrap();
+            rates.push(parameters["rate"].as_str().unwrap().to_string());
+        }
+        rates
+    }
+
+    async fn suppressed_reasons(db_conn: &Connection) -> Vec<Option<String>> {
+        let mut rows = db_conn
+            .query(
+                "SELECT suppressed_reason FROM RadioRateDecision ORDER BY id",
+                libsql_params![],
+            )
+            .await
+            .unwrap();
+        let mut reasons = Vec::new();
+        while let Some(row) = rows.next().await.unwrap() {
+            reasons.push(row.get(0).unwrap());
+        }
+        reasons
+    }
+
+    fn controller() -> RateController {
+        RateController::new(RateAdaptationConfig::default())
+    }
+
+    fn long_ago() -> i64 {
+        Utc::now().timestamp() - 3600
+    }
+
+    #[tokio::test]
+    async fn a_degraded_link_steps_down_from_the_last_sent_rate() {
+        let db_conn = database().await;
+        rate_change(&db_conn, "medium", "Sent", long_ago()).await;
+        link(&db_conn, BAD_RSSI, 20, 0).await;
+        controller().evaluate(&db_conn).await.unwrap();
+        assert_eq!(queued(&db_conn).await, ["low"]);
+    }
+
+    #[tokio::test]
+    async fn loss_alone_steps_down() {
+        let db_conn = database().await;
+        rate_change(&db_conn, "high", "Sent", long_ago()).await;
+        link(&db_conn, GOOD_RSSI, 10, 10).await;
+        controller().evaluate(&db_conn).await.unwrap();
+        assert_eq!(queued(&db_conn).await, ["medium"]);
+    }
+
+    #[tokio::test]
+    async fn a_healthy_link_steps_up() {
+        let db_conn = database().await;
+        rate_change(&db_conn, "low", "Acknowledged", long_ago()).await;
+        link(&db_conn, GOOD_RSSI, 40, 0).await;
+        controller().evaluate(&db_conn).await.unwrap();
+        assert_eq!(queued(&db_conn).await, ["medium"]);
+    }
+
+    #[tokio::test]
+    async fn between_the_thresholds_the_rate_is_held() {
+        let db_conn = database().await;
+        rate_change(&db_conn, "medium", "Sent", long_ago()).await;
+        // Neither degraded nor clearly healthy
+        link(&db_conn, MIDDLING_RSSI, 40, 0).await;
+        controller().evaluate(&db_conn).await.unwrap();
+        assert!(queued(&db_conn).await.is_empty());
+        assert!(suppressed_reasons(&db_conn).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn no_step_beyond_the_lowest_or_highest_rate() {
+        let db_conn = database().await;
+        rate_change(&db_conn, "low", "Sent", long_ago()).await;
+        link(&db_conn, BAD_RSSI, 20, 0).await;
+        controller().evaluate(&db_conn).await.unwrap();
+
+        let db_conn_high = database().await;
+        rate_change(&db_conn_high, "high", "Sent", long_ago()).await;
+        link(&db_conn_high, GOOD_RSSI, 40, 0).await;
+        controller().evaluate(&db_conn_high).await.unwrap();
+
+        assert!(queued(&db_conn).await.is_empty());
+        assert!(queued(&db_conn_high).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn an_unknown_rate_is_not_changed() {
+        let db_conn = database().await;
+        link(&db_conn, BAD_RSSI, 20, 0).await;
+        controller().evaluate(&db_conn).await.unwrap();
+
+        rate_change(&db_conn, "medium", "TimedOut", long_ago()).await;
+        controller().evaluate(&db_conn).await.unwrap();
+
+        assert!(queued(&db_conn).await.is_empty());
+        assert!(suppressed_reasons(&db_conn).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn the_manual_lock_holds_a_change_back() {
+        let db_conn = database().await;
+        rate_change(&db_conn, "medium", "Sent", long_ago()).await;
+        db_conn
+            .execute(
+                "INSERT INTO SystemConfig (key, value, updated_at) VALUES (?, 'true', 0)",
+                libsql_params![MANUAL_LOCK_CONFIG_KEY],
+            )
+            .await
+            .unwrap();
+        link(&db_conn, BAD_RSSI, 20, 0).await;
+        controller().evaluate(&db_conn).await.unwrap();
+        assert!(queued(&db_conn).await.is_empty());
+        let reasons = suppressed_reasons(&db_conn).await;
+        assert!(reasons[0].as_deref().unwrap().contains("manual lock"));
+    }
+
+    #[tokio::test]
+    async fn a_recent_change_holds_the_next_one_back() {
+        let db_conn = database().await;
+        rate_change(&db_conn, "medium", "Sent", Utc::now().timestamp() - 5).await;
+        link(&db_conn, BAD_RSSI, 20, 0).await;
+        controller().evaluate(&db_conn).await.unwrap();
+        assert!(queued(&db_conn).await.is_empty());
+        let reasons = suppressed_reasons(&db_conn).await;
+        assert!(reasons[0].as_deref().unwrap().contains("minimum dwell"));
+    }
+
+    #[tokio::test]
+    async fn an_unsent_change_holds_the_next_one_back() {
+        let db_conn = database().await;
+        rate_change(&db_conn, "high", "Sent", long_ago()).await;
+        rate_change(&db_conn, "medium", "Pending", long_ago()).await;
+        link(&db_conn, BAD_RSSI, 20, 0).await;
+        controller().evaluate(&db_conn).await.unwrap();
+        let reasons = suppressed_reasons(&db_conn).await;
+        assert!(reasons[0].as_deref().unwrap().contains("in flight"));
+    }
+
+    #[tokio::test]
+    async fn the_controller_keeps_adapting_after_its_own_change_is_sent() {
+        let db_conn = database().await;
+        rate_change(&db_conn, "high", "Sent", long_ago()).await;
+        link(&db_conn, BAD_RSSI, 20, 0).await;
+        let controller = RateController::new(RateAdaptationConfig {
+            min_dwell_secs: 0,
+            ..RateAdaptationConfig::default()
+        });
+        controller.evaluate(&db_conn).await.unwrap();
+        // As the dispatcher leaves a change that expects no acknowledgement
+        db_conn
+            .execute(
+                "UPDATE OutgoingCommand SET status = 'Sent', sent_at = ? WHERE source_service = ?",
+                libsql_params![Utc::now().timestamp(), RATE_SOURCE],
+            )
+            .await
+            .unwrap();
+        controller.evaluate(&db_conn).await.unwrap();
+        assert_eq!(queued(&db_conn).await, ["medium", "low"]);
+    }
+}
@@ -0,0 +1,133 @@
+use crate::events::{self, EventDetail};
+use crate::policy::CommandPolicies;
+use libsql::{params as libsql_params, Connection};
+use tracing::{info, warn};
+
+/// Resolves rows left in 'Sending' by a crash or a dropped gateway connection. Each row is
+/// re-queued as 'Pending' if the command type's `resend_on_recovery` policy allows it and
+/// attempts remain, otherwise it is marked 'Failed'. `reason` is recorded in `error_message`.
+pub async fn recover_stuck_commands(
+    db_conn: &Connection,
+    policies: &CommandPolicies,
+    reason: &str,
+) -> Result<(), libsql::Error> {
+    let mut rows = db_conn
+        .query(
+            "SELECT id, command_type, attempts FROM OutgoingCommand WHERE status = 'Sending'",
+            libsql_params![],
+        )
+        .await?;
+    let mut stuck: Vec<(i64, String, i64)> = Vec::new();
+    while let Some(row) = rows.next().await? {
+        stuck.push((row.get(0)?, row.get(1)?, row.get(2)?));
+    }
+
+    if stuck.is_empty() {
+        return Ok(());
+    }
+    info!(
+        "Found {} command(s) stuck in 'Sending' after {}. Reconciling...",
+        stuck.len(),
+        reason
+    );
+
+    for (cmd_id, command_type, attempts) in stuck {
+        let policy = policies.get(&command_type);
+        let (to_status, msg) = if !policy.resend_on_recovery {
+            (
+                "Failed",
+                format!(
+                    "Left in 'Sending' after {}; {} is never resent automatically, it may or may not have been transmitted",
+                    reason, command_type
+                ),
+            )
+        } else if attempts >= i64::from(policy.max_attempts) {
+            (
+                "Failed",
+                format!(
+                    "Left in 'Sending' after {}; all {} attempt(s) used, it may or may not have been transmitted",
+                    reason, policy.max_attempts
+                ),
+            )
+        } else {
+            (
+                "Pending",
+                format!("Left in 'Sending' after {}; re-queued", reason),
+            )
+        };
+        if to_status == "Pending" {
+            info!("[Cmd ID: {}] {}", cmd_id, msg);
+        } else {
+            warn!("[Cmd ID: {}] {}", cmd_id, msg);
+        }
+        let updated = db_conn
+            .execute(
+                "UPDATE OutgoingCommand SET status = ?, error_message = ? WHERE id = ? AND status = 'Sending'",
+                libsql_params![to_status, msg.clone(), cmd_id],
+            )
+            .await?;
+        if updated > 0 {
+            events::record(
+                db_conn,
+                cmd_id,
+                "Sending",
+                to_status,
+                EventDetail {
+                    error: Some(&msg),
+                    ..EventDetail::default()
+                },
+            )
+            .await;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::database;
+
+    async fn sending(db_conn: &Connection, command_type: &str, attempts: i64) -> i64 {
+        db_conn
+            .execute(
+                "INSERT INTO OutgoingCommand (command_type, status, created_at, attempts, source_service) \
+                 VALUES (?, 'Sending', 0, ?, 'ops')",
+                libsql_params![command_type, attempts],
+            )
+            .await
+            .unwrap();
+        db_conn.last_insert_rowid()
+    }
+
+    async fn status(db_conn: &Connection, id: i64) -> String {
+        let mut rows = db_conn
+            .query(
+                "SELECT status FROM OutgoingCommand WHERE id = ?",
+                libsql_params![id],
+            )
+            .await
+            .unwrap();
+        rows.next().await.unwrap().unwrap().get(0).unwrap()
+    }
+
+    #[tokio::test]
+    async fn requeues_only_resendable_commands_with_attempts_left() {
+        let db_conn = database().await;
+        let online = sending(&db_conn, "Online", 1).await;
+        let exhausted = sending(&db_conn, "Online", 3).await;
+        let ping = sending(&db_conn, "Ping", 1).await;
+        let power_down = sending(&db_conn, "PowerDown", 0).await;
+        let deploy = sending(&db_conn, "DeployMain", 0).await;
+
+        recover_stuck_commands(&db_conn, &CommandPolicies::default(), "a test")
+            .await
+            .unwrap();
+
+        assert_eq!(status(&db_conn, online).await, "Pending");
+        assert_eq!(status(&db_conn, exhausted).await, "Failed");
+        assert_eq!(status(&db_conn, ping).await, "Failed");
+        assert_eq!(status(&db_conn, power_down).await, "Failed");
+        assert_eq!(status(&db_conn, deploy).await, "Failed");
+    }
+}
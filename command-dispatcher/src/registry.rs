@@ -0,0 +1,701 @@
+use chrono::Utc;
+use libsql::{params as libsql_params, Connection};
+use messages_prost::command as cmd;
+use messages_prost::common::Node;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Why a command's `command_type` or `parameters` were rejected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError(pub String);
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamKind {
+    Bool,
+    U32,
+    /// A node name (case-insensitive) or its numeric enum value
+    Node,
+    /// `low`, `medium` or `high`
+    RadioRate,
+}
+
+#[derive(Debug)]
+pub struct ParamSpec {
+    pub name: &'static str,
+    pub kind: ParamKind,
+    pub required: bool,
+    pub description: &'static str,
+}
+
+#[derive(Debug)]
+pub struct CommandSpec {
+    pub command_type: &'static str,
+    pub description: &'static str,
+    pub params: &'static [ParamSpec],
+}
+
+/// Accepted by every command type on top of its own parameters.
+pub const TARGET_PARAM: ParamSpec = ParamSpec {
+    name: "target",
+    kind: ParamKind::Node,
+    required: false,
+    description:
+        "Node the command is addressed to. Defaults to the command type's configured target.",
+};
+
+/// Every command the dispatcher can send, one per `messages_prost::command` variant.
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        command_type: "Online",
+        description: "Set a node's online flag.",
+        params: &[ParamSpec {
+            name: "online",
+            kind: ParamKind::Bool,
+            required: true,
+            description: "New online state.",
+        }],
+    },
+    CommandSpec {
+        command_type: "DeployDrogue",
+        description: "Fire the drogue parachute pyro.",
+        params: &[ParamSpec {
+            name: "val",
+            kind: ParamKind::Bool,
+            required: true,
+            description: "Deploy when true.",
+        }],
+    },
+    CommandSpec {
+        command_type: "DeployMain",
+        description: "Fire the main parachute pyro.",
+        params: &[ParamSpec {
+            name: "val",
+            kind: ParamKind::Bool,
+            required: true,
+            description: "Deploy when true.",
+        }],
+    },
+    CommandSpec {
+        command_type: "PowerDown",
+        description: "Power down a board.",
+        params: &[ParamSpec {
+            name: "board",
+            kind: ParamKind::Node,
+            required: true,
+            description: "Board to power down. Also the default target.",
+        }],
+    },
+    CommandSpec {
+        command_type: "PowerUpCamera",
+        description: "Power up the camera.",
+        params: &[],
+    },
+    CommandSpec {
+        command_type: "PowerDownCamera",
+        description: "Power down the camera.",
+        params: &[],
+    },
+    CommandSpec {
+        command_type: "RadioRateChange",
+        description: "Change the radio data rate.",
+        params: &[ParamSpec {
+            name: "rate",
+            kind: ParamKind::RadioRate,
+            required: true,
+            description: "New radio rate.",
+        }],
+    },
+    CommandSpec {
+        command_type: "Ping",
+        description: "Request a Pong with the same id.",
+        params: &[ParamSpec {
+            name: "id",
+            kind: ParamKind::U32,
+            required: true,
+            description: "Correlation id echoed in the Pong.",
+        }],
+    },
+    CommandSpec {
+        command_type: "Pong",
+        description: "Answer a Ping.",
+        params: &[ParamSpec {
+            name: "id",
+            kind: ParamKind::U32,
+            required: true,
+            description: "Id of the Ping being answered.",
+        }],
+    },
+];
+
+pub fn find(command_type: &str) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|c| c.command_type == command_type)
+}
+
+/// Names accepted for a node, excluding `Unspecified`.
+const NODE_NAMES: &[&str] = &[
+    "PressureBoard",
+    "StrainBoard",
+    "TemperatureBoard",
+    "GroundStation",
+    "Phoenix",
+];
+
+/// Parses a node by name, ignoring case, `_` and `-` (e.g. `phoenix`, `pressure_board`),
+/// or by its numeric enum value.
+pub fn parse_node(s: &str) -> Option<Node> {
+    if let Ok(value) = s.trim().parse::<i32>() {
+        return Node::try_from(value).ok();
+    }
+    let normalized: String = s
+        .chars()
+        .filter(|c| !matches!(c, '_' | '-' | ' '))
+        .collect::<String>()
+        .to_lowercase();
+    match normalized.as_str() {
+        "unspecified" => Some(Node::Unspecified),
+        "pressureboard" => Some(Node::PressureBoard),
+        "strainboard" => Some(Node::StrainBoard),
+        "temperatureboard" => Some(Node::TemperatureBoard),
+        "groundstation" => Some(Node::GroundStation),
+        "phoenix" => Some(Node::Phoenix),
+        _ => None,
+    }
+}
+
+/// Names accepted for a radio rate, as listed in the schema.
+const RADIO_RATE_NAMES: &[&str] = &["low", "medium", "high"];
+
+pub fn parse_radio_rate(s: &str) -> Option<cmd::RadioRate> {
+    match s {
+        "low" => Some(cmd::RadioRate::RateLow),
+        "medium" => Some(cmd::RadioRate::RateMedium),
+        "high" => Some(cmd::RadioRate::RateHigh),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ParamValue {
+    Bool(bool),
+    U32(u32),
+    Node(Node),
+    RadioRate(cmd::RadioRate),
+}
+
+fn parse_value(
+    command_type: &str,
+    spec: &ParamSpec,
+    value: &Value,
+) -> Result<ParamValue, ValidationError> {
+    let invalid = |expected: &str| {
+        ValidationError(format!(
+            "{}: parameter '{}' must be {}, got {}",
+            command_type, spec.name, expected, value
+        ))
+    };
+    match spec.kind {
+        ParamKind::Bool => value
+            .as_bool()
+            .map(ParamValue::Bool)
+            .ok_or_else(|| invalid("a boolean")),
+        ParamKind::U32 => value
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .map(ParamValue::U32)
+            .ok_or_else(|| invalid("an unsigned 32-bit integer")),
+        ParamKind::Node => {
+            let node = match value {
+                Value::String(s) => parse_node(s),
+                Value::Number(n) => n
+                    .as_i64()
+                    .and_then(|v| i32::try_from(v).ok())
+                    .and_then(|v| Node::try_from(v).ok()),
+                _ => None,
+            };
+            match node {
+                Some(Node::Unspecified) | None => {
+                    Err(invalid(&format!("one of {}", NODE_NAMES.join(", "))))
+                }
+                Some(node) => Ok(ParamValue::Node(node)),
+            }
+        }
+        ParamKind::RadioRate => value
+            .as_str()
+            .and_then(parse_radio_rate)
+            .map(ParamValue::RadioRate)
+            .ok_or_else(|| invalid(&format!("one of {}", RADIO_RATE_NAMES.join(", ")))),
+    }
+}
+
+/// A command whose type and parameters passed validation.
+#[derive(Debug, Clone)]
+pub struct ValidatedCommand {
+    pub data: cmd::command::Data,
+    /// Explicit `target` parameter, if given
+    pub target: Option<Node>,
+    /// Node implied by the command itself (the board of a PowerDown)
+    pub implied_target: Option<Node>,
+}
+
+/// Strictly validates `parameters` (a JSON object, or absent) for `command_type` and builds
+/// the protobuf payload. Unknown types, unknown or missing parameters and out-of-range
+/// values are all rejected with a descriptive error.
+pub fn validate(
+    command_type: &str,
+    parameters: Option<&str>,
+) -> Result<ValidatedCommand, ValidationError> {
+    let spec = find(command_type)
+        .ok_or_else(|| ValidationError(format!("Unknown command type: {}", command_type)))?;
+
+    let object = match parameters.map(str::trim) {
+        None | Some("") => Map::new(),
+        Some(text) => match serde_json::from_str::<Value>(text) {
+            Ok(Value::Object(object)) => object,
+            Ok(Value::Null) => Map::new(),
+            Ok(other) => {
+                return Err(ValidationError(format!(
+                    "{}: parameters must be a JSON object, got {}",
+                    command_type, other
+                )))
+            }
+            Err(e) => {
+                return Err(ValidationError(format!(
+                    "{}: parameters are not valid JSON: {}",
+                    command_type, e
+                )))
+            }
+        },
+    };
+
+    let all_params = || spec.params.iter().chain(std::iter::once(&TARGET_PARAM));
+    for key in object.keys() {
+        if !all_params().any(|p| p.name == key) {
+            let expected: Vec<&str> = all_params().map(|p| p.name).collect();
+            return Err(ValidationError(format!(
+                "{}: unknown parameter '{}' (expected: {})",
+                command_type,
+                key,
+                expected.join(", ")
+            )));
+        }
+    }
+
+    let mut values: HashMap<&'static str, ParamValue> = HashMap::new();
+    for param in all_params() {
+        match object.get(param.name).filter(|v| !v.is_null()) {
+            Some(value) => {
+                values.insert(param.name, parse_value(command_type, param, value)?);
+            }
+            None if param.required => {
+                return Err(ValidationError(format!(
+                    "{}: missing required parameter '{}'",
+                    command_type, param.name
+                )))
+            }
+            None => {}
+        }
+    }
+
+    let bool_param = |name| match values.get(name) {
+        Some(ParamValue::Bool(v)) => *v,
+        _ => false,
+    };
+    let u32_param = |name| match values.get(name) {
+        Some(ParamValue::U32(v)) => *v,
+        _ => 0,
+    };
+    let node_param = |name| match values.get(name) {
+        Some(ParamValue::Node(v)) => Some(*v),
+        _ => None,
+    };
+
+    let mut implied_target = None;
+    let data = match spec.command_type {
+        "Online" => cmd::command::Data::Online(cmd::Online {
+            online: bool_param("online"),
+        }),
+        "DeployDrogue" => cmd::command::Data::DeployDrogue(cmd::DeployDrogue {
+            val: bool_param("val"),
+        }),
+        "DeployMain" => cmd::command::Data::DeployMain(cmd::DeployMain {
+            val: bool_param("val"),
+        }),
+        "PowerDown" => {
+            let board = node_param("board").unwrap_or(Node::Unspecified);
+            implied_target = Some(board);
+            cmd::command::Data::PowerDown(cmd::PowerDown {
+                board: board as i32,
+            })
+        }
+        "PowerUpCamera" => cmd::command::Data::PowerUpCamera(cmd::PowerUpCamera {}),
+        "PowerDownCamera" => cmd::command::Data::PowerDownCamera(cmd::PowerDownCamera {}),
+        "RadioRateChange" => {
+            let rate = match values.get("rate") {
+                Some(ParamValue::RadioRate(rate)) => *rate,
+                _ => cmd::RadioRate::RateLow,
+            };
+            cmd::command::Data::RadioRateChange(cmd::RadioRateChange { rate: rate as i32 })
+        }
+        "Ping" => cmd::command::Data::Ping(cmd::Ping {
+            id: u32_param("id"),
+        }),
+        "Pong" => cmd::command::Data::Pong(cmd::Pong {
+            id: u32_param("id"),
+        }),
+        other => unreachable!("command type {} is registered but not built", other),
+    };
+
+    Ok(ValidatedCommand {
+        data,
+        target: node_param(TARGET_PARAM.name),
+        implied_target,
+    })
+}
+
+fn param_schema(param: &ParamSpec) -> Value {
+    let mut schema = match param.kind {
+        ParamKind::Bool => json!({ "type": "boolean" }),
+        ParamKind::U32 => json!({ "type": "integer", "minimum": 0, "maximum": u32::MAX }),
+        ParamKind::Node => json!({
+            "oneOf": [
+                { "type": "string", "enum": NODE_NAMES },
+                { "type": "integer" }
+            ]
+        }),
+        ParamKind::RadioRate => json!({ "type": "string", "enum": RADIO_RATE_NAMES }),
+    };
+    schema["description"] = Value::from(param.description);
+    schema
+}
+
+/// JSON Schema (draft 2020-12) for the `parameters` of one command type.
+pub fn command_schema(spec: &CommandSpec) -> Value {
+    let properties: Map<String, Value> = spec
+        .params
+        .iter()
+        .chain(std::iter::once(&TARGET_PARAM))
+        .map(|p| (p.name.to_string(), param_schema(p)))
+        .collect();
+    let required: Vec<&str> = spec
+        .params
+        .iter()
+        .filter(|p| p.required)
+        .map(|p| p.name)
+        .collect();
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": spec.command_type,
+        "description": spec.description,
+        "type": "object",
+        "properties": properties,
+        "required": required,
+        "additionalProperties": false
+    })
+}
+
+/// Parameter schemas for every command type, keyed by `command_type`.
+pub fn all_schemas() -> Value {
+    let schemas: Map<String, Value> = COMMANDS
+        .iter()
+        .map(|spec| (spec.command_type.to_string(), command_schema(spec)))
+        .collect();
+    Value::Object(schemas)
+}
+
+pub const SCHEMA_CONFIG_KEY: &str = "command_parameter_schema";
+
+/// Stores `all_schemas()` in `SystemConfig` so clients can build command forms from it.
+pub async fn publish_schemas(db_conn: &Connection) -> Result<(), libsql::Error> {
+    db_conn
+        .execute(
+            "INSERT INTO SystemConfig (key, value, description, updated_at) VALUES (?1, ?2, ?3, ?4) \
+             ON CONFLICT(key) DO UPDATE SET value=excluded.value, description=excluded.description, updated_at=excluded.updated_at",
+            libsql_params![
+                SCHEMA_CONFIG_KEY,
+                all_schemas().to_string(),
+                "JSON Schema of OutgoingCommand parameters per command_type, published by command-dispatcher",
+                Utc::now().timestamp(),
+            ],
+        )
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::encode_frame;
+    use messages_prost::radio::radio_frame::Payload;
+    use messages_prost::radio::RadioFrame;
+    use prost::Message as _;
+
+    struct Case {
+        command_type: &'static str,
+        parameters: Option<&'static str>,
+        data: cmd::command::Data,
+        target: Option<Node>,
+        implied_target: Option<Node>,
+    }
+
+    const fn case(
+        command_type: &'static str,
+        parameters: Option<&'static str>,
+        data: cmd::command::Data,
+    ) -> Case {
+        Case {
+            command_type,
+            parameters,
+            data,
+            target: None,
+            implied_target: None,
+        }
+    }
+
+    fn cases() -> Vec<Case> {
+        use cmd::command::Data;
+        vec![
+            case(
+                "Online",
+                Some(r#"{"online": true}"#),
+                Data::Online(cmd::Online { online: true }),
+            ),
+            case(
+                "Online",
+                Some(r#"{"online": false}"#),
+                Data::Online(cmd::Online { online: false }),
+            ),
+            case(
+                "DeployDrogue",
+                Some(r#"{"val": true}"#),
+                Data::DeployDrogue(cmd::DeployDrogue { val: true }),
+            ),
+            Case {
+                target: Some(Node::Phoenix),
+                ..case(
+                    "DeployMain",
+                    Some(r#"{"val": true, "target": "phoenix"}"#),
+                    Data::DeployMain(cmd::DeployMain { val: true }),
+                )
+            },
+            Case {
+                implied_target: Some(Node::StrainBoard),
+                ..case(
+                    "PowerDown",
+                    Some(r#"{"board": "strain_board"}"#),
+                    Data::PowerDown(cmd::PowerDown {
+                        board: Node::StrainBoard as i32,
+                    }),
+                )
+            },
+            Case {
+                target: Some(Node::Phoenix),
+                implied_target: Some(Node::PressureBoard),
+                ..case(
+                    "PowerDown",
+                    Some(r#"{"board": 1, "target": 5}"#),
+                    Data::PowerDown(cmd::PowerDown {
+                        board: Node::PressureBoard as i32,
+                    }),
+                )
+            },
+            case(
+                "PowerUpCamera",
+                None,
+                Data::PowerUpCamera(cmd::PowerUpCamera {}),
+            ),
+            case(
+                "PowerDownCamera",
+                Some("{}"),
+                Data::PowerDownCamera(cmd::PowerDownCamera {}),
+            ),
+            case(
+                "RadioRateChange",
+                Some(r#"{"rate": "medium"}"#),
+                Data::RadioRateChange(cmd::RadioRateChange {
+                    rate: cmd::RadioRate::RateMedium as i32,
+                }),
+            ),
+            case(
+                "Ping",
+                Some(r#"{"id": 0}"#),
+                Data::Ping(cmd::Ping { id: 0 }),
+            ),
+            case(
+                "Ping",
+                Some(r#"{"id": 4294967295}"#),
+                Data::Ping(cmd::Ping { id: u32::MAX }),
+            ),
+            case(
+                "Pong",
+                Some(r#"{"id": 42, "target": null}"#),
+                Data::Pong(cmd::Pong { id: 42 }),
+            ),
+        ]
+    }
+
+    #[test]
+    fn every_command_type_has_a_case() {
+        let cases = cases();
+        for spec in COMMANDS {
+            assert!(
+                cases.iter().any(|c| c.command_type == spec.command_type),
+                "no round-trip case for {}",
+                spec.command_type
+            );
+        }
+    }
+
+    #[test]
+    fn parameters_round_trip_through_the_radio_frame() {
+        for case in cases() {
+            let validated = validate(case.command_type, case.parameters)
+                .unwrap_or_else(|e| panic!("{} {:?}: {}", case.command_type, case.parameters, e));
+            assert_eq!(validated.data, case.data, "{:?}", case.parameters);
+            assert_eq!(validated.target, case.target, "{:?}", case.parameters);
+            assert_eq!(
+                validated.implied_target, case.implied_target,
+                "{:?}",
+                case.parameters
+            );
+
+            let command = cmd::Command {
+                node: Node::Phoenix as i32,
+                data: Some(validated.data),
+            };
+            let bytes = encode_frame(command, 1234);
+            let frame = RadioFrame::decode_length_delimited(&bytes[..]).unwrap();
+            assert_eq!(frame.node, Node::GroundStation as i32);
+            assert_eq!(frame.millis_since_start, 1234);
+            assert_eq!(frame.payload, Some(Payload::Command(command)));
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_and_ill_typed_parameters() {
+        let rejected = [
+            ("Launch", None, "Unknown command type: Launch"),
+            (
+                "DeployDrogue",
+                None,
+                "DeployDrogue: missing required parameter 'val'",
+            ),
+            (
+                "DeployDrogue",
+                Some(r#"{"val": true, "force": true}"#),
+                "DeployDrogue: unknown parameter 'force' (expected: val, target)",
+            ),
+            (
+                "DeployMain",
+                Some(r#"{"val": "yes"}"#),
+                "DeployMain: parameter 'val' must be a boolean, got \"yes\"",
+            ),
+            (
+                "Online",
+                Some(r#"{"online": 1}"#),
+                "Online: parameter 'online' must be a boolean, got 1",
+            ),
+            ("Ping", None, "Ping: missing required parameter 'id'"),
+            (
+                "Ping",
+                Some(r#"{"id": -1}"#),
+                "Ping: parameter 'id' must be an unsigned 32-bit integer, got -1",
+            ),
+            (
+                "Pong",
+                Some(r#"{"id": 4294967296}"#),
+                "Pong: parameter 'id' must be an unsigned 32-bit integer, got 4294967296",
+            ),
+            (
+                "PowerDown",
+                Some(r#"{"board": "Unspecified"}"#),
+                "PowerDown: parameter 'board' must be one of PressureBoard, StrainBoard, TemperatureBoard, GroundStation, Phoenix, got \"Unspecified\"",
+            ),
+            (
+                "PowerUpCamera",
+                Some(r#"{"target": "moon"}"#),
+                "PowerUpCamera: parameter 'target' must be one of PressureBoard, StrainBoard, TemperatureBoard, GroundStation, Phoenix, got \"moon\"",
+            ),
+            (
+                "RadioRateChange",
+                Some(r#"{"rate": "ludicrous"}"#),
+                "RadioRateChange: parameter 'rate' must be one of low, medium, high, got \"ludicrous\"",
+            ),
+            (
+                "RadioRateChange",
+                Some(r#"{"rate": 2}"#),
+                "RadioRateChange: parameter 'rate' must be one of low, medium, high, got 2",
+            ),
+            (
+                "PowerDownCamera",
+                Some("[]"),
+                "PowerDownCamera: parameters must be a JSON object, got []",
+            ),
+        ];
+        for (command_type, parameters, message) in rejected {
+            match validate(command_type, parameters) {
+                Ok(validated) => panic!(
+                    "{} {:?} was accepted as {:?}",
+                    command_type, parameters, validated.data
+                ),
+                Err(e) => assert_eq!(e.0, message),
+            }
+        }
+        let Err(e) = validate("Ping", Some("{id: 1}")) else {
+            panic!("invalid JSON was accepted");
+        };
+        assert!(
+            e.0.starts_with("Ping: parameters are not valid JSON"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn radio_rates_accepted_are_those_in_the_schema() {
+        let spec = find("RadioRateChange").unwrap();
+        let schema = &command_schema(spec)["properties"]["rate"];
+        let listed: Vec<&str> = schema["enum"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v.as_str().unwrap())
+            .collect();
+        let candidates = [
+            json!("low"),
+            json!("medium"),
+            json!("high"),
+            json!("Low"),
+            json!(" high"),
+            json!("slow"),
+            json!("fast"),
+            json!("ratelow"),
+            json!("RateHigh"),
+            json!("0"),
+            json!(1),
+            json!(null),
+        ];
+        for value in candidates {
+            let in_schema = value.as_str().is_some_and(|s| listed.contains(&s));
+            let parameters = json!({ "rate": value }).to_string();
+            assert_eq!(
+                validate("RadioRateChange", Some(&parameters)).is_ok(),
+                in_schema,
+                "{}",
+                parameters
+            );
+        }
+        for rate in listed {
+            let parameters = json!({ "rate": rate }).to_string();
+            assert!(validate("RadioRateChange", Some(&parameters)).is_ok());
+        }
+    }
+}
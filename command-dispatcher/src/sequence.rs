@@ -0,0 +1,646 @@
+use crate::events::{self, EventDetail};
+use crate::interlock::latest_phoenix_state;
+use crate::registry;
+use chrono::Utc;
+use libsql::{params as libsql_params, Connection};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+/// `source_service` of the `OutgoingCommand` rows issued by a sequence run.
+const SEQUENCE_SOURCE: &str = "command-dispatcher-sequence";
+
+/// Command statuses that fail the step that issued the command.
+const FAILED_STEP_STATUSES: [&str; 6] = [
+    "Failed",
+    "TimedOut",
+    "Blocked",
+    "Rejected",
+    "Expired",
+    "Cancelled",
+];
+
+/// What a step waits for, once its command is issued, before the run moves on.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WaitFor {
+    /// The command left the dispatcher ('Sent' or 'Acknowledged').
+    #[default]
+    Sent,
+    /// The command was acknowledged by a node (a `Pong` for a `Ping`).
+    Acknowledged,
+    /// The command was sent and the latest PhoenixState is this one (case-insensitive).
+    PhoenixState(String),
+}
+
+// One entry of a CommandSequence's `steps` array
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SequenceStep {
+    pub command_type: String,
+    #[serde(default)]
+    pub parameters: Option<serde_json::Value>,
+    /// Seconds to wait after the previous step completed. Ignored when `execute_at` is set.
+    #[serde(default)]
+    pub delay_secs: i64,
+    /// UNIX epoch timestamp before which the step's command is not issued.
+    #[serde(default)]
+    pub execute_at: Option<i64>,
+    #[serde(default)]
+    pub wait_for: WaitFor,
+    /// Give up on the step if `wait_for` is not met this long after its command was issued.
+    #[serde(default)]
+    pub wait_timeout_secs: Option<i64>,
+    /// Move on to the next step instead of failing the run when this one fails.
+    #[serde(default)]
+    pub continue_on_failure: bool,
+}
+
+impl SequenceStep {
+    fn parameters_json(&self) -> Option<String> {
+        match &self.parameters {
+            None | Some(serde_json::Value::Null) => None,
+            Some(value) => Some(value.to_string()),
+        }
+    }
+}
+
+/// Parses a sequence's `steps` JSON and validates every step's command parameters.
+pub fn parse_steps(steps_json: &str) -> Result<Vec<SequenceStep>, String> {
+    let steps: Vec<SequenceStep> =
+        serde_json::from_str(steps_json).map_err(|e| format!("Invalid steps: {}", e))?;
+    if steps.is_empty() {
+        return Err("Sequence has no steps".to_string());
+    }
+    for (index, step) in steps.iter().enumerate() {
+        registry::validate(&step.command_type, step.parameters_json().as_deref())
+            .map_err(|e| format!("Step {}: {}", index + 1, e))?;
+    }
+    Ok(steps)
+}
+
+// A row of SequenceRun that the dispatcher still has to drive
+#[derive(Debug)]
+struct SequenceRunRow {
+    id: i64,
+    sequence_name: String,
+    status: String,
+    steps: Option<String>,
+    current_step: i64,
+    step_started_at: i64,
+    step_command_id: Option<i64>,
+    operator: Option<String>,
+    paused_at: Option<i64>,
+}
+
+/// Drives every 'Pending' and 'Running' `SequenceRun` one step further where it can, and
+/// cancels the unsent commands of runs an operator set to 'Aborted'. A step's command is
+/// queued in `OutgoingCommand` and goes through the normal interlock and send path.
+/// Returns whether any run started, issued a command or finished a step, so the caller can
+/// come back without waiting for the next poll.
+pub async fn advance_sequences(db_conn: &Connection) -> Result<bool, libsql::Error> {
+    finalize_aborted_runs(db_conn).await?;
+    let now = Utc::now().timestamp();
+    // Time spent paused does not count towards a step's delay or wait timeout
+    db_conn
+        .execute(
+            "UPDATE SequenceRun SET paused_at = ? WHERE status = 'Paused' AND paused_at IS NULL",
+            libsql_params![now],
+        )
+        .await?;
+
+    let mut rows = db_conn
+        .query(
+            "SELECT id, sequence_name, status, steps, current_step, step_started_at, step_command_id, operator, paused_at \
+             FROM SequenceRun WHERE status IN ('Pending', 'Running') ORDER BY id ASC",
+            libsql_params![],
+        )
+        .await?;
+    let mut runs = Vec::new();
+    while let Some(row) = rows.next().await? {
+        let step_started_at: Option<i64> = row.get(5)?;
+        runs.push(SequenceRunRow {
+            id: row.get(0)?,
+            sequence_name: row.get(1)?,
+            status: row.get(2)?,
+            steps: row.get(3)?,
+            current_step: row.get(4)?,
+            step_started_at: step_started_at.unwrap_or(0),
+            step_command_id: row.get(6)?,
+            operator: row.get(7)?,
+            paused_at: row.get(8)?,
+        });
+    }
+
+    let mut moved = false;
+    for mut run in runs {
+        if run.status == "Pending" {
+            start_run(db_conn, &run).await?;
+            moved = true;
+            continue;
+        }
+        if let Some(paused_at) = run.paused_at {
+            run.step_started_at += (now - paused_at).max(0);
+            info!(
+                "[Seq Run ID: {}] Resumed after {}s paused.",
+                run.id,
+                now - paused_at
+            );
+            db_conn
+                .execute(
+                    "UPDATE SequenceRun SET step_started_at = ?, paused_at = NULL WHERE id = ? AND status = 'Running'",
+                    libsql_params![run.step_started_at, run.id],
+                )
+                .await?;
+        }
+        moved |= advance_run(db_conn, &run).await?;
+    }
+    Ok(moved)
+}
+
+// Snapshots the sequence definition into the run so later edits don't affect it.
+async fn start_run(db_conn: &Connection, run: &SequenceRunRow) -> Result<(), libsql::Error> {
+    let mut rows = db_conn
+        .query(
+            "SELECT steps FROM CommandSequence WHERE name = ?",
+            libsql_params![run.sequence_name.clone()],
+        )
+        .await?;
+    let Some(row) = rows.next().await? else {
+        let reason = format!("Unknown sequence '{}'", run.sequence_name);
+        return finish_run(db_conn, run.id, "Failed", Some(&reason)).await;
+    };
+    let steps_json: String = row.get(0)?;
+    let steps = match parse_steps(&steps_json) {
+        Ok(steps) => steps,
+        Err(reason) => return finish_run(db_conn, run.id, "Failed", Some(&reason)).await,
+    };
+
+    info!(
+        "[Seq Run ID: {}] Starting sequence '{}' ({} step(s)) for operator {:?}.",
+        run.id,
+        run.sequence_name,
+        steps.len(),
+        run.operator
+    );
+    let now = Utc::now().timestamp();
+    db_conn
+        .execute(
+            "UPDATE SequenceRun SET status = 'Running', steps = ?, current_step = 0, started_at = ?, step_started_at = ?, step_command_id = NULL \
+             WHERE id = ? AND status = 'Pending'",
+            libsql_params![steps_json, now, now, run.id],
+        )
+        .await?;
+    Ok(())
+}
+
+// Returns whether the run issued a command, finished a step or ended.
+async fn advance_run(db_conn: &Connection, run: &SequenceRunRow) -> Result<bool, libsql::Error> {
+    let steps = match parse_steps(run.steps.as_deref().unwrap_or("[]")) {
+        Ok(steps) => steps,
+        Err(reason) => {
+            finish_run(db_conn, run.id, "Failed", Some(&reason)).await?;
+            return Ok(true);
+        }
+    };
+    let Some(step) = steps.get(run.current_step as usize) else {
+        info!(
+            "[Seq Run ID: {}] Sequence '{}' completed.",
+            run.id, run.sequence_name
+        );
+        finish_run(db_conn, run.id, "Completed", None).await?;
+        return Ok(true);
+    };
+    let step_number = run.current_step + 1;
+    let now = Utc::now().timestamp();
+
+    let Some(cmd_id) = run.step_command_id else {
+        let due = step
+            .execute_at
+            .unwrap_or(run.step_started_at + step.delay_secs);
+        if now < due {
+            return Ok(false);
+        }
+        issue_step(db_conn, run, step, step_number, now).await?;
+        return Ok(true);
+    };
+
+    let mut rows = db_conn
+        .query(
+            "SELECT status FROM OutgoingCommand WHERE id = ?",
+            libsql_params![cmd_id],
+        )
+        .await?;
+    let status: String = match rows.next().await? {
+        Some(row) => row.get(0)?,
+        None => "Deleted".to_string(),
+    };
+
+    let failure = if FAILED_STEP_STATUSES.contains(&status.as_str()) || status == "Deleted" {
+        Some(format!(
+            "Step {} ({}, Cmd ID {}) ended '{}'",
+            step_number, step.command_type, cmd_id, status
+        ))
+    } else if wait_satisfied(db_conn, &step.wait_for, &status).await? {
+        info!(
+            "[Seq Run ID: {}] Step {}/{} ({}) done.",
+            run.id,
+            step_number,
+            steps.len(),
+            step.command_type
+        );
+        next_step(db_conn, run.id, now).await?;
+        return Ok(true);
+    } else {
+        match step.wait_timeout_secs {
+            Some(timeout) if now - run.step_started_at > timeout => Some(format!(
+                "Step {} ({}, Cmd ID {}): {:?} not met within {}s",
+                step_number, step.command_type, cmd_id, step.wait_for, timeout
+            )),
+            _ => None,
+        }
+    };
+
+    let Some(reason) = failure else {
+        return Ok(false);
+    };
+    cancel_unsent(db_conn, "id = ?", cmd_id).await?;
+    if step.continue_on_failure {
+        warn!("[Seq Run ID: {}] {}; continuing.", run.id, reason);
+        db_conn
+            .execute(
+                "UPDATE SequenceRun SET error_message = ? WHERE id = ?",
+                libsql_params![reason, run.id],
+            )
+            .await?;
+        next_step(db_conn, run.id, now).await?;
+    } else {
+        finish_run(db_conn, run.id, "Failed", Some(&reason)).await?;
+    }
+    Ok(true)
+}
+
+async fn issue_step(
+    db_conn: &Connection,
+    run: &SequenceRunRow,
+    step: &SequenceStep,
+    step_number: i64,
+    now: i64,
+) -> Result<(), libsql::Error> {
+    let mut rows = db_conn
+        .query(
+            "INSERT INTO OutgoingCommand (command_type, parameters, status, created_at, attempts, source_service, operator, sequence_run_id, sequence_step) \
+             VALUES (?, ?, 'Pending', ?, 0, ?, ?, ?, ?) RETURNING id",
+            libsql_params![
+                step.command_type.clone(),
+                step.parameters_json(),
+                now,
+                SEQUENCE_SOURCE,
+                run.operator.clone(),
+                run.id,
+                step_number
+            ],
+        )
+        .await?;
+    let Some(row) = rows.next().await? else {
+        return Ok(());
+    };
+    let cmd_id: i64 = row.get(0)?;
+    drop(rows);
+    info!(
+        "[Seq Run ID: {}] Step {}: queued {} as Cmd ID {}.",
+        run.id, step_number, step.command_type, cmd_id
+    );
+    // The wait timeout counts from here.
+    db_conn
+        .execute(
+            "UPDATE SequenceRun SET step_command_id = ?, step_started_at = ? WHERE id = ? AND status = 'Running'",
+            libsql_params![cmd_id, now, run.id],
+        )
+        .await?;
+    Ok(())
+}
+
+async fn wait_satisfied(
+    db_conn: &Connection,
+    wait_for: &WaitFor,
+    status: &str,
+) -> Result<bool, libsql::Error> {
+    let sent = matches!(status, "Sent" | "Acknowledged");
+    Ok(match wait_for {
+        WaitFor::Sent => sent,
+        WaitFor::Acknowledged => status == "Acknowledged",
+        WaitFor::PhoenixState(wanted) => {
+            sent && matches!(
+                latest_phoenix_state(db_conn).await?,
+                Some((state, _)) if state.eq_ignore_ascii_case(wanted)
+            )
+        }
+    })
+}
+
+async fn next_step(db_conn: &Connection, run_id: i64, now: i64) -> Result<(), libsql::Error> {
+    db_conn
+        .execute(
+            "UPDATE SequenceRun SET current_step = current_step + 1, step_started_at = ?, step_command_id = NULL \
+             WHERE id = ? AND status = 'Running'",
+            libsql_params![now, run_id],
+        )
+        .await?;
+    Ok(())
+}
+
+async fn finish_run(
+    db_conn: &Connection,
+    run_id: i64,
+    status: &str,
+    reason: Option<&str>,
+) -> Result<(), libsql::Error> {
+    if let Some(reason) = reason {
+        warn!("[Seq Run ID: {}] {}: {}", run_id, status, reason);
+    }
+    db_conn
+        .execute(
+            "UPDATE SequenceRun SET status = ?, finished_at = ?, error_message = COALESCE(?, error_message) \
+             WHERE id = ? AND status IN ('Pending', 'Running')",
+            libsql_params![status, Utc::now().timestamp(), reason, run_id],
+        )
+        .await?;
+    Ok(())
+}
+
+// Cancels the commands matching `condition` that have not been sent yet.
+async fn cancel_unsent(
+    db_conn: &Connection,
+    condition: &str,
+    arg: i64,
+) -> Result<(), libsql::Error> {
+    let mut rows = db_conn
+        .query(
+            &format!(
+                "SELECT id, status FROM OutgoingCommand \
+                 WHERE {} AND status IN ('Pending', 'AwaitingConfirmation')",
+                condition
+            ),
+            libsql_params![arg],
+        )
+        .await?;
+    let mut unsent: Vec<(i64, String)> = Vec::new();
+    while let Some(row) = rows.next().await? {
+        unsent.push((row.get(0)?, row.get(1)?));
+    }
+
+    let reason = "Cancelled by sequence run";
+    for (cmd_id, status) in unsent {
+        let updated = db_conn
+            .execute(
+                "UPDATE OutgoingCommand SET status = 'Cancelled', error_message = ? WHERE id = ? AND status = ?",
+                libsql_params![reason, cmd_id, status.clone()],
+            )
+            .await?;
+        if updated > 0 {
+            events::record(
+                db_conn,
+                cmd_id,
+                &status,
+                "Cancelled",
+                EventDetail {
+                    error: Some(reason),
+                    ..EventDetail::default()
+                },
+            )
+            .await;
+        }
+    }
+    Ok(())
+}
+
+async fn finalize_aborted_runs(db_conn: &Connection) -> Result<(), libsql::Error> {
+    let mut rows = db_conn
+        .query(
+            "SELECT id FROM SequenceRun WHERE status = 'Aborted' AND finished_at IS NULL",
+            libsql_params![],
+        )
+        .await?;
+    let mut aborted: Vec<i64> = Vec::new();
+    while let Some(row) = rows.next().await? {
+        aborted.push(row.get(0)?);
+    }
+
+    for run_id in aborted {
+        info!(
+            "[Seq Run ID: {}] Aborted by operator. Cancelling its unsent commands.",
+            run_id
+        );
+        cancel_unsent(db_conn, "sequence_run_id = ?", run_id).await?;
+        db_conn
+            .execute(
+                "UPDATE SequenceRun SET finished_at = ? WHERE id = ?",
+                libsql_params![Utc::now().timestamp(), run_id],
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::database;
+
+    const TWO_PINGS: &str = r#"[
+        {"command_type": "Ping", "parameters": {"id": 1}},
+        {"command_type": "Ping", "parameters": {"id": 2}, "wait_for": "acknowledged"}
+    ]"#;
+
+    async fn start(db_conn: &Connection, steps: &str) -> i64 {
+        db_conn
+            .execute(
+                "INSERT INTO CommandSequence (name, steps) VALUES ('test', ?)",
+                libsql_params![steps],
+            )
+            .await
+            .unwrap();
+        db_conn
+            .execute(
+                "INSERT INTO SequenceRun (sequence_name, status, operator) VALUES ('test', 'Pending', 'alice')",
+                libsql_params![],
+            )
+            .await
+            .unwrap();
+        db_conn.last_insert_rowid()
+    }
+
+    async fn run_state(db_conn: &Connection, id: i64) -> (String, i64, Option<i64>) {
+        let mut rows = db_conn
+            .query(
+                "SELECT status, current_step, step_command_id FROM SequenceRun WHERE id = ?",
+                libsql_params![id],
+            )
+            .await
+            .unwrap();
+        let row = rows.next().await.unwrap().unwrap();
+        (
+            row.get(0).unwrap(),
+            row.get(1).unwrap(),
+            row.get(2).unwrap(),
+        )
+    }
+
+    async fn set_command_status(db_conn: &Connection, id: i64, status: &str) {
+        db_conn
+            .execute(
+                "UPDATE OutgoingCommand SET status = ? WHERE id = ?",
+                libsql_params![status, id],
+            )
+            .await
+            .unwrap();
+    }
+
+    async fn command_status(db_conn: &Connection, id: i64) -> String {
+        let mut rows = db_conn
+            .query(
+                "SELECT status FROM OutgoingCommand WHERE id = ?",
+                libsql_params![id],
+            )
+            .await
+            .unwrap();
+        rows.next().await.unwrap().unwrap().get(0).unwrap()
+    }
+
+    #[test]
+    fn parses_and_validates_steps() {
+        let steps = parse_steps(TWO_PINGS).unwrap();
+        assert_eq!(steps.len(), 2);
+        assert_eq!(steps[0].wait_for, WaitFor::Sent);
+        assert_eq!(steps[1].wait_for, WaitFor::Acknowledged);
+        assert_eq!(
+            parse_steps(r#"[{"command_type": "Ping", "parameters": {"id": 1}, "wait_for": {"phoenix_state": "Armed"}}]"#)
+                .unwrap()[0]
+                .wait_for,
+            WaitFor::PhoenixState("Armed".to_string())
+        );
+
+        assert_eq!(parse_steps("[]").unwrap_err(), "Sequence has no steps");
+        assert!(parse_steps("{}").unwrap_err().starts_with("Invalid steps"));
+        assert!(
+            parse_steps(r#"[{"command_type": "Ping", "parameters": {"id": 1}, "delay": 2}]"#)
+                .unwrap_err()
+                .starts_with("Invalid steps")
+        );
+        assert_eq!(
+            parse_steps(r#"[{"command_type": "Online", "parameters": {"online": true}}, {"command_type": "Ping"}]"#)
+                .unwrap_err(),
+            "Step 2: Ping: missing required parameter 'id'"
+        );
+    }
+
+    #[tokio::test]
+    async fn moves_to_the_next_step_once_the_wait_is_met() {
+        let db_conn = database().await;
+        let run = start(&db_conn, TWO_PINGS).await;
+
+        assert!(advance_sequences(&db_conn).await.unwrap());
+        assert_eq!(
+            run_state(&db_conn, run).await,
+            ("Running".to_string(), 0, None)
+        );
+
+        assert!(advance_sequences(&db_conn).await.unwrap());
+        let (_, _, Some(first)) = run_state(&db_conn, run).await else {
+            panic!("step 1 issued no command");
+        };
+        assert!(!advance_sequences(&db_conn).await.unwrap());
+
+        set_command_status(&db_conn, first, "Sent").await;
+        assert!(advance_sequences(&db_conn).await.unwrap());
+        assert_eq!(
+            run_state(&db_conn, run).await,
+            ("Running".to_string(), 1, None)
+        );
+
+        assert!(advance_sequences(&db_conn).await.unwrap());
+        let (_, _, Some(second)) = run_state(&db_conn, run).await else {
+            panic!("step 2 issued no command");
+        };
+        set_command_status(&db_conn, second, "Sent").await;
+        assert!(!advance_sequences(&db_conn).await.unwrap());
+
+        set_command_status(&db_conn, second, "Acknowledged").await;
+        assert!(advance_sequences(&db_conn).await.unwrap());
+        assert!(advance_sequences(&db_conn).await.unwrap());
+        assert_eq!(run_state(&db_conn, run).await.0, "Completed");
+    }
+
+    #[tokio::test]
+    async fn fails_the_run_when_a_step_fails() {
+        let db_conn = database().await;
+        let run = start(&db_conn, TWO_PINGS).await;
+        advance_sequences(&db_conn).await.unwrap();
+        advance_sequences(&db_conn).await.unwrap();
+        let (_, _, Some(first)) = run_state(&db_conn, run).await else {
+            panic!("step 1 issued no command");
+        };
+
+        set_command_status(&db_conn, first, "Rejected").await;
+        assert!(advance_sequences(&db_conn).await.unwrap());
+        assert_eq!(run_state(&db_conn, run).await.0, "Failed");
+    }
+
+    #[tokio::test]
+    async fn abort_cancels_unsent_commands() {
+        let db_conn = database().await;
+        let run = start(&db_conn, TWO_PINGS).await;
+        advance_sequences(&db_conn).await.unwrap();
+        advance_sequences(&db_conn).await.unwrap();
+        let (_, _, Some(first)) = run_state(&db_conn, run).await else {
+            panic!("step 1 issued no command");
+        };
+
+        db_conn
+            .execute(
+                "UPDATE SequenceRun SET status = 'Aborted' WHERE id = ?",
+                libsql_params![run],
+            )
+            .await
+            .unwrap();
+        assert!(!advance_sequences(&db_conn).await.unwrap());
+        assert_eq!(command_status(&db_conn, first).await, "Cancelled");
+        assert_eq!(run_state(&db_conn, run).await.0, "Aborted");
+    }
+
+    #[tokio::test]
+    async fn paused_time_does_not_count_towards_a_delay() {
+        let db_conn = database().await;
+        let run = start(
+            &db_conn,
+            r#"[{"command_type": "Ping", "parameters": {"id": 1}, "delay_secs": 100}]"#,
+        )
+        .await;
+        advance_sequences(&db_conn).await.unwrap();
+
+        // Paused for 90 of the 100 seconds the step has waited so far
+        let now = Utc::now().timestamp();
+        db_conn
+            .execute(
+                "UPDATE SequenceRun SET status = 'Running', step_started_at = ?, paused_at = ? WHERE id = ?",
+                libsql_params![now - 100, now - 90, run],
+            )
+            .await
+            .unwrap();
+        assert!(!advance_sequences(&db_conn).await.unwrap());
+        assert_eq!(
+            run_state(&db_conn, run).await,
+            ("Running".to_string(), 0, None)
+        );
+
+        db_conn
+            .execute(
+                "UPDATE SequenceRun SET step_started_at = step_started_at - 90 WHERE id = ?",
+                libsql_params![run],
+            )
+            .await
+            .unwrap();
+        assert!(advance_sequences(&db_conn).await.unwrap());
+        assert!(run_state(&db_conn, run).await.2.is_some());
+    }
+}
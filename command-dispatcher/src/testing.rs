@@ -0,0 +1,13 @@
+//! Fixtures shared by the unit tests.
+
+use libsql::{Builder, Connection};
+
+/// An in-memory database with the schema of `db/seed.sql`.
+pub async fn database() -> Connection {
+    let db = Builder::new_local(":memory:").build().await.unwrap();
+    let conn = db.connect().unwrap();
+    conn.execute_batch(include_str!("../../db/seed.sql"))
+        .await
+        .unwrap();
+    conn
+}
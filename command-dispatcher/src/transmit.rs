@@ -0,0 +1,179 @@
+use std::io::Read;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Quiet time after which the current downlink burst is considered over.
+const BURST_GAP: Duration = Duration::from_millis(50);
+/// Airtime of one POSTCARD frame at 57600 baud, plus margin. A gap shorter than this before
+/// the next predicted burst is not used.
+const TX_GUARD: Duration = Duration::from_millis(50);
+/// Weight of the newest sample in the burst period and duration averages.
+const EWMA_WEIGHT: f64 = 0.2;
+/// A model that has not seen this many burst periods go by without downlink is stale.
+const STALE_PERIODS: u32 = 3;
+/// Read timeout of the listening connection.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Downlink burst timing learned from frames received on the gateway connection
+#[derive(Debug, Default)]
+struct DownlinkTiming {
+    last_rx: Option<Instant>,
+    burst_start: Option<Instant>,
+    period: Option<Duration>,
+    duration: Option<Duration>,
+}
+
+fn ewma(previous: Option<Duration>, sample: Duration) -> Duration {
+    match previous {
+        Some(previous) => previous.mul_f64(1.0 - EWMA_WEIGHT) + sample.mul_f64(EWMA_WEIGHT),
+        None => sample,
+    }
+}
+
+impl DownlinkTiming {
+    fn on_frame(&mut self, now: Instant) {
+        let new_burst = self
+            .last_rx
+            .is_none_or(|last| now.duration_since(last) > BURST_GAP);
+        if new_burst {
+            if let (Some(start), Some(last)) = (self.burst_start, self.last_rx) {
+                self.duration = Some(ewma(self.duration, last.duration_since(start)));
+                self.period = Some(ewma(self.period, now.duration_since(start)));
+            }
+            self.burst_start = Some(now);
+        }
+        self.last_rx = Some(now);
+    }
+
+    /// How long to hold an uplink frame so it lands in a downlink gap, `None` if now is fine.
+    fn transmit_delay(&self, now: Instant) -> Option<Duration> {
+        let last_rx = self.last_rx?;
+        let since_rx = now.duration_since(last_rx);
+        if since_rx < BURST_GAP {
+            // A burst is in progress
+            return Some(BURST_GAP - since_rx);
+        }
+        let (Some(period), Some(start)) = (self.period, self.burst_start) else {
+            return None;
+        };
+        if since_rx > period * STALE_PERIODS {
+            return None;
+        }
+        // Wait out the next burst if it is due before our frame would be off the air.
+        let next_burst = start + period;
+        let until_next = next_burst.saturating_duration_since(now);
+        if next_burst > now && until_next < TX_GUARD {
+            return Some(until_next + self.duration.unwrap_or_default() + BURST_GAP);
+        }
+        None
+    }
+}
+
+/// Whether an uplink frame went out in a gap or was forced out at its deadline.
+#[derive(Debug, PartialEq)]
+pub enum TransmitWindow {
+    Clear { waited: Duration },
+    Deadline { waited: Duration },
+}
+
+/// Places uplink frames between downlink bursts on the half-duplex radio link. The burst
+/// timing is learned on a background thread from a second, listen-only connection to the
+/// gateway, which sends the downlink to every client; the connection commands are sent on is
+/// never read.
+pub struct TransmitScheduler {
+    timing: Arc<Mutex<DownlinkTiming>>,
+    stop: Arc<AtomicBool>,
+    listener: Option<JoinHandle<()>>,
+}
+
+impl TransmitScheduler {
+    /// Connects to the gateway of a `tcpout:host:port` connection string and starts learning
+    /// its downlink timing.
+    pub fn start(connection_string: &str) -> std::io::Result<Self> {
+        let address = connection_string.strip_prefix("tcpout:").ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "only tcpout gateways can be listened to",
+            )
+        })?;
+        let mut stream = TcpStream::connect(address)?;
+        // Bounds how long the thread takes to notice it should stop
+        stream.set_read_timeout(Some(STOP_POLL_INTERVAL))?;
+
+        let timing = Arc::new(Mutex::new(DownlinkTiming::default()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (thread_timing, thread_stop) = (timing.clone(), stop.clone());
+        let listener = std::thread::spawn(move || {
+            // Only arrival times matter, so frames are not decoded; bytes less than BURST_GAP
+            // apart belong to the same burst.
+            let mut buffer = [0u8; 1024];
+            while !thread_stop.load(Ordering::Relaxed) {
+                match stream.read(&mut buffer) {
+                    Ok(0) => {
+                        warn!("Gateway closed the listening connection; transmit scheduling falls back to sending immediately.");
+                        break;
+                    }
+                    Ok(_) => {
+                        if let Ok(mut timing) = thread_timing.lock() {
+                            timing.on_frame(Instant::now());
+                        }
+                    }
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            std::io::ErrorKind::WouldBlock
+                                | std::io::ErrorKind::TimedOut
+                                | std::io::ErrorKind::Interrupted
+                        ) => {}
+                    Err(e) => {
+                        warn!(
+                            "Gateway listening connection failed ({}); transmit scheduling falls back to sending immediately.",
+                            e
+                        );
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(Self {
+            timing,
+            stop,
+            listener: Some(listener),
+        })
+    }
+
+    /// Waits for a downlink gap, but never longer than `deadline`.
+    pub async fn wait_for_window(&self, deadline: Duration) -> TransmitWindow {
+        let started = Instant::now();
+        loop {
+            let delay = match self.timing.lock() {
+                Ok(timing) => timing.transmit_delay(Instant::now()),
+                Err(_) => None,
+            };
+            let waited = started.elapsed();
+            let Some(delay) = delay else {
+                return TransmitWindow::Clear { waited };
+            };
+            let remaining = deadline.saturating_sub(waited);
+            if remaining.is_zero() {
+                return TransmitWindow::Deadline { waited };
+            }
+            tokio::time::sleep(delay.min(remaining)).await;
+        }
+    }
+}
+
+impl Drop for TransmitScheduler {
+    fn drop(&mut self) {
+        // Runs when the gateway is replaced or lost. The thread sees the flag within one read
+        // timeout and closes its connection on the way out.
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(listener) = self.listener.take() {
+            let _ = listener.join();
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use axum::{http::StatusCode, routing::post, Router};
+use libsql::{params::params_from_iter, Connection};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+/// Serves `POST /trigger` on `addr`. Each request wakes the dispatch loop; the body (e.g. a
+/// database event trigger payload) is ignored because the loop reads the queue itself.
+pub async fn run_trigger_server(
+    addr: SocketAddr,
+    trigger: Arc<Notify>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let app = Router::new().route(
+        "/trigger",
+        post(move || {
+            let trigger = trigger.clone();
+            async move {
+                trigger.notify_one();
+                StatusCode::ACCEPTED
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!(
+        "Dispatch trigger endpoint listening on http://{}/trigger",
+        addr
+    );
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+/// Waits until the next dispatch cycle is due: a trigger request, a 'Pending' command seen
+/// by the cheap fast poll, or `poll_interval` elapsing, whichever comes first. The fast poll
+/// does not count the `skipped` commands, which the last cycle could not process.
+pub async fn wait_for_work(
+    db_conn: &Connection,
+    trigger: &Notify,
+    skipped: &HashSet<i64>,
+    poll_interval: Duration,
+    fast_poll_interval: Duration,
+) {
+    let deadline = tokio::time::Instant::now() + poll_interval;
+    let fast_poll_interval = if fast_poll_interval.is_zero() {
+        poll_interval
+    } else {
+        fast_poll_interval
+    };
+    loop {
+        let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+        if remaining.is_zero() {
+            return;
+        }
+        tokio::select! {
+            _ = trigger.notified() => return,
+            _ = tokio::time::sleep(fast_poll_interval.min(remaining)) => {}
+        }
+        match has_pending(db_conn, skipped).await {
+            Ok(true) => return,
+            Ok(false) => {}
+            Err(e) => warn!("Fast poll for pending commands failed: {:?}", e),
+        }
+    }
+}
+
+// Index-only lookup, cheap enough to run several times a second.
+async fn has_pending(db_conn: &Connection, skipped: &HashSet<i64>) -> Result<bool, libsql::Error> {
+    let placeholders = vec!["?"; skipped.len()].join(", ");
+    let sql = format!(
+        "SELECT 1 FROM OutgoingCommand WHERE status = 'Pending' AND id NOT IN ({}) LIMIT 1",
+        placeholders
+    );
+    let mut rows = db_conn
+        .query(&sql, params_from_iter(skipped.iter().copied()))
+        .await?;
+    Ok(rows.next().await?.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libsql::params as libsql_params;
+
+    #[tokio::test]
+    async fn fast_poll_ignores_skipped_commands() {
+        let db = crate::testing::database().await;
+        db.execute(
+            "INSERT INTO OutgoingCommand (id, command_type, status, created_at, source_service) VALUES (7, 'Ping', 'Pending', 0, 'web')",
+            libsql_params![],
+        )
+        .await
+        .unwrap();
+
+        assert!(has_pending(&db, &HashSet::new()).await.unwrap());
+        assert!(!has_pending(&db, &HashSet::from([7])).await.unwrap());
+        assert!(has_pending(&db, &HashSet::from([8])).await.unwrap());
+    }
+}
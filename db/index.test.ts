@@ -1,7 +1,8 @@
 import { expect, test } from "bun:test";
 import { Database } from "bun:sqlite";
 const seed = await Bun.file(new URL("./seed.sql", import.meta.url)).text();
-import { parseSqlStatements } from "./sql";
+const migrations = await Bun.file(new URL("./migrations.sql", import.meta.url)).text();
+import { parseAddedColumn, parseSqlStatements } from "./sql";
 
 test("seed.sql applies cleanly to in-memory sqlite", () => {
   const db = new Database(":memory:");
@@ -19,3 +20,39 @@ test("seed.sql applies cleanly to in-memory sqlite", () => {
 });
 
 
+
+test("migrations.sql brings tables created before its columns up to date", () => {
+  const seedStmts = parseSqlStatements(seed);
+  const migrationStmts = parseSqlStatements(migrations);
+  const added = migrationStmts.map(parseAddedColumn);
+  expect(added).not.toContain(null);
+  const tables = [...new Set(added.map((a) => a!.table))];
+
+  const columns = (db: Database, table: string) =>
+    db.query(`PRAGMA table_info(${table})`).all() as { name: string; type: string }[];
+  const fresh = new Database(":memory:");
+  for (const s of seedStmts) fresh.run(s);
+
+  // A database created before the migrated columns existed, seeded again
+  const old = new Database(":memory:");
+  for (const s of seedStmts) old.run(s);
+  for (const table of tables) {
+    const kept = columns(old, table)
+      .map((c) => c.name)
+      .filter((name) => !added.some((a) => a!.table === table && a!.column === name));
+    old.run(`CREATE TABLE ${table}_old AS SELECT ${kept.join(", ")} FROM ${table}`);
+    old.run(`DROP TABLE ${table}`);
+    old.run(`ALTER TABLE ${table}_old RENAME TO ${table}`);
+  }
+  for (const s of seedStmts) old.run(s);
+  for (const s of migrationStmts) old.run(s);
+
+  for (const table of tables) {
+    const migrated = columns(old, table);
+    expect(migrated.map((c) => c.name)).toEqual(columns(fresh, table).map((c) => c.name));
+    for (const a of added.filter((a) => a!.table === table)) {
+      const want = columns(fresh, table).find((c) => c.name === a!.column);
+      expect(migrated.find((c) => c.name === a!.column)?.type).toBe(want!.type);
+    }
+  }
+});
@@ -1,5 +1,5 @@
 import { createClient } from "@libsql/client";
-import { parseSqlStatements } from "./sql";
+import { parseAddedColumn, parseSqlStatements } from "./sql";
 
 const DATABASE_URL = process.env.DATABASE_URL || "http://0.0.0.0:8080";
 
@@ -52,9 +52,37 @@ for (const statement of statements) {
   }
 }
 
+// Add the columns that tables created before them are missing
+const migrations = parseSqlStatements(
+  await Bun.file(new URL("./migrations.sql", import.meta.url)).text(),
+);
+let nApplied = 0;
+for (const statement of migrations) {
+  const added = parseAddedColumn(statement);
+  try {
+    if (added) {
+      const columns = await client.execute(`PRAGMA table_info(${added.table})`);
+      if (columns.rows.some((row) => row.name === added.column)) {
+        continue;
+      }
+    }
+    await client.execute(statement);
+    nApplied++;
+    console.log(`${statement}  ...OK`);
+  } catch (e) {
+    nErrors++;
+    console.error("Migration failed:");
+    console.log("--------------------------------");
+    console.log(statement);
+    console.log("--------------------------------");
+    console.error(e);
+    console.log("--------------------------------");
+  }
+}
+
 if (nErrors > 0) {
   console.error(`Failed to execute ${nErrors} statements`);
   process.exit(1);
 }
 
-console.log(`Successfully executed ${statements.length} statements`);
+console.log(`Successfully executed ${statements.length} statements and ${nApplied} migrations`);
@@ -0,0 +1,26 @@
+-- Columns added to tables after they were first created. seed.sql only creates tables that do
+-- not exist yet, so a database from before a column was added is brought up to date here.
+-- index.ts runs each ALTER whose column is missing, after seed.sql. Append new columns to the
+-- end; the same column must also be in the table's CREATE TABLE in seed.sql.
+
+-- OutgoingCommand
+ALTER TABLE OutgoingCommand ADD COLUMN operator TEXT;
+ALTER TABLE OutgoingCommand ADD COLUMN confirmation_token TEXT;
+ALTER TABLE OutgoingCommand ADD COLUMN confirmation_requested_at INTEGER;
+ALTER TABLE OutgoingCommand ADD COLUMN priority INTEGER;
+ALTER TABLE OutgoingCommand ADD COLUMN expires_at INTEGER;
+ALTER TABLE OutgoingCommand ADD COLUMN sequence_run_id INTEGER;
+ALTER TABLE OutgoingCommand ADD COLUMN sequence_step INTEGER;
+ALTER TABLE OutgoingCommand ADD COLUMN mavlink_sequence INTEGER;
+ALTER TABLE OutgoingCommand ADD COLUMN acknowledged_at INTEGER;
+
+-- SequenceRun
+ALTER TABLE SequenceRun ADD COLUMN paused_at INTEGER;
+
+-- RadioFrame
+ALTER TABLE RadioFrame ADD COLUMN received_at_ms INTEGER;
+
+-- RadioMetrics
+ALTER TABLE RadioMetrics ADD COLUMN gateway TEXT;
+ALTER TABLE RadioMetrics ADD COLUMN frames_received INTEGER;
+ALTER TABLE RadioMetrics ADD COLUMN frames_duplicate INTEGER;
@@ -5,18 +5,105 @@ CREATE TABLE IF NOT EXISTS OutgoingCommand (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     command_type TEXT NOT NULL, -- e.g., 'DeployDrogue', 'Online', 'Ping'
     parameters TEXT, -- JSON string or simple value representing command arguments. Can be NULL if no params.
-    status TEXT NOT NULL, -- e.g., 'Pending', 'Queued', 'Sending', 'Sent', 'Failed', 'Cancelled'
+    status TEXT NOT NULL, -- e.g., 'Pending', 'AwaitingConfirmation', 'Queued', 'Sending', 'Sent', 'Acknowledged', 'TimedOut', 'Blocked', 'Rejected', 'Expired', 'Failed', 'Cancelled'
     created_at INTEGER NOT NULL, -- UNIX epoch timestamp when the command was requested
     queued_at INTEGER, -- UNIX epoch timestamp when the dispatcher picked it up (optional)
     sent_at INTEGER, -- UNIX epoch timestamp when the dispatcher attempted to send it (optional)
     attempts INTEGER NOT NULL DEFAULT 0, -- Number of send attempts
     error_message TEXT, -- Details if sending failed (optional)
-    source_service TEXT NOT NULL -- e.g., 'rgs-web', 'rgs-heartbeat'
+    source_service TEXT NOT NULL, -- e.g., 'rgs-web', 'rgs-heartbeat'
+    operator TEXT, -- Person who requested the command (optional)
+    confirmation_token TEXT, -- Identifies the current confirmation request; confirmations are signed over it (optional)
+    confirmation_requested_at INTEGER, -- UNIX epoch timestamp when confirmation was requested (optional)
+    priority INTEGER, -- Higher is sent first. NULL uses the command type's default priority
+    expires_at INTEGER, -- UNIX epoch timestamp after which an unsent command becomes 'Expired' (optional)
+    sequence_run_id INTEGER, -- SequenceRun.id that issued this command (optional)
+    sequence_step INTEGER, -- 1-based step of that run (optional)
+    mavlink_sequence INTEGER, -- MAVLink header sequence of the last frame sent for the command (optional)
+    acknowledged_at INTEGER -- UNIX epoch timestamp when evidence of receipt was seen (optional)
 );
 
 -- Index for efficient retrieval of pending commands by the dispatcher
 CREATE INDEX IF NOT EXISTS idx_outgoingcommand_status_created ON OutgoingCommand (status, created_at);
 
+-- Append-only audit trail of OutgoingCommand status transitions, written by the command dispatcher.
+CREATE TABLE IF NOT EXISTS CommandEvent (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    command_id INTEGER NOT NULL, -- OutgoingCommand.id
+    at_ms INTEGER NOT NULL, -- UNIX epoch timestamp of the transition, in milliseconds
+    from_status TEXT, -- Status before the transition
+    to_status TEXT NOT NULL, -- Status after the transition
+    attempt INTEGER, -- OutgoingCommand.attempts at the time
+    operator TEXT, -- OutgoingCommand.operator at the time
+    source_service TEXT, -- OutgoingCommand.source_service at the time
+    gateway_connection_id TEXT, -- Dispatcher instance and connection number, e.g. 'command-dispatcher@host-42#3' (send events only)
+    mavlink_sequence INTEGER, -- MAVLink header sequence number used (send events only)
+    payload BLOB, -- Exact encoded RadioFrame placed in the POSTCARD_MESSAGE (send events only)
+    error_message TEXT, -- Reason for the transition, if any
+    recorded_by TEXT -- Service that wrote the event, e.g. 'command-dispatcher', 'rgs-web-commands'
+);
+
+CREATE INDEX IF NOT EXISTS idx_commandevent_command ON CommandEvent (command_id, at_ms);
+
+-- The trail starts when a command is queued, whoever inserts it
+CREATE TRIGGER IF NOT EXISTS trg_outgoingcommand_insert
+AFTER INSERT ON OutgoingCommand
+BEGIN
+    INSERT INTO CommandEvent (command_id, at_ms, from_status, to_status, attempt, operator, source_service, recorded_by)
+    VALUES (NEW.id, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER), NULL, NEW.status, NEW.attempts, NEW.operator, NEW.source_service, NEW.source_service);
+END;
+
+-- Events are appended only, never changed or removed
+CREATE TRIGGER IF NOT EXISTS trg_commandevent_no_update
+BEFORE UPDATE ON CommandEvent
+BEGIN
+    SELECT RAISE(ABORT, 'CommandEvent rows cannot be updated');
+END;
+
+CREATE TRIGGER IF NOT EXISTS trg_commandevent_no_delete
+BEFORE DELETE ON CommandEvent
+BEGIN
+    SELECT RAISE(ABORT, 'CommandEvent rows cannot be deleted');
+END;
+
+-- Second-operator confirmations for commands held in 'AwaitingConfirmation'.
+CREATE TABLE IF NOT EXISTS CommandConfirmation (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    command_id INTEGER NOT NULL, -- OutgoingCommand.id being confirmed
+    token TEXT NOT NULL, -- Signed by the command API for OutgoingCommand.confirmation_token and the operator
+    operator TEXT NOT NULL, -- Confirming operator, must differ from OutgoingCommand.operator
+    created_at INTEGER NOT NULL DEFAULT (strftime('%s','now'))
+);
+
+CREATE INDEX IF NOT EXISTS idx_commandconfirmation_command ON CommandConfirmation (command_id);
+
+-- Named command sequences (scripts) run step by step by the command dispatcher.
+CREATE TABLE IF NOT EXISTS CommandSequence (
+    name TEXT PRIMARY KEY, -- e.g., 'pre-launch'
+    description TEXT, -- Optional description of the sequence
+    steps TEXT NOT NULL, -- JSON array of steps, see command-dispatcher/README.md
+    updated_at INTEGER NOT NULL DEFAULT (strftime('%s','now'))
+);
+
+-- One execution of a CommandSequence. Insert a 'Pending' row to start one.
+CREATE TABLE IF NOT EXISTS SequenceRun (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    sequence_name TEXT NOT NULL, -- CommandSequence.name
+    status TEXT NOT NULL, -- 'Pending', 'Running', 'Paused', 'Completed', 'Failed', 'Aborted'
+    steps TEXT, -- Snapshot of CommandSequence.steps taken when the run started
+    current_step INTEGER NOT NULL DEFAULT 0, -- 0-based index of the step in progress
+    step_started_at INTEGER, -- UNIX epoch timestamp the current step began, or its command was issued
+    step_command_id INTEGER, -- OutgoingCommand.id issued for the current step (optional)
+    operator TEXT, -- Person who started the run; copied to its commands (optional)
+    created_at INTEGER NOT NULL DEFAULT (strftime('%s','now')),
+    started_at INTEGER, -- UNIX epoch timestamp the dispatcher started the run (optional)
+    finished_at INTEGER, -- UNIX epoch timestamp the run ended (optional)
+    error_message TEXT, -- Why the run failed, or the last step failure it continued past (optional)
+    paused_at INTEGER -- UNIX epoch timestamp the dispatcher saw the run paused, cleared on resume (optional)
+);
+
+CREATE INDEX IF NOT EXISTS idx_sequencerun_status ON SequenceRun (status);
+
 -- Table to store system-wide configuration settings.
 CREATE TABLE IF NOT EXISTS SystemConfig (
     key TEXT PRIMARY KEY, -- Unique configuration key, e.g., 'gateway_mode', 'serial_port_path'
@@ -61,7 +148,8 @@ CREATE TABLE IF NOT EXISTS RadioFrame (
     node TEXT NOT NULL, -- Enum Node type (PressureBoard, StrainBoard, etc)
     data_type TEXT NOT NULL, -- Payload type: "Command" | "Log" | "PhoenixState" | "PhoenixEvent" | "ArgusState" | "ArgusEvent" | "SbgGpsPos" | "SbgUtcTime" | "SbgImu" | "SbgEkfQuat" | "SbgEkfNav" | "SbgGpsVel" | "SbgAir" | "Gps" | "Imu" | "Madgwick" | "Barometer" | "ArgusPressure" | "ArgusTemperature" | "ArgusStrain"
     data_id INTEGER NOT NULL, -- Foreign key to specific data table
-    millis_since_start INTEGER -- Milliseconds since node start, if provided
+    millis_since_start INTEGER, -- Milliseconds since node start, if provided
+    received_at_ms INTEGER -- UNIX epoch timestamp in milliseconds when the ingestor received the frame (optional)
 );
 
 
@@ -247,16 +335,66 @@ CREATE TABLE IF NOT EXISTS SbgGpsPos (
     differential_age INTEGER
 );
 
--- Table to store radio metrics such as RSSI and packet loss statistics
+-- Table to store radio metrics such as RSSI and packet loss statistics, per gateway
 CREATE TABLE IF NOT EXISTS RadioMetrics (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     timestamp INTEGER NOT NULL,
     rssi INTEGER,
-    packets_lost INTEGER
+    packets_lost INTEGER,
+    gateway TEXT, -- Connection string of the gateway the metric was seen on (optional)
+    frames_received INTEGER, -- POSTCARD frames the gateway delivered since its previous row (optional)
+    frames_duplicate INTEGER -- Of those, frames another gateway had already delivered (optional)
 );
 
 CREATE INDEX IF NOT EXISTS idx_radiometrics_timestamp ON RadioMetrics (timestamp);
 
+-- Changes the command dispatcher's radio rate controller made or held back, with the link quality behind them.
+CREATE TABLE IF NOT EXISTS RadioRateDecision (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    decided_at INTEGER NOT NULL, -- UNIX epoch timestamp of the decision
+    avg_rssi REAL, -- Average RSSI over the evaluation window (optional)
+    loss_ratio REAL, -- Lost / (received + lost) packets over the window (optional)
+    current_rate TEXT, -- Rate of the last RadioRateChange sent: 'low', 'medium' or 'high' (optional)
+    wanted_rate TEXT NOT NULL, -- Rate the controller wanted
+    reason TEXT NOT NULL, -- 'link degraded' or 'link healthy'
+    suppressed_reason TEXT, -- Why no command was queued, e.g. manual lock or dwell time (optional)
+    command_id INTEGER -- OutgoingCommand.id queued for the change (optional)
+);
+
+CREATE INDEX IF NOT EXISTS idx_radioratedecision_decided ON RadioRateDecision (decided_at);
+
+-- Pings issued by the command dispatcher's link monitor and the Pongs matched to them.
+CREATE TABLE IF NOT EXISTS LinkPing (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    node TEXT NOT NULL, -- Node pinged, e.g. 'Phoenix'
+    ping_id INTEGER NOT NULL, -- Id carried by the Ping and echoed in the Pong
+    command_id INTEGER NOT NULL, -- OutgoingCommand.id of the Ping
+    created_at_ms INTEGER NOT NULL, -- UNIX epoch timestamp in milliseconds when the Ping was queued
+    sent_at_ms INTEGER, -- UNIX epoch timestamp in milliseconds when the Ping went to the gateway (optional)
+    pong_at_ms INTEGER, -- UNIX epoch timestamp in milliseconds when the matching Pong was received (optional)
+    rtt_ms INTEGER, -- pong_at_ms - sent_at_ms (optional)
+    status TEXT NOT NULL -- 'Waiting', 'Answered', 'Lost' (sent, no Pong in time) or 'NotSent' (never left the queue)
+);
+
+CREATE INDEX IF NOT EXISTS idx_linkping_node_status ON LinkPing (node, status, id);
+
+-- Per-node link statistics computed by the link monitor over its most recent resolved pings.
+CREATE TABLE IF NOT EXISTS LinkStats (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    node TEXT NOT NULL, -- Node the statistics are for
+    computed_at INTEGER NOT NULL, -- UNIX epoch timestamp of the computation
+    pings INTEGER NOT NULL, -- Answered + lost pings in the window
+    lost INTEGER NOT NULL, -- Pings in the window without a Pong in time
+    loss_ratio REAL, -- lost / pings (optional)
+    rtt_ms_last INTEGER, -- Round-trip time of the newest answered ping (optional)
+    rtt_ms_avg REAL, -- Mean round-trip time of the answered pings (optional)
+    rtt_ms_min INTEGER, -- (optional)
+    rtt_ms_max INTEGER, -- (optional)
+    jitter_ms REAL -- Mean absolute difference between consecutive round-trip times (optional)
+);
+
+CREATE INDEX IF NOT EXISTS idx_linkstats_node_computed ON LinkStats (node, computed_at);
+
 -- Summary table to maintain aggregates for fast queries
 CREATE TABLE IF NOT EXISTS MetricsSummary (
     id INTEGER PRIMARY KEY CHECK (id = 1),
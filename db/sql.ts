@@ -59,6 +59,15 @@ export function parseSqlStatements(sql: string): string[] {
   return statements;
 }
 
+/**
+ * Table and column of an `ALTER TABLE <table> ADD COLUMN <column> ...` statement,
+ * or null for any other statement.
+ */
+export function parseAddedColumn(statement: string): { table: string; column: string } | null {
+  const match = /^ALTER\s+TABLE\s+(\w+)\s+ADD\s+(?:COLUMN\s+)?(\w+)/i.exec(statement.trim());
+  return match ? { table: match[1], column: match[2] } : null;
+}
+
 export interface StatementExecutor {
   execute(sql: string): Promise<void>;
 }
@@ -0,0 +1,89 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::process::Command;
+
+/// How to tell that a running service is ready, as written in the definitions file. Exactly
+/// one of `tcp` and `command` is given; both may use `{placeholders}`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheckDefinition {
+    /// `host:port` that accepts TCP connections once the service is up.
+    pub tcp: Option<String>,
+    /// Program and arguments that exit 0 while the service is healthy.
+    pub command: Option<Vec<String>>,
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: f64,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: f64,
+}
+
+fn default_interval_secs() -> f64 {
+    1.0
+}
+
+fn default_timeout_secs() -> f64 {
+    2.0
+}
+
+#[derive(Debug, Clone)]
+pub enum Probe {
+    Tcp(String),
+    Command(Vec<String>),
+}
+
+/// A health check with its placeholders filled in for one run.
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    pub probe: Probe,
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// Working directory of the service, for command probes.
+    pub working_dir: PathBuf,
+}
+
+/// Result of the latest health check of a run.
+#[derive(Serialize, Debug, Clone)]
+pub struct HealthStatus {
+    pub healthy: bool,
+    /// UNIX milliseconds of the check.
+    pub checked_at_ms: i64,
+    pub error: Option<String>,
+}
+
+impl HealthCheck {
+    pub async fn check(&self) -> Result<(), String> {
+        match tokio::time::timeout(self.timeout, self.probe()).await {
+            Ok(result) => result,
+            Err(_) => Err(format!("no answer within {:?}", self.timeout)),
+        }
+    }
+
+    async fn probe(&self) -> Result<(), String> {
+        match &self.probe {
+            Probe::Tcp(address) => TcpStream::connect(address)
+                .await
+                .map(drop)
+                .map_err(|e| format!("connecting to {}: {}", address, e)),
+            Probe::Command(argv) => {
+                let status = Command::new(&argv[0])
+                    .args(&argv[1..])
+                    .current_dir(&self.working_dir)
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .kill_on_drop(true)
+                    .status()
+                    .await
+                    .map_err(|e| format!("running {}: {}", argv[0], e))?;
+                if status.success() {
+                    Ok(())
+                } else {
+                    Err(format!("{} exited with {}", argv[0], status))
+                }
+            }
+        }
+    }
+}
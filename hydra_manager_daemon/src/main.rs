@@ -1,67 +1,73 @@
+mod health;
+mod output;
+mod profile;
+mod restart;
+mod service;
+mod supervisor;
+
 use axum::{
-    extract::{Json, State},
-    http::StatusCode,
+    body::Bytes,
+    extract::{Json, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     routing::{get, post},
     Router,
 };
 use chrono::Local;
+use clap::Parser;
+use futures_util::stream::{self, Stream, StreamExt};
+use output::{OutputLine, OutputPage, OutputStream, MAX_OUTPUT_LINES};
+use profile::{ProfileDefinition, ProfileReport};
 use serde::{Deserialize, Serialize};
+use service::StartServicePayload;
 use std::collections::VecDeque;
 use std::net::SocketAddr;
-use std::process::Command;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
-use tokio::task::JoinHandle;
+use std::time::Duration;
+use supervisor::{ServiceStatus, Supervisor, SupervisorError, DEFAULT_STOP_GRACE_PERIOD};
+use tokio::sync::broadcast::error::RecvError;
 use tracing::{error, info, instrument}; // Added for timestamps
 
 const MAX_LOG_LINES: usize = 50;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct ServiceInfo {
-    name: String,
-    details: String,
-    // pid: Option<u32>, // Keep if you plan to use PIDs for stopping
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-enum ServiceType {
-    SerGW,
-}
-
-#[derive(Deserialize, Debug)]
-struct StartServicePayload {
-    service_type: ServiceType,
-    serial_port: Option<String>,
-    baud_rate: Option<u32>,
-    output_tcp_address: String,
-    output_tcp_port: u16,
-}
-
-#[derive(Serialize, Debug)]
-struct ServiceStatus {
-    message: String,
-    active_service: Option<String>,
-    details: Option<String>,
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// TOML file of `[[service]]` and `[[profile]]` definitions, reloaded on SIGHUP or `POST /services/reload`.
+    /// Defaults to the first `services.toml` found in `$XDG_CONFIG_HOME/hydra_manager_daemon`
+    /// (or `~/.config/hydra_manager_daemon`) or next to the executable
+    #[arg(long)]
+    services_file: Option<PathBuf>,
+
+    /// Directory of the ground station binaries, the `{bin_dir}` of service definitions.
+    /// Defaults to the file's `bin_dir`, else the directory of this executable
+    #[arg(long)]
+    bin_dir: Option<PathBuf>,
+
+    #[arg(long, default_value = "0.0.0.0:3030")]
+    listen_addr: SocketAddr,
 }
 
+/// The daemon's own messages, kept for the web UI.
 #[derive(Clone)]
-struct AppState {
-    active_service_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
-    current_service_info: Arc<Mutex<Option<ServiceInfo>>>,
-    log_store: Arc<Mutex<VecDeque<String>>>, // Added log store
+pub struct DaemonLog {
+    store: Arc<Mutex<VecDeque<String>>>,
 }
 
-impl AppState {
+impl DaemonLog {
     fn new() -> Self {
         Self {
-            active_service_handle: Arc::new(Mutex::new(None)),
-            current_service_info: Arc::new(Mutex::new(None)),
-            log_store: Arc::new(Mutex::new(VecDeque::with_capacity(MAX_LOG_LINES))),
+            store: Arc::new(Mutex::new(VecDeque::with_capacity(MAX_LOG_LINES))),
         }
     }
 
     // Method to add a log entry
-    fn add_log(&self, message: String) {
-        let mut store = self.log_store.lock().unwrap();
+    pub fn add(&self, message: String) {
+        let mut store = self.store.lock().unwrap();
         let timestamped_message =
             format!("[{}] {}", Local::now().format("%Y-%m-%d %H:%M:%S"), message);
 
@@ -76,27 +82,135 @@ impl AppState {
     }
 }
 
+#[derive(Clone)]
+struct AppState {
+    supervisor: Arc<Supervisor>,
+    log: DaemonLog,
+}
+
+impl AppState {
+    fn new(services_file: PathBuf, bin_dir: Option<PathBuf>) -> Result<Self, String> {
+        let log = DaemonLog::new();
+        Ok(Self {
+            supervisor: Arc::new(Supervisor::load(services_file, bin_dir, log.clone())?),
+            log,
+        })
+    }
+
+    fn add_log(&self, message: String) {
+        self.log.add(message);
+    }
+}
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt::init();
-    let app_state = AppState::new();
+    let args = Args::parse();
+    let services_file = match args.services_file.map_or_else(default_services_file, Ok) {
+        Ok(path) => path,
+        Err(e) => {
+            error!("Failed to load service definitions: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let app_state = match AppState::new(services_file, args.bin_dir) {
+        Ok(state) => state,
+        Err(e) => {
+            error!("Failed to load service definitions: {}", e);
+            std::process::exit(1);
+        }
+    };
     app_state.add_log("Hydra Manager Daemon starting up...".to_string());
 
+    let supervisor = app_state.supervisor.clone();
+    tokio::spawn(async move {
+        let mut hangups = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .expect("Failed to listen for SIGHUP");
+        while hangups.recv().await.is_some() {
+            if let Err(e) = supervisor.reload() {
+                error!("Failed to reload service definitions: {:?}", e);
+            }
+        }
+    });
+
     let app = Router::new()
         .route("/", get(root_handler))
-        .route("/service/start", post(start_new_service_handler))
-        .route("/service/stop", post(stop_service_handler))
-        .route("/service/status", get(get_service_status_handler))
+        .route("/services", get(list_services_handler))
+        .route("/services/reload", post(reload_services_handler))
+        .route("/services/:name", get(get_service_status_handler))
+        .route("/services/:name/start", post(start_service_handler))
+        .route("/services/:name/stop", post(stop_service_handler))
+        .route("/services/:name/restart", post(restart_service_handler))
+        .route("/services/:name/output", get(get_service_output_handler))
+        .route(
+            "/services/:name/output/stream",
+            get(stream_service_output_handler),
+        )
+        .route("/profiles", get(list_profiles_handler))
+        .route("/profiles/:name/start", post(start_profile_handler))
+        .route("/profiles/:name/stop", post(stop_profile_handler))
         .route("/logs", get(get_logs_handler)) // Added logs route
         .with_state(app_state.clone());
 
-    let addr_str = "0.0.0.0:3030";
-    let addr: SocketAddr = addr_str.parse().expect("Failed to parse address");
+    app_state.add_log(format!("Daemon listening on {}", args.listen_addr));
+
+    let listener = tokio::net::TcpListener::bind(args.listen_addr)
+        .await
+        .unwrap();
+    // Output streams never end on their own, so the server is dropped rather than drained
+    tokio::select! {
+        result = axum::serve(listener, app) => result.unwrap(),
+        signal = shutdown_signal() => {
+            app_state.add_log(format!("Received {}; stopping all services.", signal));
+            app_state
+                .supervisor
+                .stop_all(DEFAULT_STOP_GRACE_PERIOD)
+                .await;
+            app_state.add_log("All services stopped; exiting.".to_string());
+        }
+    }
+}
 
-    app_state.add_log(format!("Daemon listening on {}", addr_str));
+// Services run in their own process groups, so ctrl-c and SIGTERM only reach the daemon
+async fn shutdown_signal() -> &'static str {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
 
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+// The first services.toml in the config directory or next to the executable, so the daemon
+// finds its definitions whatever directory it is started from
+fn default_services_file() -> Result<PathBuf, String> {
+    const FILE_NAME: &str = "services.toml";
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+    let exe_dir = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(PathBuf::from));
+    let candidates: Vec<PathBuf> = config_dir
+        .map(|dir| dir.join("hydra_manager_daemon").join(FILE_NAME))
+        .into_iter()
+        .chain(exe_dir.map(|dir| dir.join(FILE_NAME)))
+        .collect();
+    candidates
+        .iter()
+        .find(|candidate| candidate.is_file())
+        .cloned()
+        .ok_or_else(|| {
+            format!(
+                "No {} found (searched {}); pass --services-file",
+                FILE_NAME,
+                candidates
+                    .iter()
+                    .map(|candidate| candidate.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
 }
 
 #[instrument(skip(state))]
@@ -112,212 +226,299 @@ struct LogsResponse {
 
 #[instrument(skip(state))]
 async fn get_logs_handler(State(state): State<AppState>) -> Json<LogsResponse> {
-    let store = state.log_store.lock().unwrap();
+    let store = state.log.store.lock().unwrap();
     Json(LogsResponse {
         logs: store.iter().cloned().collect(),
     })
 }
 
-#[instrument(skip(state, payload))]
-async fn start_new_service_handler(
-    State(state): State<AppState>,
-    Json(payload): Json<StartServicePayload>,
-) -> (StatusCode, Json<ServiceStatus>) {
-    state.add_log(format!(
-        "Received start request for service: {:?}",
-        payload.service_type
-    ));
-
-    let mut handle_guard = state.active_service_handle.lock().unwrap();
-    if handle_guard.is_some() {
-        state.add_log(
-            "Attempted to start a new service while another is already running.".to_string(),
-        );
-        return (
-            StatusCode::CONFLICT,
-            Json(ServiceStatus {
-                message: "A service is already running. Please stop it first.".to_string(),
-                active_service: state
-                    .current_service_info
-                    .lock()
-                    .unwrap()
-                    .as_ref()
-                    .map(|si| si.name.clone()),
-                details: state
-                    .current_service_info
-                    .lock()
-                    .unwrap()
-                    .as_ref()
-                    .map(|si| si.details.clone()),
-            }),
-        );
-    }
+#[derive(Serialize, Debug)]
+struct ActionResponse {
+    message: String,
+    status: ServiceStatus,
+}
 
-    let service_name_for_status = format!("{:?}", payload.service_type);
-    let service_details_for_status = match payload.service_type {
-        ServiceType::SerGW => format!(
-            "SerGW - Serial: {}, Baud: {}, Host: {}:{}",
-            payload.serial_port.as_deref().unwrap_or("N/A"),
-            payload
-                .baud_rate
-                .map_or("N/A".to_string(), |b| b.to_string()),
-            payload.output_tcp_address,
-            payload.output_tcp_port
-        ),
-    };
+enum ApiError {
+    BadPayload(String),
+    Supervisor(SupervisorError),
+}
 
-    let state_clone = state.clone();
-    let service_info_for_task = ServiceInfo {
-        name: service_name_for_status.clone(),
-        details: service_details_for_status.clone(),
-    };
+impl From<SupervisorError> for ApiError {
+    fn from(e: SupervisorError) -> Self {
+        ApiError::Supervisor(e)
+    }
+}
 
-    let handle = tokio::spawn(async move {
-        let mut cmd = match payload.service_type {
-            ServiceType::SerGW => {
-                let mut command = Command::new("./target/debug/sergw"); // Ensure this path is correct
-                command.arg("listen"); // Added "listen" subcommand
-                if let Some(serial_port) = payload.serial_port {
-                    command.arg("--serial").arg(serial_port);
-                }
-                if let Some(baud_rate) = payload.baud_rate {
-                    command.arg("--baud").arg(baud_rate.to_string());
-                }
-                command.arg("--host").arg(format!(
-                    "{}:{}",
-                    payload.output_tcp_address, payload.output_tcp_port
-                ));
-                command
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, message, service) = match self {
+            ApiError::Supervisor(SupervisorError::UnknownService(name)) => {
+                (StatusCode::NOT_FOUND, format!("No service {}", name), None)
             }
-        };
-
-        state_clone.add_log(format!(
-            "Attempting to start {} with command: {:?}",
-            service_info_for_task.name, cmd
-        ));
-
-        match cmd.status() {
-            Ok(status) => {
-                if status.success() {
-                    state_clone.add_log(format!(
-                        "Service {} finished successfully.",
-                        service_info_for_task.name
-                    ));
-                } else {
-                    state_clone.add_log(format!(
-                        "Service {} exited with status: {}. Check daemon logs for more details.",
-                        service_info_for_task.name, status
-                    ));
-                    // Attempt to capture stderr if available
-                    // This part is tricky with std::process::Command and async,
-                    // consider tokio::process::Command for better output handling.
-                }
+            ApiError::Supervisor(SupervisorError::UnknownProfile(name)) => {
+                (StatusCode::NOT_FOUND, format!("No profile {}", name), None)
             }
-            Err(e) => {
-                state_clone.add_log(format!(
-                    "Failed to start service {}: {}",
-                    service_info_for_task.name, e
-                ));
-                error!(
-                    "Failed to start service {}: {}",
-                    service_info_for_task.name, e
-                );
+            ApiError::BadPayload(message) => (StatusCode::BAD_REQUEST, message, None),
+            ApiError::Supervisor(SupervisorError::AlreadyRunning(status)) => (
+                StatusCode::CONFLICT,
+                format!(
+                    "{} is already running. Please stop it first.",
+                    status.service
+                ),
+                Some(status),
+            ),
+            ApiError::Supervisor(SupervisorError::NotRunning(name)) => (
+                StatusCode::NOT_FOUND,
+                format!("{} is not running.", name),
+                None,
+            ),
+            ApiError::Supervisor(SupervisorError::InvalidPayload(message)) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, message, None)
             }
-        }
-        // Clear service info when task ends
-        let mut info_guard = state_clone.current_service_info.lock().unwrap();
-        *info_guard = None;
-        let mut handle_guard_task_end = state_clone.active_service_handle.lock().unwrap(); //
-        *handle_guard_task_end = None; // Clear the handle itself from within the task upon completion
-        state_clone.add_log(format!(
-            "Service task for {} ended.",
-            service_info_for_task.name
-        ));
-    });
+            ApiError::Supervisor(SupervisorError::Spawn(name, e)) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to start {}: {}", name, e),
+                None,
+            ),
+            ApiError::Supervisor(SupervisorError::Definitions(message)) => {
+                (StatusCode::CONFLICT, message, None)
+            }
+        };
+        (
+            status,
+            Json(serde_json::json!({ "error": message, "status": service })),
+        )
+            .into_response()
+    }
+}
 
-    *handle_guard = Some(handle);
-    let mut current_info_guard = state.current_service_info.lock().unwrap();
-    *current_info_guard = Some(ServiceInfo {
-        name: service_name_for_status.clone(),
-        details: service_details_for_status,
-    });
+// An empty body means "same as last time"; anything else must be a valid payload
+fn start_payload(body: &Bytes) -> Result<Option<StartServicePayload>, ApiError> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(None);
+    }
+    serde_json::from_slice(body)
+        .map(Some)
+        .map_err(|e| ApiError::BadPayload(format!("Invalid start payload: {}", e)))
+}
 
-    state.add_log(format!(
-        "Service {} start process initiated.",
-        service_name_for_status
-    ));
-
-    (
-        StatusCode::OK,
-        Json(ServiceStatus {
-            message: format!("Service {} starting...", service_name_for_status),
-            active_service: Some(service_name_for_status),
-            details: current_info_guard.as_ref().map(|si| si.details.clone()),
-        }),
-    )
+#[derive(Deserialize, Debug)]
+struct StopQuery {
+    /// Seconds between SIGTERM and SIGKILL.
+    grace_secs: Option<u64>,
 }
 
-#[instrument(skip(state))]
-async fn stop_service_handler(State(state): State<AppState>) -> (StatusCode, Json<ServiceStatus>) {
-    state.add_log("Received request to stop active service.".to_string());
-    let mut handle_guard = state.active_service_handle.lock().unwrap();
-    let mut current_info_guard = state.current_service_info.lock().unwrap();
-
-    if let Some(handle) = handle_guard.take() {
-        handle.abort(); // Send abort signal
-                        // Note: Aborting a JoinHandle doesn't directly kill the spawned OS process.
-                        // True process killing needs OS-specific calls (e.g., using PID, if stored and managed).
-                        // For now, we rely on the service itself to terminate gracefully upon task cancellation if possible,
-                        // or the OS to clean it up if it's a child process that exits when parent does (not always the case).
-
-        let service_name = current_info_guard
-            .as_ref()
-            .map_or("Unknown".to_string(), |si| si.name.clone());
-        *current_info_guard = None; // Clear current service info
-
-        state.add_log(format!(
-            "Abort signal sent to service task: {}",
-            service_name
-        ));
-        (
-            StatusCode::OK,
-            Json(ServiceStatus {
-                message: format!(
-                    "Stop signal sent to service {}. It might take a moment to terminate.",
-                    service_name
-                ),
-                active_service: None,
-                details: None,
-            }),
-        )
-    } else {
-        state.add_log("No active service found to stop.".to_string());
-        (
-            StatusCode::NOT_FOUND,
-            Json(ServiceStatus {
-                message: "No active service was running.".to_string(),
-                active_service: None,
-                details: None,
-            }),
-        )
+impl StopQuery {
+    fn grace(&self) -> Duration {
+        self.grace_secs
+            .map_or(DEFAULT_STOP_GRACE_PERIOD, Duration::from_secs)
     }
 }
 
 #[instrument(skip(state))]
-async fn get_service_status_handler(State(state): State<AppState>) -> Json<ServiceStatus> {
+async fn list_services_handler(State(state): State<AppState>) -> Json<Vec<ServiceStatus>> {
+    Json(state.supervisor.list())
+}
+
+#[instrument(skip(state))]
+async fn reload_services_handler(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<ServiceStatus>>, ApiError> {
+    state.add_log("Received request to reload service definitions.".to_string());
+    Ok(Json(state.supervisor.reload()?))
+}
+
+#[instrument(skip(state))]
+async fn get_service_status_handler(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<ServiceStatus>, ApiError> {
     // state.add_log("Service status requested.".to_string()); // Can be verbose, enable if needed
-    let info_guard = state.current_service_info.lock().unwrap();
-    if let Some(info) = &*info_guard {
-        Json(ServiceStatus {
-            message: "Service is running.".to_string(),
-            active_service: Some(info.name.clone()),
-            details: Some(info.details.clone()),
-        })
+    Ok(Json(state.supervisor.service(&name)?.status()))
+}
+
+#[instrument(skip(state, body))]
+async fn start_service_handler(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    body: Bytes,
+) -> Result<Json<ActionResponse>, ApiError> {
+    let service = state.supervisor.service(&name)?;
+    state.add_log(format!("Received start request for service: {}", name));
+    let status = service.start(start_payload(&body)?)?;
+    Ok(Json(ActionResponse {
+        message: format!("Service {} starting...", name),
+        status,
+    }))
+}
+
+#[instrument(skip(state))]
+async fn stop_service_handler(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(query): Query<StopQuery>,
+) -> Result<Json<ActionResponse>, ApiError> {
+    let service = state.supervisor.service(&name)?;
+    state.add_log(format!("Received request to stop service: {}", name));
+    let status = service.stop(query.grace()).await?;
+    Ok(Json(ActionResponse {
+        message: format!("Service {} stopped.", name),
+        status,
+    }))
+}
+
+#[instrument(skip(state, body))]
+async fn restart_service_handler(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(query): Query<StopQuery>,
+    body: Bytes,
+) -> Result<Json<ActionResponse>, ApiError> {
+    let service = state.supervisor.service(&name)?;
+    state.add_log(format!("Received restart request for service: {}", name));
+    let status = service
+        .restart(start_payload(&body)?, query.grace())
+        .await?;
+    Ok(Json(ActionResponse {
+        message: format!("Service {} restarting...", name),
+        status,
+    }))
+}
+
+#[instrument(skip(state))]
+async fn list_profiles_handler(State(state): State<AppState>) -> Json<Vec<ProfileDefinition>> {
+    Json(
+        state
+            .supervisor
+            .profiles()
+            .iter()
+            .map(|p| ProfileDefinition::clone(p))
+            .collect(),
+    )
+}
+
+// 424 Failed Dependency when a service of the profile did not come up or go down
+fn profile_response(report: ProfileReport) -> (StatusCode, Json<ProfileReport>) {
+    let status = if report.ok {
+        StatusCode::OK
     } else {
-        Json(ServiceStatus {
-            message: "No service is currently running.".to_string(),
-            active_service: None,
-            details: None,
-        })
-    }
+        StatusCode::FAILED_DEPENDENCY
+    };
+    (status, Json(report))
+}
+
+#[instrument(skip(state))]
+async fn start_profile_handler(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<(StatusCode, Json<ProfileReport>), ApiError> {
+    let profile = state.supervisor.profile(&name)?;
+    state.add_log(format!("Received start request for profile: {}", name));
+    Ok(profile_response(
+        profile::start(&state.supervisor, &profile).await,
+    ))
+}
+
+#[instrument(skip(state))]
+async fn stop_profile_handler(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(query): Query<StopQuery>,
+) -> Result<(StatusCode, Json<ProfileReport>), ApiError> {
+    let profile = state.supervisor.profile(&name)?;
+    state.add_log(format!("Received stop request for profile: {}", name));
+    Ok(profile_response(
+        profile::stop(&state.supervisor, &profile, query.grace()).await,
+    ))
+}
+
+#[derive(Deserialize, Debug)]
+struct OutputQuery {
+    /// Lines after this `seq`, oldest first.
+    after: Option<u64>,
+    /// Lines before this `seq`, to page backwards. Ignored with `after`.
+    before: Option<u64>,
+    #[serde(default = "default_output_limit")]
+    limit: usize,
+    /// `stdout` or `stderr`; both when absent.
+    stream: Option<OutputStream>,
+}
+
+fn default_output_limit() -> usize {
+    200
+}
+
+#[instrument(skip(state))]
+async fn get_service_output_handler(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(query): Query<OutputQuery>,
+) -> Result<Json<OutputPage>, ApiError> {
+    Ok(Json(state.supervisor.service(&name)?.output().page(
+        query.after,
+        query.before,
+        query.limit.min(MAX_OUTPUT_LINES),
+        query.stream,
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct TailQuery {
+    /// Resume after this `seq`. A reconnecting `EventSource` sends `Last-Event-ID` instead.
+    after: Option<u64>,
+    /// Buffered lines sent first when neither `after` nor `Last-Event-ID` is given.
+    #[serde(default = "default_tail_backlog")]
+    backlog: usize,
+    stream: Option<OutputStream>,
+}
+
+fn default_tail_backlog() -> usize {
+    100
+}
+
+fn output_event(line: &OutputLine) -> Result<Event, axum::Error> {
+    Event::default()
+        .id(line.seq.to_string())
+        .event(line.stream.as_str())
+        .json_data(line)
+}
+
+// Server-sent events of a service's output lines, starting with the buffered ones
+#[instrument(skip(state, headers))]
+async fn stream_service_output_handler(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(query): Query<TailQuery>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, ApiError> {
+    let service = state.supervisor.service(&name)?;
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|id| id.to_str().ok()?.parse().ok());
+    let (backlog, receiver) = service
+        .output()
+        .follow(query.after.or(last_event_id), query.backlog);
+    let wanted = query.stream;
+    let matching = move |line: &OutputLine| wanted.is_none_or(|s| line.stream == s);
+
+    let buffered = stream::iter(
+        backlog
+            .into_iter()
+            .filter(matching)
+            .map(|line| output_event(&line)),
+    );
+    let live = stream::unfold(receiver, move |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(line) if matching(&line) => return Some((output_event(&line), receiver)),
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    let event = Event::default().comment(format!("skipped {} lines", skipped));
+                    return Some((Ok(event), receiver));
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    Ok(Sse::new(buffered.chain(live)).keep_alive(KeepAlive::default()))
 }
@@ -0,0 +1,166 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::sync::broadcast;
+
+/// Lines of output kept per service; older ones are dropped.
+pub const MAX_OUTPUT_LINES: usize = 5000;
+/// Lines a live tail may fall behind before it skips ahead.
+const TAIL_CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+impl OutputStream {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OutputStream::Stdout => "stdout",
+            OutputStream::Stderr => "stderr",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct OutputLine {
+    /// Position in the service's output, counting up across runs. Used as the paging cursor.
+    pub seq: u64,
+    /// UNIX milliseconds the line was read.
+    pub at_ms: i64,
+    /// Start count of the service the line came from, as in `ServiceStatus`.
+    pub run: u64,
+    pub stream: OutputStream,
+    pub line: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct OutputPage {
+    pub lines: Vec<OutputLine>,
+    /// Oldest `seq` still buffered; anything older was dropped.
+    pub first_seq: Option<u64>,
+    /// `seq` of the newest line, to continue with `after`.
+    pub last_seq: Option<u64>,
+}
+
+#[derive(Default)]
+struct Lines {
+    next_seq: u64,
+    lines: VecDeque<OutputLine>,
+}
+
+/// The last `MAX_OUTPUT_LINES` stdout and stderr lines of one service, with a channel for live
+/// tails.
+pub struct OutputBuffer {
+    lines: Mutex<Lines>,
+    tail: broadcast::Sender<OutputLine>,
+}
+
+impl OutputBuffer {
+    pub fn new() -> Self {
+        Self {
+            lines: Mutex::new(Lines::default()),
+            tail: broadcast::channel(TAIL_CHANNEL_CAPACITY).0,
+        }
+    }
+
+    fn push(&self, run: u64, stream: OutputStream, line: String) {
+        let mut lines = self.lines.lock().unwrap();
+        let line = OutputLine {
+            seq: lines.next_seq,
+            at_ms: Utc::now().timestamp_millis(),
+            run,
+            stream,
+            line,
+        };
+        lines.next_seq += 1;
+        if lines.lines.len() >= MAX_OUTPUT_LINES {
+            lines.lines.pop_front();
+        }
+        lines.lines.push_back(line.clone());
+        // Sent under the lock so tails see lines in `seq` order; no receivers is fine
+        let _ = self.tail.send(line);
+    }
+
+    /// Up to `limit` lines of `stream` (both when `None`): the ones right after `after`, else
+    /// the ones right before `before`, else the newest. Oldest first either way.
+    pub fn page(
+        &self,
+        after: Option<u64>,
+        before: Option<u64>,
+        limit: usize,
+        stream: Option<OutputStream>,
+    ) -> OutputPage {
+        let lines = self.lines.lock().unwrap();
+        let matching = |line: &&OutputLine| stream.is_none_or(|s| line.stream == s);
+        let page: Vec<OutputLine> = match after {
+            Some(after) => lines
+                .lines
+                .iter()
+                .filter(|line| line.seq > after)
+                .filter(matching)
+                .take(limit)
+                .cloned()
+                .collect(),
+            None => {
+                let mut newest: Vec<OutputLine> = lines
+                    .lines
+                    .iter()
+                    .rev()
+                    .filter(|line| before.is_none_or(|before| line.seq < before))
+                    .filter(matching)
+                    .take(limit)
+                    .cloned()
+                    .collect();
+                newest.reverse();
+                newest
+            }
+        };
+        OutputPage {
+            first_seq: lines.lines.front().map(|line| line.seq),
+            last_seq: page.last().map(|line| line.seq),
+            lines: page,
+        }
+    }
+
+    /// Lines after `after` (the newest `backlog` when `None`) and a receiver of every line
+    /// pushed from then on, with no gap or overlap between the two.
+    pub fn follow(
+        &self,
+        after: Option<u64>,
+        backlog: usize,
+    ) -> (Vec<OutputLine>, broadcast::Receiver<OutputLine>) {
+        let lines = self.lines.lock().unwrap();
+        let receiver = self.tail.subscribe();
+        let skip = match after {
+            Some(after) => lines.lines.iter().take_while(|l| l.seq <= after).count(),
+            None => lines.lines.len().saturating_sub(backlog),
+        };
+        (lines.lines.iter().skip(skip).cloned().collect(), receiver)
+    }
+
+    /// Reads `reader` line by line into the buffer until it closes. Invalid UTF-8 is replaced
+    /// rather than ending the capture.
+    pub async fn capture(&self, run: u64, stream: OutputStream, reader: impl AsyncRead + Unpin) {
+        let mut reader = BufReader::new(reader);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf).await {
+                Ok(0) => break,
+                Ok(_) => {
+                    let line = String::from_utf8_lossy(&buf);
+                    self.push(run, stream, line.trim_end_matches(['\n', '\r']).to_string());
+                }
+                Err(e) => {
+                    self.push(run, stream, format!("<output capture failed: {}>", e));
+                    break;
+                }
+            }
+        }
+    }
+}
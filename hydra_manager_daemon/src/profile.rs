@@ -0,0 +1,431 @@
+use crate::service::{self, ServiceDefinition, StartServicePayload};
+use crate::supervisor::{ManagedService, RunState, Supervisor, SupervisorError};
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
+use tokio::sync::watch;
+
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A `[[profile]]` entry of the definitions file: a group of services started and stopped
+/// together.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileDefinition {
+    pub name: String,
+    /// Services to run. Their dependencies are included even when not listed.
+    pub services: Vec<String>,
+    /// Start payload per service, as in `POST /services/:name/start`. Services without one
+    /// are started with their last payload.
+    #[serde(default)]
+    pub start: BTreeMap<String, StartServicePayload>,
+    /// How long each service gets to become ready before the start fails.
+    #[serde(default = "default_ready_timeout_secs")]
+    pub ready_timeout_secs: f64,
+    /// The services with their dependencies, every service after the ones it depends on.
+    #[serde(skip_deserializing)]
+    pub order: Vec<String>,
+}
+
+fn default_ready_timeout_secs() -> f64 {
+    60.0
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProfileOutcome {
+    /// Started and ready.
+    Started,
+    /// Was already running, and is ready.
+    AlreadyRunning,
+    Stopped,
+    NotRunning,
+    Failed,
+    /// Not started because a dependency failed.
+    Skipped,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ProfileServiceReport {
+    pub service: String,
+    pub outcome: ProfileOutcome,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ProfileReport {
+    pub profile: String,
+    /// Every service came up, or every service stopped.
+    pub ok: bool,
+    /// In dependency order.
+    pub services: Vec<ProfileServiceReport>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Readiness {
+    Pending,
+    Ready,
+    Failed,
+}
+
+/// Checks the profiles against the service definitions and works out their start order.
+pub fn resolve(
+    profiles: &mut [ProfileDefinition],
+    definitions: &[ServiceDefinition],
+) -> Result<(), String> {
+    fn visit(name: &str, definitions: &[ServiceDefinition], order: &mut Vec<String>) {
+        if order.iter().any(|n| n == name) {
+            return;
+        }
+        if let Some(definition) = definitions.iter().find(|d| d.name == name) {
+            for dependency in &definition.depends_on {
+                visit(dependency, definitions, order);
+            }
+        }
+        order.push(name.to_string());
+    }
+
+    let mut names = HashSet::new();
+    for profile in profiles.iter_mut() {
+        service::validate_name(&profile.name)
+            .map_err(|e| format!("profile '{}': {}", profile.name, e))?;
+        if !names.insert(profile.name.clone()) {
+            return Err(format!("profile '{}' is defined twice", profile.name));
+        }
+        if !(profile.ready_timeout_secs > 0.0 && profile.ready_timeout_secs.is_finite()) {
+            return Err(format!(
+                "profile '{}': ready_timeout_secs must be positive",
+                profile.name
+            ));
+        }
+        for service in &profile.services {
+            if !definitions.iter().any(|d| d.name == *service) {
+                return Err(format!(
+                    "profile '{}' has unknown service '{}'",
+                    profile.name, service
+                ));
+            }
+        }
+        let mut order = Vec::new();
+        for service in &profile.services {
+            visit(service, definitions, &mut order);
+        }
+        for service in profile.start.keys() {
+            if !order.contains(service) {
+                return Err(format!(
+                    "profile '{}' has a start payload for '{}', which it does not run",
+                    profile.name, service
+                ));
+            }
+        }
+        profile.order = order;
+    }
+    Ok(())
+}
+
+/// Starts the services of `profile`, each once the services it depends on are ready: running,
+/// and healthy if they have a health check. Services that do not depend on each other start
+/// at the same time. Services already running are left as they are; nothing is stopped when
+/// a service fails.
+pub async fn start(supervisor: &Supervisor, profile: &ProfileDefinition) -> ProfileReport {
+    supervisor.log().add(format!(
+        "Starting profile {}: {}",
+        profile.name,
+        profile.order.join(", ")
+    ));
+    let timeout = Duration::from_secs_f64(profile.ready_timeout_secs);
+    let mut senders = HashMap::new();
+    let mut readiness = HashMap::new();
+    for name in &profile.order {
+        let (tx, rx) = watch::channel(Readiness::Pending);
+        senders.insert(name.clone(), tx);
+        readiness.insert(name.clone(), rx);
+    }
+
+    let bring_ups = profile.order.iter().map(|name| {
+        let ready_tx = senders.remove(name).expect("one sender per service");
+        let readiness = &readiness;
+        async move {
+            let report = bring_up(supervisor, profile, name, readiness, timeout).await;
+            let _ = ready_tx.send(match report.outcome {
+                ProfileOutcome::Started | ProfileOutcome::AlreadyRunning => Readiness::Ready,
+                _ => Readiness::Failed,
+            });
+            report
+        }
+    });
+    let services = join_all(bring_ups).await;
+    finish(supervisor, profile, "start", services)
+}
+
+async fn bring_up(
+    supervisor: &Supervisor,
+    profile: &ProfileDefinition,
+    name: &str,
+    readiness: &HashMap<String, watch::Receiver<Readiness>>,
+    timeout: Duration,
+) -> ProfileServiceReport {
+    let report = |outcome, error| ProfileServiceReport {
+        service: name.to_string(),
+        outcome,
+        error,
+    };
+    let service = match supervisor.service(name) {
+        Ok(service) => service,
+        Err(e) => return report(ProfileOutcome::Failed, Some(format!("{:?}", e))),
+    };
+
+    for dependency in service.depends_on() {
+        let Some(rx) = readiness.get(&dependency) else {
+            continue;
+        };
+        let mut rx = rx.clone();
+        let ready = rx
+            .wait_for(|r| *r != Readiness::Pending)
+            .await
+            .is_ok_and(|r| *r == Readiness::Ready);
+        if !ready {
+            return report(
+                ProfileOutcome::Skipped,
+                Some(format!("dependency {} did not come up", dependency)),
+            );
+        }
+    }
+
+    let outcome = match service.start(profile.start.get(name).cloned()) {
+        Ok(_) => ProfileOutcome::Started,
+        Err(SupervisorError::AlreadyRunning(_)) => match profile.start.get(name) {
+            // Running with other values than the profile asks for is not what it needs
+            Some(wanted) if !same_payload(wanted, &service.last_payload()) => {
+                return report(
+                    ProfileOutcome::Failed,
+                    Some(
+                        "already running with a different start payload; stop or restart it with the profile's"
+                            .to_string(),
+                    ),
+                )
+            }
+            _ => ProfileOutcome::AlreadyRunning,
+        },
+        Err(SupervisorError::InvalidPayload(message)) => {
+            return report(ProfileOutcome::Failed, Some(message))
+        }
+        Err(SupervisorError::Spawn(_, e)) => {
+            return report(
+                ProfileOutcome::Failed,
+                Some(format!("failed to start: {}", e)),
+            )
+        }
+        Err(e) => return report(ProfileOutcome::Failed, Some(format!("{:?}", e))),
+    };
+    match wait_ready(&service, timeout).await {
+        Ok(()) => {
+            supervisor
+                .log()
+                .add(format!("Profile {}: {} is ready.", profile.name, name));
+            report(outcome, None)
+        }
+        Err(e) => report(ProfileOutcome::Failed, Some(e)),
+    }
+}
+
+fn same_payload(a: &StartServicePayload, b: &StartServicePayload) -> bool {
+    serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
+}
+
+// Waits until the service runs and, if it has a health check, has passed it
+async fn wait_ready(service: &ManagedService, timeout: Duration) -> Result<(), String> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let status = service.status();
+        match status.state {
+            RunState::Running
+                if !service.has_health_check()
+                    || status.health.as_ref().is_some_and(|h| h.healthy) =>
+            {
+                return Ok(())
+            }
+            // Waiting for the first passing health check, or for a restart after a crash
+            RunState::Running | RunState::Backoff => {}
+            RunState::Stopping | RunState::Stopped | RunState::Failed => {
+                return Err(match status.last_exit {
+                    Some(exit) => format!("exited before becoming ready: {:?}", exit),
+                    None => "stopped before becoming ready".to_string(),
+                });
+            }
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return Err(match status.health.and_then(|h| h.error) {
+                Some(error) => format!("not healthy within {:?}: {}", timeout, error),
+                None => format!("not ready within {:?}", timeout),
+            });
+        }
+        tokio::time::sleep(READY_POLL_INTERVAL).await;
+    }
+}
+
+/// Stops the services of `profile` in reverse dependency order, each given `grace` between
+/// SIGTERM and SIGKILL.
+pub async fn stop(
+    supervisor: &Supervisor,
+    profile: &ProfileDefinition,
+    grace: Duration,
+) -> ProfileReport {
+    supervisor
+        .log()
+        .add(format!("Stopping profile {}", profile.name));
+    let mut services = Vec::with_capacity(profile.order.len());
+    for name in profile.order.iter().rev() {
+        let result = match supervisor.service(name) {
+            Ok(service) => service.stop(grace).await,
+            Err(e) => Err(e),
+        };
+        let (outcome, error) = match result {
+            Ok(_) => (ProfileOutcome::Stopped, None),
+            Err(SupervisorError::NotRunning(_)) => (ProfileOutcome::NotRunning, None),
+            Err(e) => (ProfileOutcome::Failed, Some(format!("{:?}", e))),
+        };
+        services.push(ProfileServiceReport {
+            service: name.clone(),
+            outcome,
+            error,
+        });
+    }
+    services.reverse();
+    finish(supervisor, profile, "stop", services)
+}
+
+fn finish(
+    supervisor: &Supervisor,
+    profile: &ProfileDefinition,
+    action: &str,
+    services: Vec<ProfileServiceReport>,
+) -> ProfileReport {
+    let failed: Vec<&str> = services
+        .iter()
+        .filter(|s| matches!(s.outcome, ProfileOutcome::Failed | ProfileOutcome::Skipped))
+        .map(|s| s.service.as_str())
+        .collect();
+    supervisor.log().add(if failed.is_empty() {
+        format!("Profile {} {} finished.", profile.name, action)
+    } else {
+        format!(
+            "Profile {} {} failed for: {}",
+            profile.name,
+            action,
+            failed.join(", ")
+        )
+    });
+    ProfileReport {
+        profile: profile.name.clone(),
+        ok: failed.is_empty(),
+        services,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn service(name: &str, program: &str, depends_on: &[&str]) -> ServiceDefinition {
+        serde_json::from_value(json!({
+            "name": name,
+            "program": program,
+            "args": ["{secs}"],
+            "working_dir": "/tmp",
+            "depends_on": depends_on,
+            "defaults": { "secs": 1000 }
+        }))
+        .unwrap()
+    }
+
+    fn profile(services: &[&str], start: serde_json::Value) -> ProfileDefinition {
+        serde_json::from_value(json!({
+            "name": "test",
+            "services": services,
+            "start": start,
+            "ready_timeout_secs": 5
+        }))
+        .unwrap()
+    }
+
+    fn outcomes(report: &ProfileReport) -> Vec<(&str, ProfileOutcome)> {
+        report
+            .services
+            .iter()
+            .map(|s| (s.service.as_str(), s.outcome))
+            .collect()
+    }
+
+    #[test]
+    fn orders_services_after_their_dependencies() {
+        let definitions = [
+            service("ingestor", "sleep", &["sergw"]),
+            service("dispatcher", "sleep", &["sergw", "db"]),
+            service("sergw", "sleep", &[]),
+            service("db", "sleep", &[]),
+        ];
+        let mut profiles = [profile(&["dispatcher", "ingestor"], json!({}))];
+        resolve(&mut profiles, &definitions).unwrap();
+        assert_eq!(profiles[0].order, ["sergw", "db", "dispatcher", "ingestor"]);
+
+        let mut unknown = [profile(&["gps"], json!({}))];
+        assert!(resolve(&mut unknown, &definitions).is_err());
+        let mut stray_payload = [profile(&["sergw"], json!({ "db": {} }))];
+        assert!(resolve(&mut stray_payload, &definitions).is_err());
+    }
+
+    #[tokio::test]
+    async fn skips_services_whose_dependency_failed() {
+        let definitions = vec![
+            service("sergw", "/nonexistent/sergw", &[]),
+            service("ingestor", "sleep", &["sergw"]),
+        ];
+        let mut profiles = [profile(&["ingestor"], json!({}))];
+        resolve(&mut profiles, &definitions).unwrap();
+        let supervisor = Supervisor::from_definitions(definitions);
+
+        let report = start(&supervisor, &profiles[0]).await;
+        assert!(!report.ok);
+        assert_eq!(
+            outcomes(&report),
+            [
+                ("sergw", ProfileOutcome::Failed),
+                ("ingestor", ProfileOutcome::Skipped)
+            ]
+        );
+        assert!(supervisor
+            .service("ingestor")
+            .unwrap()
+            .status()
+            .pid
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn a_running_service_must_have_the_profiles_payload() {
+        let definitions = vec![service("sergw", "sleep", &[])];
+        let mut profiles = [
+            profile(&["sergw"], json!({ "sergw": { "secs": 1000 } })),
+            profile(&["sergw"], json!({ "sergw": { "secs": 999 } })),
+        ];
+        profiles[1].name = "other".to_string();
+        resolve(&mut profiles, &definitions).unwrap();
+        let supervisor = Supervisor::from_definitions(definitions);
+
+        let report = start(&supervisor, &profiles[0]).await;
+        assert_eq!(outcomes(&report), [("sergw", ProfileOutcome::Started)]);
+        let report = start(&supervisor, &profiles[0]).await;
+        assert_eq!(
+            outcomes(&report),
+            [("sergw", ProfileOutcome::AlreadyRunning)]
+        );
+        let report = start(&supervisor, &profiles[1]).await;
+        assert_eq!(outcomes(&report), [("sergw", ProfileOutcome::Failed)]);
+
+        let report = stop(&supervisor, &profiles[0], Duration::from_secs(1)).await;
+        assert_eq!(outcomes(&report), [("sergw", ProfileOutcome::Stopped)]);
+    }
+}
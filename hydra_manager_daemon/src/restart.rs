@@ -0,0 +1,284 @@
+use crate::supervisor::ExitInfo;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Restarts kept in `RestartStatus::history`.
+const MAX_HISTORY: usize = 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartMode {
+    Never,
+    /// Restart after a non-zero exit code or a signal that was not a stop request.
+    #[default]
+    OnFailure,
+    /// Restart after any exit that was not a stop request.
+    Always,
+}
+
+/// When and how fast a service is restarted after it exits by itself.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct RestartPolicy {
+    pub mode: RestartMode,
+    /// Delay before the first restart; doubled for each further restart in a row.
+    pub initial_backoff_secs: f64,
+    pub max_backoff_secs: f64,
+    /// Restarts in a row after which the service is left stopped; unlimited when absent.
+    pub max_retries: Option<u32>,
+    /// A run lasting this long resets the backoff and retry count.
+    pub stable_after_secs: u64,
+    /// This many exits within `crash_loop_window_secs` is a crash loop, and further restarts
+    /// wait the full `max_backoff_secs`.
+    pub crash_loop_exits: usize,
+    pub crash_loop_window_secs: i64,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            mode: RestartMode::OnFailure,
+            initial_backoff_secs: 1.0,
+            max_backoff_secs: 60.0,
+            max_retries: None,
+            stable_after_secs: 30,
+            crash_loop_exits: 5,
+            crash_loop_window_secs: 120,
+        }
+    }
+}
+
+/// An automatic restart.
+#[derive(Serialize, Debug, Clone)]
+pub struct RestartRecord {
+    /// Restart in the current streak, from 1.
+    pub attempt: u32,
+    /// The exit that caused it.
+    pub exit: ExitInfo,
+    pub delay_ms: u64,
+    pub crash_loop: bool,
+}
+
+/// What to do after a service exited by itself.
+#[derive(Debug, PartialEq)]
+pub enum RestartDecision {
+    Stay,
+    Restart(Duration),
+    GiveUp(String),
+}
+
+/// Restart bookkeeping of one service.
+#[derive(Default, Debug)]
+pub struct RestartTracker {
+    /// Restarts since the last stable run or manual start.
+    pub attempts: u32,
+    /// UNIX seconds of recent exits that were not stop requests.
+    recent_exits: VecDeque<i64>,
+    pub crash_loop: bool,
+    /// Why the policy stopped restarting, until the next manual start or stop.
+    pub gave_up: Option<String>,
+    pub history: VecDeque<RestartRecord>,
+}
+
+impl RestartTracker {
+    /// Forgets the current streak, as after a manual start or stop.
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+        self.recent_exits.clear();
+        self.crash_loop = false;
+        self.gave_up = None;
+    }
+
+    /// Decides on a restart after `exit`, for a run that lasted `ran_for_secs`.
+    pub fn on_exit(
+        &mut self,
+        policy: &RestartPolicy,
+        exit: &ExitInfo,
+        ran_for_secs: i64,
+    ) -> RestartDecision {
+        if exit.requested {
+            return RestartDecision::Stay;
+        }
+        let failed = exit.code != Some(0);
+        match policy.mode {
+            RestartMode::Never => return RestartDecision::Stay,
+            RestartMode::OnFailure if !failed => return RestartDecision::Stay,
+            RestartMode::OnFailure | RestartMode::Always => {}
+        }
+
+        if ran_for_secs >= policy.stable_after_secs as i64 {
+            self.reset();
+        }
+        self.recent_exits.push_back(exit.at);
+        while self
+            .recent_exits
+            .front()
+            .is_some_and(|&at| exit.at - at > policy.crash_loop_window_secs)
+        {
+            self.recent_exits.pop_front();
+        }
+        self.crash_loop = self.recent_exits.len() >= policy.crash_loop_exits.max(1);
+
+        if let Some(max_retries) = policy.max_retries {
+            if self.attempts >= max_retries {
+                let reason = format!("gave up after {} restart(s) in a row", self.attempts);
+                self.gave_up = Some(reason.clone());
+                return RestartDecision::GiveUp(reason);
+            }
+        }
+        self.attempts += 1;
+        let backoff = if self.crash_loop {
+            policy.max_backoff_secs
+        } else {
+            (policy.initial_backoff_secs * 2f64.powi(self.attempts as i32 - 1))
+                .min(policy.max_backoff_secs)
+        };
+        let delay = Duration::from_secs_f64(backoff.clamp(0.0, u32::MAX as f64));
+
+        if self.history.len() >= MAX_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(RestartRecord {
+            attempt: self.attempts,
+            exit: exit.clone(),
+            delay_ms: delay.as_millis() as u64,
+            crash_loop: self.crash_loop,
+        });
+        RestartDecision::Restart(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crash(at: i64) -> ExitInfo {
+        ExitInfo {
+            at,
+            code: Some(1),
+            signal: None,
+            requested: false,
+            error: None,
+        }
+    }
+
+    fn restart(secs: f64) -> RestartDecision {
+        RestartDecision::Restart(Duration::from_secs_f64(secs))
+    }
+
+    #[test]
+    fn doubles_the_backoff_up_to_the_maximum() {
+        let policy = RestartPolicy {
+            max_backoff_secs: 5.0,
+            crash_loop_exits: 100,
+            ..RestartPolicy::default()
+        };
+        let mut tracker = RestartTracker::default();
+        let delays: Vec<RestartDecision> = (0..5)
+            .map(|i| tracker.on_exit(&policy, &crash(i * 10), 1))
+            .collect();
+        assert_eq!(
+            delays,
+            [
+                restart(1.0),
+                restart(2.0),
+                restart(4.0),
+                restart(5.0),
+                restart(5.0)
+            ]
+        );
+        assert_eq!(tracker.attempts, 5);
+        assert_eq!(tracker.history.len(), 5);
+    }
+
+    #[test]
+    fn gives_up_after_max_retries() {
+        let policy = RestartPolicy {
+            max_retries: Some(2),
+            ..RestartPolicy::default()
+        };
+        let mut tracker = RestartTracker::default();
+        assert_eq!(tracker.on_exit(&policy, &crash(0), 1), restart(1.0));
+        assert_eq!(tracker.on_exit(&policy, &crash(1), 1), restart(2.0));
+        assert!(matches!(
+            tracker.on_exit(&policy, &crash(2), 1),
+            RestartDecision::GiveUp(_)
+        ));
+        assert!(tracker.gave_up.is_some());
+
+        tracker.reset();
+        assert!(tracker.gave_up.is_none());
+        assert_eq!(tracker.on_exit(&policy, &crash(3), 1), restart(1.0));
+    }
+
+    #[test]
+    fn a_stable_run_resets_the_streak() {
+        let policy = RestartPolicy {
+            max_retries: Some(2),
+            ..RestartPolicy::default()
+        };
+        let mut tracker = RestartTracker::default();
+        tracker.on_exit(&policy, &crash(0), 1);
+        tracker.on_exit(&policy, &crash(1), 1);
+        assert_eq!(
+            tracker.on_exit(&policy, &crash(100), policy.stable_after_secs as i64),
+            restart(1.0)
+        );
+        assert_eq!(tracker.attempts, 1);
+    }
+
+    #[test]
+    fn exits_within_the_window_are_a_crash_loop() {
+        let policy = RestartPolicy {
+            crash_loop_exits: 3,
+            crash_loop_window_secs: 60,
+            ..RestartPolicy::default()
+        };
+        let mut tracker = RestartTracker::default();
+        assert_eq!(tracker.on_exit(&policy, &crash(0), 1), restart(1.0));
+        assert_eq!(tracker.on_exit(&policy, &crash(30), 1), restart(2.0));
+        assert!(!tracker.crash_loop);
+        assert_eq!(tracker.on_exit(&policy, &crash(60), 1), restart(60.0));
+        assert!(tracker.crash_loop);
+        assert!(tracker.history.back().unwrap().crash_loop);
+
+        // The exits at 0 and 30 have left the window
+        assert_eq!(tracker.on_exit(&policy, &crash(100), 1), restart(8.0));
+        assert!(!tracker.crash_loop);
+        assert_eq!(tracker.on_exit(&policy, &crash(110), 1), restart(60.0));
+        assert!(tracker.crash_loop);
+    }
+
+    #[test]
+    fn follows_the_restart_mode() {
+        let mut tracker = RestartTracker::default();
+        let clean = ExitInfo {
+            code: Some(0),
+            ..crash(0)
+        };
+        let stopped = ExitInfo {
+            requested: true,
+            ..crash(0)
+        };
+        let on_failure = RestartPolicy::default();
+        assert_eq!(
+            tracker.on_exit(&on_failure, &clean, 1),
+            RestartDecision::Stay
+        );
+        assert_eq!(
+            tracker.on_exit(&on_failure, &stopped, 1),
+            RestartDecision::Stay
+        );
+        let always = RestartPolicy {
+            mode: RestartMode::Always,
+            ..RestartPolicy::default()
+        };
+        assert_eq!(tracker.on_exit(&always, &clean, 1), restart(1.0));
+        let never = RestartPolicy {
+            mode: RestartMode::Never,
+            ..RestartPolicy::default()
+        };
+        assert_eq!(tracker.on_exit(&never, &crash(1), 1), RestartDecision::Stay);
+    }
+}
@@ -0,0 +1,569 @@
+use crate::health::{HealthCheck, HealthCheckDefinition, Probe};
+use crate::profile::{self, ProfileDefinition};
+use crate::restart::RestartPolicy;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::process::Command;
+
+/// Placeholder for the directory of the ground station binaries, set by the daemon rather than
+/// by start requests, e.g. `program = "{bin_dir}/command-dispatcher"`.
+pub const BIN_DIR_PLACEHOLDER: &str = "bin_dir";
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ServicesFile {
+    /// Directory `{bin_dir}` stands for, relative to the file's directory. The daemon's
+    /// `--bin-dir` takes precedence; without either it is the daemon's own directory.
+    bin_dir: Option<String>,
+    #[serde(rename = "service", default)]
+    services: Vec<ServiceDefinition>,
+    #[serde(rename = "profile", default)]
+    profiles: Vec<ProfileDefinition>,
+}
+
+/// The contents of a definitions file.
+pub struct Definitions {
+    /// In file order.
+    pub services: Vec<ServiceDefinition>,
+    pub profiles: Vec<ProfileDefinition>,
+}
+
+/// One command line argument of a service definition.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ArgTemplate {
+    Arg(String),
+    /// Arguments passed only when every placeholder in them has a value, e.g.
+    /// `["--baud", "{baud_rate}"]`.
+    Optional(Vec<String>),
+}
+
+/// A `[[service]]` entry of the definitions file. `program`, `args`, `env` values,
+/// `working_dir` and the health check may contain `{name}` placeholders, filled from the start
+/// request or `defaults`; `{{` and `}}` are literal braces.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceDefinition {
+    /// Name used in the API paths, e.g. `/services/sergw/start`.
+    pub name: String,
+    /// Path of the executable. A relative path with a `/` is relative to the definitions
+    /// file's directory; a bare name is looked up on `PATH`.
+    pub program: String,
+    #[serde(default)]
+    pub args: Vec<ArgTemplate>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Relative to the definitions file's directory, which is also the default.
+    pub working_dir: Option<String>,
+    /// Services this one needs running and healthy first.
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    /// Placeholder values used when the start request does not give them.
+    #[serde(default)]
+    pub defaults: BTreeMap<String, serde_json::Value>,
+    pub health_check: Option<HealthCheckDefinition>,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+    /// Directory of the file the definition was loaded from.
+    #[serde(skip)]
+    base_dir: PathBuf,
+    /// Value of `{bin_dir}`.
+    #[serde(skip)]
+    bin_dir: PathBuf,
+}
+
+/// Body of a start or restart request: placeholder values of the service's definition, plus
+/// arguments to append and a restart policy to use instead of the definition's.
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct StartServicePayload {
+    /// Appended to the command line as given.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_args: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restart: Option<RestartPolicy>,
+    /// Placeholder values, e.g. `"serial_port": "/dev/ttyUSB0"`. `null` unsets a default.
+    #[serde(flatten)]
+    pub params: BTreeMap<String, serde_json::Value>,
+}
+
+/// A service definition filled in for one run.
+pub struct ResolvedRun {
+    pub command: Command,
+    pub health_check: Option<HealthCheck>,
+    pub restart: RestartPolicy,
+}
+
+/// Reads the `[[service]]` and `[[profile]]` definitions of a TOML file and checks them: unique
+/// names, known dependencies without cycles, well-formed placeholders, and profiles of known
+/// services. `bin_dir` overrides the file's `bin_dir`, and `default_bin_dir` is used when
+/// neither gives one.
+pub fn load(
+    path: &Path,
+    bin_dir: Option<&Path>,
+    default_bin_dir: &Path,
+) -> Result<Definitions, String> {
+    let text =
+        std::fs::read_to_string(path).map_err(|e| format!("reading {}: {}", path.display(), e))?;
+    let file: ServicesFile =
+        toml::from_str(&text).map_err(|e| format!("parsing {}: {}", path.display(), e))?;
+    // Absolute, as a relative program path would be ambiguous once `current_dir` is set
+    let base_dir = std::fs::canonicalize(path)
+        .map_err(|e| format!("resolving {}: {}", path.display(), e))?
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("/"));
+    let bin_dir = match (bin_dir, &file.bin_dir) {
+        (Some(dir), _) => dir.to_path_buf(),
+        (None, Some(dir)) => base_dir.join(dir),
+        (None, None) => default_bin_dir.to_path_buf(),
+    };
+
+    let mut definitions = file.services;
+    let mut names = HashSet::new();
+    for definition in &mut definitions {
+        definition.base_dir = base_dir.clone();
+        definition.bin_dir = bin_dir.clone();
+        definition
+            .validate()
+            .map_err(|e| format!("service '{}': {}", definition.name, e))?;
+        if !names.insert(definition.name.clone()) {
+            return Err(format!("service '{}' is defined twice", definition.name));
+        }
+    }
+    for definition in &definitions {
+        for dependency in &definition.depends_on {
+            if !names.contains(dependency) {
+                return Err(format!(
+                    "service '{}' depends on unknown service '{}'",
+                    definition.name, dependency
+                ));
+            }
+        }
+    }
+    if let Some(cycle) = find_cycle(&definitions) {
+        return Err(format!("dependency cycle: {}", cycle.join(" -> ")));
+    }
+    let mut profiles = file.profiles;
+    profile::resolve(&mut profiles, &definitions)?;
+    Ok(Definitions {
+        services: definitions,
+        profiles,
+    })
+}
+
+fn find_cycle(definitions: &[ServiceDefinition]) -> Option<Vec<String>> {
+    fn visit<'a>(
+        name: &'a str,
+        definitions: &'a [ServiceDefinition],
+        path: &mut Vec<&'a str>,
+        done: &mut HashSet<&'a str>,
+    ) -> Option<Vec<String>> {
+        if let Some(start) = path.iter().position(|n| *n == name) {
+            let mut cycle: Vec<String> = path[start..].iter().map(|n| n.to_string()).collect();
+            cycle.push(name.to_string());
+            return Some(cycle);
+        }
+        if done.contains(name) {
+            return None;
+        }
+        path.push(name);
+        let definition = definitions.iter().find(|d| d.name == name)?;
+        for dependency in &definition.depends_on {
+            if let Some(cycle) = visit(dependency, definitions, path, done) {
+                return Some(cycle);
+            }
+        }
+        path.pop();
+        done.insert(name);
+        None
+    }
+
+    let mut done = HashSet::new();
+    definitions
+        .iter()
+        .find_map(|d| visit(&d.name, definitions, &mut Vec::new(), &mut done))
+}
+
+impl ServiceDefinition {
+    fn validate(&self) -> Result<(), String> {
+        validate_name(&self.name)?;
+        if self.name == "reload" {
+            return Err("'reload' is reserved for POST /services/reload".to_string());
+        }
+        if self.program.is_empty() {
+            return Err("program is empty".to_string());
+        }
+        if let Some(check) = &self.health_check {
+            match (&check.tcp, &check.command) {
+                (Some(_), None) => {}
+                (None, Some(argv)) if !argv.is_empty() => {}
+                _ => return Err("health_check needs exactly one of tcp or command".to_string()),
+            }
+            if !(check.interval_secs > 0.0 && check.timeout_secs > 0.0) {
+                return Err("health_check intervals must be positive".to_string());
+            }
+        }
+        if self.defaults.contains_key(BIN_DIR_PLACEHOLDER) {
+            return Err(format!(
+                "'{}' is set by the daemon and cannot have a default",
+                BIN_DIR_PLACEHOLDER
+            ));
+        }
+        for value in self.defaults.values() {
+            param_value(value)?;
+        }
+        for template in self.templates() {
+            placeholders(template)?;
+        }
+        Ok(())
+    }
+
+    fn templates(&self) -> impl Iterator<Item = &String> {
+        let args = self.args.iter().flat_map(|arg| match arg {
+            ArgTemplate::Arg(arg) => std::slice::from_ref(arg),
+            ArgTemplate::Optional(args) => args.as_slice(),
+        });
+        let health = self
+            .health_check
+            .iter()
+            .flat_map(|check| check.tcp.iter().chain(check.command.iter().flatten()));
+        std::iter::once(&self.program)
+            .chain(args)
+            .chain(self.env.values())
+            .chain(self.working_dir.iter())
+            .chain(health)
+    }
+
+    /// Fills in the definition with the placeholder values of `payload`, falling back to
+    /// `defaults`. A value the definition has no placeholder for is an error, so typos do not
+    /// go unnoticed.
+    pub fn resolve(&self, payload: &StartServicePayload) -> Result<ResolvedRun, String> {
+        let mut known = HashSet::new();
+        for template in self.templates() {
+            known.extend(placeholders(template)?);
+        }
+        let mut params = BTreeMap::new();
+        for (name, value) in &self.defaults {
+            if let Some(value) = param_value(value)? {
+                params.insert(name.clone(), value);
+            }
+        }
+        for (name, value) in &payload.params {
+            if !known.contains(name) || name == BIN_DIR_PLACEHOLDER {
+                return Err(format!("{} takes no parameter '{}'", self.name, name));
+            }
+            match param_value(value).map_err(|e| format!("parameter '{}': {}", name, e))? {
+                Some(value) => params.insert(name.clone(), value),
+                None => params.remove(name),
+            };
+        }
+        params.insert(
+            BIN_DIR_PLACEHOLDER.to_string(),
+            self.bin_dir.display().to_string(),
+        );
+        let fill = |template: &str| render(template, &params);
+
+        let working_dir = match &self.working_dir {
+            Some(dir) => self.base_dir.join(fill(dir)?),
+            None => self.base_dir.clone(),
+        };
+        let program = fill(&self.program)?;
+        let program = if program.contains('/') {
+            self.base_dir.join(program)
+        } else {
+            PathBuf::from(program)
+        };
+        let mut command = Command::new(program);
+        for arg in &self.args {
+            match arg {
+                ArgTemplate::Arg(arg) => {
+                    command.arg(fill(arg)?);
+                }
+                ArgTemplate::Optional(args) => {
+                    if let Ok(args) = args.iter().map(|a| fill(a)).collect::<Result<Vec<_>, _>>() {
+                        command.args(args);
+                    }
+                }
+            }
+        }
+        command.args(&payload.extra_args);
+        for (key, value) in &self.env {
+            command.env(key, fill(value)?);
+        }
+        command.current_dir(&working_dir);
+
+        let health_check = match &self.health_check {
+            Some(check) => Some(HealthCheck {
+                probe: match (&check.tcp, &check.command) {
+                    (Some(address), _) => Probe::Tcp(fill(address)?),
+                    (None, Some(argv)) => {
+                        Probe::Command(argv.iter().map(|a| fill(a)).collect::<Result<_, _>>()?)
+                    }
+                    (None, None) => unreachable!("checked by validate"),
+                },
+                interval: Duration::from_secs_f64(check.interval_secs),
+                timeout: Duration::from_secs_f64(check.timeout_secs),
+                working_dir,
+            }),
+            None => None,
+        };
+        Ok(ResolvedRun {
+            command,
+            health_check,
+            restart: payload
+                .restart
+                .clone()
+                .unwrap_or_else(|| self.restart.clone()),
+        })
+    }
+}
+
+/// Names of services and profiles appear in API paths.
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err("names may only use letters, digits, '-' and '_'".to_string());
+    }
+    Ok(())
+}
+
+// A placeholder value as text; `None` for null
+fn param_value(value: &serde_json::Value) -> Result<Option<String>, String> {
+    match value {
+        serde_json::Value::Null => Ok(None),
+        serde_json::Value::String(s) => Ok(Some(s.clone())),
+        serde_json::Value::Number(n) => Ok(Some(n.to_string())),
+        serde_json::Value::Bool(b) => Ok(Some(b.to_string())),
+        _ => Err("values must be strings, numbers or booleans".to_string()),
+    }
+}
+
+enum Segment {
+    Text(String),
+    Placeholder(String),
+}
+
+fn segments(template: &str) -> Result<Vec<Segment>, String> {
+    let mut segments = Vec::new();
+    let mut text = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            }
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) if c.is_ascii_alphanumeric() || c == '_' || c == '-' => {
+                            name.push(c)
+                        }
+                        _ => return Err(format!("bad placeholder in \"{}\"", template)),
+                    }
+                }
+                if name.is_empty() {
+                    return Err(format!("empty placeholder in \"{}\"", template));
+                }
+                segments.push(Segment::Text(std::mem::take(&mut text)));
+                segments.push(Segment::Placeholder(name));
+            }
+            '}' => return Err(format!("unmatched '}}' in \"{}\"", template)),
+            c => text.push(c),
+        }
+    }
+    segments.push(Segment::Text(text));
+    Ok(segments)
+}
+
+fn placeholders(template: &str) -> Result<Vec<String>, String> {
+    Ok(segments(template)?
+        .into_iter()
+        .filter_map(|segment| match segment {
+            Segment::Placeholder(name) => Some(name),
+            Segment::Text(_) => None,
+        })
+        .collect())
+}
+
+fn render(template: &str, params: &BTreeMap<String, String>) -> Result<String, String> {
+    let mut rendered = String::new();
+    for segment in segments(template)? {
+        match segment {
+            Segment::Text(text) => rendered.push_str(&text),
+            Segment::Placeholder(name) => rendered.push_str(
+                params
+                    .get(&name)
+                    .ok_or_else(|| format!("missing parameter '{}'", name))?,
+            ),
+        }
+    }
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn definition(value: serde_json::Value) -> ServiceDefinition {
+        let mut definition: ServiceDefinition = serde_json::from_value(value).unwrap();
+        definition.base_dir = PathBuf::from("/srv/rgs");
+        definition.bin_dir = PathBuf::from("/opt/rgs/bin");
+        definition
+    }
+
+    fn sergw() -> ServiceDefinition {
+        definition(json!({
+            "name": "sergw",
+            "program": "{bin_dir}/sergw",
+            "args": ["--serial", "{serial_port}", ["--baud", "{baud_rate}"], "--host", "{host}"],
+            "defaults": { "host": "127.0.0.1:5656" }
+        }))
+    }
+
+    fn payload(params: serde_json::Value) -> StartServicePayload {
+        serde_json::from_value(params).unwrap()
+    }
+
+    fn command_line(run: &ResolvedRun) -> Vec<String> {
+        let command = run.command.as_std();
+        std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn renders_placeholders_and_escaped_braces() {
+        let params = BTreeMap::from([("port".to_string(), "5656".to_string())]);
+        assert_eq!(
+            render("{{\"port\": {port}}}", &params).unwrap(),
+            "{\"port\": 5656}"
+        );
+        assert_eq!(placeholders("{a}-{b}{{c}}").unwrap(), ["a", "b"]);
+        assert!(render("{host}", &params)
+            .unwrap_err()
+            .contains("missing parameter 'host'"));
+        for bad in ["{}", "{port", "port}", "{po rt}"] {
+            assert!(segments(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn drops_optional_arguments_without_a_value() {
+        let service = sergw();
+        let run = service
+            .resolve(&payload(json!({ "serial_port": "/dev/ttyUSB0" })))
+            .unwrap();
+        assert_eq!(
+            command_line(&run),
+            [
+                "/opt/rgs/bin/sergw",
+                "--serial",
+                "/dev/ttyUSB0",
+                "--host",
+                "127.0.0.1:5656"
+            ]
+        );
+
+        let run = service
+            .resolve(&payload(json!({
+                "serial_port": "/dev/ttyUSB0",
+                "baud_rate": 57600,
+                "host": "0.0.0.0:5656",
+                "extra_args": ["-v"]
+            })))
+            .unwrap();
+        assert_eq!(
+            command_line(&run),
+            [
+                "/opt/rgs/bin/sergw",
+                "--serial",
+                "/dev/ttyUSB0",
+                "--baud",
+                "57600",
+                "--host",
+                "0.0.0.0:5656",
+                "-v"
+            ]
+        );
+        assert_eq!(
+            run.command.as_std().get_current_dir(),
+            Some(Path::new("/srv/rgs"))
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_and_missing_parameters() {
+        let service = sergw();
+        assert_eq!(
+            service
+                .resolve(&payload(json!({ "serial_port": "x", "serial": "y" })))
+                .err()
+                .unwrap(),
+            "sergw takes no parameter 'serial'"
+        );
+        assert!(service
+            .resolve(&payload(json!({})))
+            .err()
+            .unwrap()
+            .contains("missing parameter 'serial_port'"));
+        // null unsets a default
+        assert!(service
+            .resolve(&payload(json!({ "serial_port": "x", "host": null })))
+            .err()
+            .unwrap()
+            .contains("missing parameter 'host'"));
+    }
+
+    #[test]
+    fn bin_dir_is_set_by_the_daemon_only() {
+        assert_eq!(
+            sergw()
+                .resolve(&payload(json!({ "serial_port": "x", "bin_dir": "/tmp" })))
+                .err()
+                .unwrap(),
+            "sergw takes no parameter 'bin_dir'"
+        );
+        let with_default = definition(json!({
+            "name": "sergw",
+            "program": "{bin_dir}/sergw",
+            "defaults": { "bin_dir": "/tmp" }
+        }));
+        assert!(with_default.validate().is_err());
+    }
+
+    #[test]
+    fn reports_dependency_cycles() {
+        let service = |name: &str, depends_on: &[&str]| {
+            definition(json!({ "name": name, "program": "x", "depends_on": depends_on }))
+        };
+        let acyclic = [
+            service("a", &["b", "c"]),
+            service("b", &["c"]),
+            service("c", &[]),
+        ];
+        assert_eq!(find_cycle(&acyclic), None);
+
+        let cyclic = [
+            service("a", &["b"]),
+            service("b", &["c"]),
+            service("c", &["a"]),
+            service("d", &[]),
+        ];
+        assert_eq!(find_cycle(&cyclic).unwrap(), ["a", "b", "c", "a"]);
+        assert_eq!(find_cycle(&[service("a", &["a"])]).unwrap(), ["a", "a"]);
+    }
+}
@@ -0,0 +1,777 @@
+use crate::health::{HealthCheck, HealthStatus};
+use crate::output::{OutputBuffer, OutputStream};
+use crate::profile::ProfileDefinition;
+use crate::restart::{RestartDecision, RestartPolicy, RestartRecord, RestartTracker};
+use crate::service::{self, ServiceDefinition, StartServicePayload};
+use crate::DaemonLog;
+use chrono::Utc;
+use serde::Serialize;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::process::Child;
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinHandle;
+use tracing::{error, warn};
+
+/// How long a stopped service gets to exit after SIGTERM before it is sent SIGKILL.
+pub const DEFAULT_STOP_GRACE_PERIOD: Duration = Duration::from_secs(10);
+/// How often a process group left behind by an exited service is checked for survivors.
+const GROUP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// A running child process. Its supervision task owns the `Child`.
+struct RunningProcess {
+    pid: u32,
+    /// Asks the supervision task to stop the process with this grace period. Taken once a
+    /// stop has been requested.
+    stop_tx: Option<oneshot::Sender<Duration>>,
+    /// Becomes true once the process has exited and the state records it.
+    exited: watch::Receiver<bool>,
+}
+
+// An automatic restart waiting out its backoff
+struct PendingRestart {
+    /// UNIX milliseconds it is due.
+    at_ms: i64,
+    handle: JoinHandle<()>,
+}
+
+struct ServiceState {
+    /// Definition the next run is started from; replaced on reload.
+    definition: Arc<ServiceDefinition>,
+    /// Counts starts, so a finished run never clears the state of a newer one.
+    run: u64,
+    process: Option<RunningProcess>,
+    /// Command line of the current or last run.
+    command_line: Option<String>,
+    started_at: Option<i64>,
+    /// Payload of the last start, reused by a restart without a body.
+    last_payload: StartServicePayload,
+    /// Restart policy of the current or last run.
+    policy: RestartPolicy,
+    last_exit: Option<ExitInfo>,
+    restarts: RestartTracker,
+    pending_restart: Option<PendingRestart>,
+    /// Latest health check of the current run.
+    health: Option<HealthStatus>,
+}
+
+impl ServiceState {
+    fn cancel_pending_restart(&mut self) -> bool {
+        match self.pending_restart.take() {
+            Some(pending) => {
+                pending.handle.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Running, or about to be restarted
+    fn is_active(&self) -> bool {
+        self.process.is_some() || self.pending_restart.is_some()
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RunState {
+    Running,
+    /// Sent SIGTERM and not yet exited.
+    Stopping,
+    /// Exited and waiting out the backoff before an automatic restart.
+    Backoff,
+    /// Exited and left stopped because the restart policy gave up.
+    Failed,
+    Stopped,
+}
+
+/// How a run of a service ended.
+#[derive(Serialize, Debug, Clone)]
+pub struct ExitInfo {
+    /// UNIX seconds the process exited.
+    pub at: i64,
+    /// Exit code, when the process exited by itself.
+    pub code: Option<i32>,
+    /// Signal that terminated the process, e.g. 15 for SIGTERM or 9 for SIGKILL.
+    pub signal: Option<i32>,
+    /// The process exited because a stop was requested.
+    pub requested: bool,
+    /// Waiting on the process failed; code and signal are unknown.
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ServiceStatus {
+    pub service: String,
+    pub state: RunState,
+    /// Start count of the current or last run; output lines carry the run they came from.
+    pub run: u64,
+    pub pid: Option<u32>,
+    pub command_line: Option<String>,
+    /// UNIX seconds the current run started, when running.
+    pub started_at: Option<i64>,
+    pub depends_on: Vec<String>,
+    /// Latest health check of the current run; absent without a health check or before the
+    /// first one.
+    pub health: Option<HealthStatus>,
+    pub last_exit: Option<ExitInfo>,
+    pub restart: RestartStatus,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RestartStatus {
+    pub policy: RestartPolicy,
+    /// Automatic restarts since the last stable run or manual start.
+    pub attempts: u32,
+    pub crash_loop: bool,
+    /// UNIX milliseconds the pending automatic restart is due.
+    pub next_restart_at_ms: Option<i64>,
+    /// Why the policy stopped restarting the service.
+    pub gave_up: Option<String>,
+    /// The latest automatic restarts, oldest first.
+    pub history: Vec<RestartRecord>,
+}
+
+#[derive(Debug)]
+pub enum SupervisorError {
+    UnknownService(String),
+    UnknownProfile(String),
+    AlreadyRunning(Box<ServiceStatus>),
+    NotRunning(String),
+    InvalidPayload(String),
+    Spawn(String, std::io::Error),
+    /// The definitions file could not be loaded or applied.
+    Definitions(String),
+}
+
+/// One defined service, with its state and output buffer.
+pub struct ManagedService {
+    name: String,
+    state: Mutex<ServiceState>,
+    output: OutputBuffer,
+    log: DaemonLog,
+}
+
+/// Runs each service of the definitions file at most once at a time as a child process.
+pub struct Supervisor {
+    /// In definitions file order.
+    services: RwLock<Vec<Arc<ManagedService>>>,
+    profiles: RwLock<Vec<Arc<ProfileDefinition>>>,
+    definitions_path: PathBuf,
+    /// `--bin-dir`, if given.
+    bin_dir: Option<PathBuf>,
+    /// Directory of the daemon's executable, for `{bin_dir}` when nothing else sets it.
+    exe_dir: PathBuf,
+    log: DaemonLog,
+}
+
+impl Supervisor {
+    /// Loads the services and profiles of the definitions file at `definitions_path`.
+    pub fn load(
+        definitions_path: PathBuf,
+        bin_dir: Option<PathBuf>,
+        log: DaemonLog,
+    ) -> Result<Self, String> {
+        let exe_dir = std::env::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().map(Path::to_path_buf))
+            .ok_or("cannot find the daemon's own directory")?;
+        let definitions = service::load(&definitions_path, bin_dir.as_deref(), &exe_dir)?;
+        log.add(format!(
+            "Loaded {} service definition(s) and {} profile(s) from {}",
+            definitions.services.len(),
+            definitions.profiles.len(),
+            definitions_path.display()
+        ));
+        Ok(Self {
+            services: RwLock::new(
+                definitions
+                    .services
+                    .into_iter()
+                    .map(|definition| Arc::new(ManagedService::new(definition, log.clone())))
+                    .collect(),
+            ),
+            profiles: RwLock::new(definitions.profiles.into_iter().map(Arc::new).collect()),
+            definitions_path,
+            bin_dir,
+            exe_dir,
+            log,
+        })
+    }
+
+    /// A supervisor of `definitions` that has no definitions file to reload.
+    #[cfg(test)]
+    pub fn from_definitions(definitions: Vec<ServiceDefinition>) -> Self {
+        let log = DaemonLog::new();
+        Self {
+            services: RwLock::new(
+                definitions
+                    .into_iter()
+                    .map(|definition| Arc::new(ManagedService::new(definition, log.clone())))
+                    .collect(),
+            ),
+            profiles: RwLock::new(Vec::new()),
+            definitions_path: PathBuf::new(),
+            bin_dir: None,
+            exe_dir: PathBuf::new(),
+            log,
+        }
+    }
+
+    /// Reads the definitions file again. New services are added, changed ones take effect on
+    /// their next start, and removed ones are dropped; nothing changes if a removed service is
+    /// still running or the file is invalid. Profiles are replaced.
+    pub fn reload(&self) -> Result<Vec<ServiceStatus>, SupervisorError> {
+        let service::Definitions {
+            services: definitions,
+            profiles,
+        } = service::load(
+            &self.definitions_path,
+            self.bin_dir.as_deref(),
+            &self.exe_dir,
+        )
+        .map_err(SupervisorError::Definitions)?;
+        let mut services = self.services.write().unwrap();
+        for service in services.iter() {
+            if !definitions.iter().any(|d| d.name == service.name)
+                && service.state.lock().unwrap().is_active()
+            {
+                return Err(SupervisorError::Definitions(format!(
+                    "{} is running but no longer defined; stop it before reloading",
+                    service.name
+                )));
+            }
+        }
+        let mut reloaded = Vec::with_capacity(definitions.len());
+        for definition in definitions {
+            match services.iter().find(|s| s.name == definition.name) {
+                Some(service) => {
+                    service.state.lock().unwrap().definition = Arc::new(definition);
+                    reloaded.push(service.clone());
+                }
+                None => reloaded.push(Arc::new(ManagedService::new(definition, self.log.clone()))),
+            }
+        }
+        *services = reloaded;
+        *self.profiles.write().unwrap() = profiles.into_iter().map(Arc::new).collect();
+        self.log.add(format!(
+            "Reloaded {} service definition(s) and {} profile(s) from {}",
+            services.len(),
+            self.profiles.read().unwrap().len(),
+            self.definitions_path.display()
+        ));
+        Ok(services.iter().map(|s| s.status()).collect())
+    }
+
+    pub fn service(&self, name: &str) -> Result<Arc<ManagedService>, SupervisorError> {
+        self.services
+            .read()
+            .unwrap()
+            .iter()
+            .find(|s| s.name == name)
+            .cloned()
+            .ok_or_else(|| SupervisorError::UnknownService(name.to_string()))
+    }
+
+    pub fn list(&self) -> Vec<ServiceStatus> {
+        self.services
+            .read()
+            .unwrap()
+            .iter()
+            .map(|s| s.status())
+            .collect()
+    }
+
+    /// Stops every service, each after the services that depend on it, for the daemon's
+    /// shutdown. Pending restarts are cancelled.
+    pub async fn stop_all(&self, grace: Duration) {
+        fn visit(
+            service: &Arc<ManagedService>,
+            services: &[Arc<ManagedService>],
+            order: &mut Vec<Arc<ManagedService>>,
+        ) {
+            if order.iter().any(|s| s.name == service.name) {
+                return;
+            }
+            for dependency in service.depends_on() {
+                if let Some(dependency) = services.iter().find(|s| s.name == dependency) {
+                    visit(dependency, services, order);
+                }
+            }
+            order.push(service.clone());
+        }
+
+        let services = self.services.read().unwrap().clone();
+        let mut order = Vec::with_capacity(services.len());
+        for service in &services {
+            visit(service, &services, &mut order);
+        }
+        for service in order.iter().rev() {
+            match service.stop(grace).await {
+                Ok(_) | Err(SupervisorError::NotRunning(_)) => {}
+                Err(e) => error!("Failed to stop {} on shutdown: {:?}", service.name, e),
+            }
+        }
+    }
+
+    pub fn profile(&self, name: &str) -> Result<Arc<ProfileDefinition>, SupervisorError> {
+        self.profiles
+            .read()
+            .unwrap()
+            .iter()
+            .find(|p| p.name == name)
+            .cloned()
+            .ok_or_else(|| SupervisorError::UnknownProfile(name.to_string()))
+    }
+
+    pub fn profiles(&self) -> Vec<Arc<ProfileDefinition>> {
+        self.profiles.read().unwrap().clone()
+    }
+
+    pub fn log(&self) -> &DaemonLog {
+        &self.log
+    }
+}
+
+impl ManagedService {
+    fn new(definition: ServiceDefinition, log: DaemonLog) -> Self {
+        let state = ServiceState {
+            policy: definition.restart.clone(),
+            definition: Arc::new(definition),
+            run: 0,
+            process: None,
+            command_line: None,
+            started_at: None,
+            last_payload: StartServicePayload::default(),
+            last_exit: None,
+            restarts: RestartTracker::default(),
+            pending_restart: None,
+            health: None,
+        };
+        Self {
+            name: state.definition.name.clone(),
+            state: Mutex::new(state),
+            output: OutputBuffer::new(),
+            log,
+        }
+    }
+
+    pub fn output(&self) -> &OutputBuffer {
+        &self.output
+    }
+
+    pub fn status(&self) -> ServiceStatus {
+        let state = self.state.lock().unwrap();
+        self.status_of(&state)
+    }
+
+    pub fn depends_on(&self) -> Vec<String> {
+        self.state.lock().unwrap().definition.depends_on.clone()
+    }
+
+    /// The payload of the current or last run.
+    pub fn last_payload(&self) -> StartServicePayload {
+        self.state.lock().unwrap().last_payload.clone()
+    }
+
+    /// Whether the service is only ready once its health check passes.
+    pub fn has_health_check(&self) -> bool {
+        self.state.lock().unwrap().definition.health_check.is_some()
+    }
+
+    /// Starts the service with `payload`, or with the payload of its last start when `None`. A
+    /// pending automatic restart is replaced and the restart count starts over.
+    pub fn start(
+        self: &Arc<Self>,
+        payload: Option<StartServicePayload>,
+    ) -> Result<ServiceStatus, SupervisorError> {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        if state.process.is_some() {
+            self.log.add(format!(
+                "Attempted to start {} while it is already running.",
+                self.name
+            ));
+            return Err(SupervisorError::AlreadyRunning(Box::new(
+                self.status_of(state),
+            )));
+        }
+        let payload = payload.unwrap_or_else(|| state.last_payload.clone());
+        state.cancel_pending_restart();
+        state.restarts.reset();
+        self.spawn_run(state, payload)?;
+        Ok(self.status_of(state))
+    }
+
+    fn spawn_run(
+        self: &Arc<Self>,
+        state: &mut ServiceState,
+        payload: StartServicePayload,
+    ) -> Result<(), SupervisorError> {
+        let resolved = state
+            .definition
+            .resolve(&payload)
+            .map_err(SupervisorError::InvalidPayload)?;
+        let mut cmd = resolved.command;
+        let command_line = format!("{:?}", cmd.as_std());
+        self.log.add(format!(
+            "Attempting to start {} with command: {}",
+            self.name, command_line
+        ));
+
+        cmd.stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // Its own process group, so stopping it reaches the processes it starts, and a
+            // terminal's ctrl-c reaches only the daemon, which stops it in order
+            .process_group(0)
+            .kill_on_drop(true);
+
+        let mut child = cmd.spawn().map_err(|e| {
+            self.log
+                .add(format!("Failed to start service {}: {}", self.name, e));
+            error!("Failed to start service {}: {}", self.name, e);
+            SupervisorError::Spawn(self.name.clone(), e)
+        })?;
+        let pid = child.id().unwrap_or_default();
+        self.log
+            .add(format!("Service {} started with PID {}.", self.name, pid));
+
+        state.run += 1;
+        let run = state.run;
+        if let Some(stdout) = child.stdout.take() {
+            let service = self.clone();
+            tokio::spawn(async move {
+                service
+                    .output
+                    .capture(run, OutputStream::Stdout, stdout)
+                    .await
+            });
+        }
+        if let Some(stderr) = child.stderr.take() {
+            let service = self.clone();
+            tokio::spawn(async move {
+                service
+                    .output
+                    .capture(run, OutputStream::Stderr, stderr)
+                    .await
+            });
+        }
+        if let Some(health_check) = resolved.health_check {
+            tokio::spawn(self.clone().watch_health(run, health_check));
+        }
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let (exited_tx, exited_rx) = watch::channel(false);
+        tokio::spawn(self.clone().supervise(run, child, stop_rx, exited_tx));
+
+        state.process = Some(RunningProcess {
+            pid,
+            stop_tx: Some(stop_tx),
+            exited: exited_rx,
+        });
+        state.command_line = Some(command_line);
+        state.started_at = Some(Utc::now().timestamp());
+        state.last_payload = payload;
+        state.policy = resolved.restart;
+        state.health = None;
+        Ok(())
+    }
+
+    /// Sends the process SIGTERM, then SIGKILL if it has not exited after `grace`, and returns
+    /// once it has exited. A second stop while one is pending waits for the first. A service
+    /// waiting to be restarted automatically just has the restart cancelled.
+    pub async fn stop(&self, grace: Duration) -> Result<ServiceStatus, SupervisorError> {
+        let mut exited = {
+            let mut guard = self.state.lock().unwrap();
+            let state = &mut *guard;
+            state.restarts.reset();
+            let Some(process) = state.process.as_mut() else {
+                if state.cancel_pending_restart() {
+                    self.log.add(format!(
+                        "Cancelled the pending restart of service {}.",
+                        self.name
+                    ));
+                    return Ok(self.status_of(state));
+                }
+                return Err(SupervisorError::NotRunning(self.name.clone()));
+            };
+            if let Some(stop_tx) = process.stop_tx.take() {
+                self.log.add(format!(
+                    "Sending SIGTERM to service {} (PID {}).",
+                    self.name, process.pid
+                ));
+                let _ = stop_tx.send(grace);
+            }
+            process.exited.clone()
+        };
+        // An error means the supervision task is gone, which also only happens after exit
+        let _ = exited.wait_for(|exited| *exited).await;
+        Ok(self.status())
+    }
+
+    /// Stops the service if it is running and starts it again.
+    pub async fn restart(
+        self: &Arc<Self>,
+        payload: Option<StartServicePayload>,
+        grace: Duration,
+    ) -> Result<ServiceStatus, SupervisorError> {
+        match self.stop(grace).await {
+            Ok(_) | Err(SupervisorError::NotRunning(_)) => {}
+            Err(e) => return Err(e),
+        }
+        self.start(payload)
+    }
+
+    // Waits for the child to exit, or terminates it when asked to, and records how it ended.
+    async fn supervise(
+        self: Arc<Self>,
+        run: u64,
+        mut child: Child,
+        stop_rx: oneshot::Receiver<Duration>,
+        exited_tx: watch::Sender<bool>,
+    ) {
+        let group = child.id();
+        let (result, requested) = tokio::select! {
+            result = child.wait() => (result, false),
+            Ok(grace) = stop_rx => (self.terminate(&mut child, grace).await, true),
+        };
+        if let (Some(pgid), false) = (group, requested) {
+            // The leader is gone but anything it started is not, and would hold on to ports
+            // and devices the restarted service needs
+            self.stop_group(pgid, DEFAULT_STOP_GRACE_PERIOD).await;
+        }
+        let exit = exit_info(result, requested);
+        self.log.add(format!(
+            "Service {} exited: {}.",
+            self.name,
+            describe_exit(&exit)
+        ));
+
+        {
+            let mut guard = self.state.lock().unwrap();
+            let state = &mut *guard;
+            if state.run == run {
+                let ran_for_secs = exit.at - state.started_at.unwrap_or(exit.at);
+                state.process = None;
+                state.started_at = None;
+                state.health = None;
+                state.last_exit = Some(exit.clone());
+                self.after_exit(state, &exit, ran_for_secs);
+            }
+        }
+        let _ = exited_tx.send(true);
+    }
+
+    // Runs the health check every interval for as long as run `run` is running
+    async fn watch_health(self: Arc<Self>, run: u64, health_check: HealthCheck) {
+        loop {
+            tokio::time::sleep(health_check.interval).await;
+            let result = health_check.check().await;
+            let mut state = self.state.lock().unwrap();
+            if state.run != run || state.process.is_none() {
+                return;
+            }
+            let was_healthy = state.health.as_ref().is_some_and(|h| h.healthy);
+            if result.is_ok() != was_healthy {
+                self.log.add(match &result {
+                    Ok(()) => format!("Service {} is healthy.", self.name),
+                    Err(e) => format!("Service {} is unhealthy: {}.", self.name, e),
+                });
+            }
+            state.health = Some(HealthStatus {
+                healthy: result.is_ok(),
+                checked_at_ms: Utc::now().timestamp_millis(),
+                error: result.err(),
+            });
+        }
+    }
+
+    // Applies the restart policy to an exit, scheduling the restart if there is one
+    fn after_exit(self: &Arc<Self>, state: &mut ServiceState, exit: &ExitInfo, ran_for_secs: i64) {
+        match state.restarts.on_exit(&state.policy, exit, ran_for_secs) {
+            RestartDecision::Stay => {}
+            RestartDecision::Restart(delay) => {
+                self.log.add(format!(
+                    "Restarting service {} in {:?} (restart {}{}).",
+                    self.name,
+                    delay,
+                    state.restarts.attempts,
+                    if state.restarts.crash_loop {
+                        ", crash loop"
+                    } else {
+                        ""
+                    }
+                ));
+                let service = self.clone();
+                let run = state.run;
+                let handle = tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    service.restart_now(run);
+                });
+                state.pending_restart = Some(PendingRestart {
+                    at_ms: Utc::now().timestamp_millis() + delay.as_millis() as i64,
+                    handle,
+                });
+            }
+            RestartDecision::GiveUp(reason) => {
+                warn!("Not restarting {}: {}", self.name, reason);
+                self.log
+                    .add(format!("Not restarting service {}: {}.", self.name, reason));
+            }
+        }
+    }
+
+    // The due automatic restart, unless a manual start or stop got there first
+    fn restart_now(self: &Arc<Self>, run: u64) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        if state.run != run || state.process.is_some() || state.pending_restart.take().is_none() {
+            return;
+        }
+        let payload = state.last_payload.clone();
+        if let Err(e) = self.spawn_run(state, payload) {
+            let exit = ExitInfo {
+                at: Utc::now().timestamp(),
+                code: None,
+                signal: None,
+                requested: false,
+                error: Some(match e {
+                    SupervisorError::InvalidPayload(message) => message,
+                    SupervisorError::Spawn(_, e) => format!("failed to start: {}", e),
+                    other => format!("{:?}", other),
+                }),
+            };
+            state.last_exit = Some(exit.clone());
+            self.after_exit(state, &exit, 0);
+        }
+    }
+
+    async fn terminate(&self, child: &mut Child, grace: Duration) -> std::io::Result<ExitStatus> {
+        // The child leads its process group and is only reaped by `wait` below, so the group
+        // cannot have been reused yet
+        let group = child.id();
+        if let Some(pgid) = group {
+            self.signal_group(pgid, libc::SIGTERM);
+        }
+        match tokio::time::timeout(grace, child.wait()).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.log.add(format!(
+                    "Service {} did not exit within {:?} of SIGTERM; sending SIGKILL.",
+                    self.name, grace
+                ));
+                match group {
+                    Some(pgid) => self.signal_group(pgid, libc::SIGKILL),
+                    None => child.start_kill()?,
+                }
+                child.wait().await
+            }
+        }
+    }
+
+    // Sends what is left of a process group whose leader has exited SIGTERM, then SIGKILL if
+    // it is still there after `grace`
+    async fn stop_group(&self, pgid: u32, grace: Duration) {
+        if !group_exists(pgid) {
+            return;
+        }
+        self.log.add(format!(
+            "Service {} left processes running in its group; sending SIGTERM.",
+            self.name
+        ));
+        self.signal_group(pgid, libc::SIGTERM);
+        let deadline = tokio::time::Instant::now() + grace;
+        while group_exists(pgid) {
+            if tokio::time::Instant::now() >= deadline {
+                self.log.add(format!(
+                    "Processes of service {} did not exit within {:?} of SIGTERM; sending SIGKILL.",
+                    self.name, grace
+                ));
+                self.signal_group(pgid, libc::SIGKILL);
+                return;
+            }
+            tokio::time::sleep(GROUP_POLL_INTERVAL).await;
+        }
+    }
+
+    fn signal_group(&self, pgid: u32, signal: libc::c_int) {
+        // SAFETY: kill(2) has no memory-safety preconditions.
+        if unsafe { libc::kill(-(pgid as libc::pid_t), signal) } != 0 {
+            warn!(
+                "Failed to send signal {} to {} (process group {}): {}",
+                signal,
+                self.name,
+                pgid,
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+
+    fn status_of(&self, state: &ServiceState) -> ServiceStatus {
+        let run_state = match &state.process {
+            Some(process) if process.stop_tx.is_none() => RunState::Stopping,
+            Some(_) => RunState::Running,
+            None if state.pending_restart.is_some() => RunState::Backoff,
+            None if state.restarts.gave_up.is_some() => RunState::Failed,
+            None => RunState::Stopped,
+        };
+        ServiceStatus {
+            service: self.name.clone(),
+            state: run_state,
+            run: state.run,
+            pid: state.process.as_ref().map(|p| p.pid),
+            command_line: state.command_line.clone(),
+            started_at: state.started_at,
+            depends_on: state.definition.depends_on.clone(),
+            health: state.health.clone(),
+            last_exit: state.last_exit.clone(),
+            restart: RestartStatus {
+                policy: state.policy.clone(),
+                attempts: state.restarts.attempts,
+                crash_loop: state.restarts.crash_loop,
+                next_restart_at_ms: state.pending_restart.as_ref().map(|p| p.at_ms),
+                gave_up: state.restarts.gave_up.clone(),
+                history: state.restarts.history.iter().cloned().collect(),
+            },
+        }
+    }
+}
+
+fn exit_info(result: std::io::Result<ExitStatus>, requested: bool) -> ExitInfo {
+    let at = Utc::now().timestamp();
+    match result {
+        Ok(status) => ExitInfo {
+            at,
+            code: status.code(),
+            signal: status.signal(),
+            requested,
+            error: None,
+        },
+        Err(e) => ExitInfo {
+            at,
+            code: None,
+            signal: None,
+            requested,
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+fn describe_exit(exit: &ExitInfo) -> String {
+    match (exit.code, exit.signal, &exit.error) {
+        (Some(code), _, _) => format!("exit code {}", code),
+        (_, Some(signal), _) => format!("killed by signal {}", signal),
+        (_, _, Some(e)) => format!("unknown ({})", e),
+        _ => "unknown".to_string(),
+    }
+}
+
+// Whether any process is left in the group. Its id cannot be reused while it has a member.
+fn group_exists(pgid: u32) -> bool {
+    // SAFETY: kill(2) with signal 0 only checks for the target's existence.
+    let result = unsafe { libc::kill(-(pgid as libc::pid_t), 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
@@ -24,7 +24,7 @@ pub async fn connect_to_database(db_url: String) -> Result<Connection, Box<dyn s
     }
 }
 
-pub async fn connect_to_mavlink(
+pub fn connect_to_mavlink(
     connection_string: &str,
 ) -> Result<Box<dyn MavConnection<MavMessage> + Send + Sync>, Box<dyn std::error::Error>> {
     info!(
@@ -0,0 +1,72 @@
+use crate::connection_manager;
+use chrono::Utc;
+use mavlink::uorocketry::MavMessage;
+use mavlink::MavHeader;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{error, info};
+
+/// A message as received on one gateway connection.
+pub struct GatewayFrame {
+    /// Index of the gateway in `--gateway-connection-string`.
+    pub gateway: usize,
+    pub header: MavHeader,
+    pub message: MavMessage,
+    /// UNIX epoch milliseconds at receipt.
+    pub received_at_ms: i64,
+}
+
+/// Reads one gateway on its own thread, reconnecting whenever the connection fails, and
+/// forwards every message to `tx`. Stops once the receiving side is gone.
+pub fn spawn_reader(gateway: usize, connection_string: String, tx: UnboundedSender<GatewayFrame>) {
+    std::thread::spawn(move || loop {
+        let connection = match connection_manager::connect_to_mavlink(&connection_string) {
+            Ok(connection) => connection,
+            Err(_) => {
+                std::thread::sleep(Duration::from_secs(5));
+                continue;
+            }
+        };
+        info!("Getting Messages from {}...", connection_string);
+        loop {
+            match connection.recv() {
+                Ok((header, message)) => {
+                    let frame = GatewayFrame {
+                        gateway,
+                        header,
+                        message,
+                        received_at_ms: Utc::now().timestamp_millis(),
+                    };
+                    if tx.send(frame).is_err() {
+                        return;
+                    }
+                }
+                Err(mavlink::error::MessageReadError::Io(io_err)) => match io_err.kind() {
+                    std::io::ErrorKind::WouldBlock => {}
+                    std::io::ErrorKind::UnexpectedEof => {
+                        error!(
+                            "Connection to {} closed unexpectedly (EOF). Reconnecting...",
+                            connection_string
+                        );
+                        break;
+                    }
+                    _ => {
+                        error!(
+                            "Mavlink IO Error receiving message from {}: {:?}",
+                            connection_string, io_err
+                        );
+                        std::thread::sleep(Duration::from_secs(1));
+                    }
+                },
+                Err(e) => {
+                    error!(
+                        "Mavlink Non-IO Error receiving message from {}: {:?}",
+                        connection_string, e
+                    );
+                    std::thread::sleep(Duration::from_secs(1));
+                }
+            }
+        }
+        std::thread::sleep(Duration::from_secs(5));
+    });
+}
@@ -1,4 +1,5 @@
 mod connection_manager;
+mod gateway;
 mod heartbeat;
 mod message_handler;
 mod savers;
@@ -7,7 +8,6 @@ use clap::Parser;
 use tracing::error;
 use tracing::Level;
 use tracing_subscriber;
-use std::time::Duration;
 
 #[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
@@ -20,10 +20,11 @@ struct Args {
     #[arg(
         long,
         default_value = "tcpout:127.0.0.1:5656",
+        value_delimiter = ',',
         // Renamed parameter in help text
-        help = "Gateway MAVLink connection string (e.g., tcpout:localhost:5656, udpin:0.0.0.0:14550)"
+        help = "Gateway MAVLink connection strings, all read at once (e.g., tcpout:localhost:5656,udpin:0.0.0.0:14550)"
     )]
-    gateway_connection_string: String, // Renamed field
+    gateway_connection_string: Vec<String>, // Renamed field
 }
 
 #[tokio::main]
@@ -44,19 +45,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Establish database connection (retry handled internally by libsql if needed)
     let db_connection = connection_manager::connect_to_database(args.libsql_url).await?;
 
-    // Keep running even if the gateway (e.g., `sergw`) is absent. Retry connect and, if the
-    // message loop exits, attempt to reconnect after a short delay.
-    loop {
-        match connection_manager::connect_to_mavlink(&args.gateway_connection_string).await {
-            Ok(mavlink_connection) => {
-                if let Err(e) = message_handler::handle_messages(mavlink_connection, db_connection.clone()).await {
-                    error!("Message handler exited: {:?}", e);
-                }
-            }
-            Err(e) => {
-                error!("Failed to connect to MAVLink: {:?}", e);
-            }
-        }
-        tokio::time::sleep(Duration::from_secs(5)).await;
-    }
+    // Keep running even if a gateway (e.g., `sergw`) is absent: each gateway is read on its
+    // own thread, which retries the connection until it succeeds.
+    message_handler::handle_messages(args.gateway_connection_string, db_connection).await
 }
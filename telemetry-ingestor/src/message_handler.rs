@@ -1,45 +1,132 @@
+use crate::gateway::{self, GatewayFrame};
 use crate::savers;
 use chrono::Utc;
 use libsql::Connection;
 use mavlink::uorocketry::MavMessage;
-use mavlink::MavConnection;
 // Messages are decoded in batch; no need to parse them here
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
 use tracing::{error, info, warn};
 
 const BATCH_SIZE: usize = 100;
 const BATCH_TIMEOUT: Duration = Duration::from_millis(500);
+/// A frame from one gateway repeating one already taken from another gateway within this
+/// window is a duplicate.
+const DEDUP_WINDOW: Duration = Duration::from_secs(2);
+/// How often each gateway's frame counts are written to `RadioMetrics`.
+const GATEWAY_STATS_INTERVAL: Duration = Duration::from_secs(5);
 
+// Link statistics of one gateway
+struct GatewayStats {
+    name: Arc<str>,
+    last_sequence: Option<u8>,
+    frames_received: i64,
+    frames_duplicate: i64,
+}
+
+// POSTCARD payloads recently taken, by hash, with the gateway they were taken from
+#[derive(Default)]
+struct RecentFrames {
+    seen: HashMap<u64, (usize, Instant)>,
+    order: VecDeque<(u64, Instant)>,
+}
+
+impl RecentFrames {
+    /// Whether `payload` arriving on `gateway` was already taken from another gateway.
+    fn is_duplicate(&mut self, gateway: usize, payload: &[u8], now: Instant) -> bool {
+        while let Some(&(hash, at)) = self.order.front() {
+            if now.duration_since(at) <= DEDUP_WINDOW {
+                break;
+            }
+            self.order.pop_front();
+            if self
+                .seen
+                .get(&hash)
+                .is_some_and(|&(_, seen_at)| seen_at == at)
+            {
+                self.seen.remove(&hash);
+            }
+        }
+        let mut hasher = DefaultHasher::new();
+        payload.hash(&mut hasher);
+        let hash = hasher.finish();
+        // The same gateway repeating a payload is the node sending it twice, not a duplicate.
+        if self
+            .seen
+            .get(&hash)
+            .is_some_and(|&(from, _)| from != gateway)
+        {
+            return true;
+        }
+        self.seen.insert(hash, (gateway, now));
+        self.order.push_back((hash, now));
+        false
+    }
+}
+
+/// Receives from every gateway in `gateway_connection_strings` at once, keeps the first copy
+/// of frames seen on more than one, and saves them in batches. Packet loss, RSSI and frame
+/// counts are recorded in `RadioMetrics` per gateway.
 pub async fn handle_messages(
-    mavlink_connection: Box<dyn MavConnection<MavMessage> + Send + Sync>,
+    gateway_connection_strings: Vec<String>,
     db_connection: Connection,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    info!("Getting Messages...");
-    let mut last_seq_num = 0;
-    let mut message_buffer: Vec<Vec<u8>> = Vec::with_capacity(BATCH_SIZE);
+    let (tx, mut rx) = mpsc::unbounded_channel::<GatewayFrame>();
+    let mut gateways: Vec<GatewayStats> = Vec::new();
+    for (index, connection_string) in gateway_connection_strings.into_iter().enumerate() {
+        gateway::spawn_reader(index, connection_string.clone(), tx.clone());
+        gateways.push(GatewayStats {
+            name: connection_string.into(),
+            last_sequence: None,
+            frames_received: 0,
+            frames_duplicate: 0,
+        });
+    }
+    drop(tx);
+
+    // Each message with its receive time in UNIX epoch milliseconds
+    let mut message_buffer: Vec<(Vec<u8>, i64)> = Vec::with_capacity(BATCH_SIZE);
     let mut last_batch_time = Instant::now();
+    let mut last_stats_time = Instant::now();
+    let mut recent_frames = RecentFrames::default();
 
     loop {
         let db_conn_for_batch = db_connection.clone();
-        let recv_result = mavlink_connection.recv();
-
-        // info!("recv_result: {:?}", recv_result);
+        let recv_result = match tokio::time::timeout(BATCH_TIMEOUT, rx.recv()).await {
+            Ok(Some(frame)) => Some(frame),
+            Ok(None) => {
+                error!("All gateway readers stopped. Shutting down receiver loop.");
+                break;
+            }
+            // Nothing received; still flush the batch and stats below
+            Err(_) => None,
+        };
 
-        match recv_result {
-            Ok((header, message)) => {
-                // info!("Received message: {:?}", header.sequence);
+        if let Some(GatewayFrame {
+            gateway,
+            header,
+            message,
+            received_at_ms,
+        }) = recv_result
+        {
+            let stats = &mut gateways[gateway];
+            if let Some(last_seq_num) = stats.last_sequence {
                 let packets_lost =
                     ((header.sequence as i32) - (last_seq_num as i32) - 1).rem_euclid(256);
-                last_seq_num = header.sequence;
-
                 if packets_lost > 0 {
-                    warn!("Packets Lost: {}", packets_lost);
+                    warn!("Packets Lost on {}: {}", stats.name, packets_lost);
                     let ts = Utc::now().timestamp();
                     let conn_clone = db_connection.clone();
+                    let name = stats.name.clone();
                     tokio::spawn(async move {
                         if let Err(e) = savers::radio::save_radio_metrics(
                             &conn_clone,
                             ts,
+                            &name,
                             None,
                             Some(packets_lost as i64),
                         )
@@ -49,57 +136,44 @@ pub async fn handle_messages(
                         }
                     });
                 }
+            }
+            stats.last_sequence = Some(header.sequence);
 
-                match message {
-                    MavMessage::POSTCARD_MESSAGE(data) => {
+            match message {
+                MavMessage::POSTCARD_MESSAGE(data) => {
+                    stats.frames_received += 1;
+                    if recent_frames.is_duplicate(gateway, &data.message, Instant::now()) {
+                        stats.frames_duplicate += 1;
+                    } else {
                         // Buffer POSTCARD messages for decoding in batch
-                        message_buffer.push(data.message.to_vec());
-                    }
-                    MavMessage::RADIO_STATUS(data) => {
-                        info!("Received radio status: {:?}", data);
-                        let rssi_val = data.rssi as i64;
-                        let ts = Utc::now().timestamp();
-                        let conn_clone = db_connection.clone();
-                        tokio::spawn(async move {
-                            if let Err(e) = savers::radio::save_radio_metrics(
-                                &conn_clone,
-                                ts,
-                                Some(rssi_val),
-                                None,
-                            )
-                            .await
-                            {
-                                error!("Failed to save radio status: {:?}", e);
-                            }
-                        });
-                    }
-                    other => {
-                        error!("Received an unexpected message type {:?}", other);
-                        continue;
-                    }
-                };
-            }
-            Err(e) => match e {
-                mavlink::error::MessageReadError::Io(io_err) => match io_err.kind() {
-                    std::io::ErrorKind::WouldBlock => {}
-                    std::io::ErrorKind::UnexpectedEof => {
-                        error!(
-                            "Connection closed unexpectedly (EOF). Shutting down receiver loop."
-                        );
-                        break;
+                        message_buffer.push((data.message.to_vec(), received_at_ms));
                     }
-                    _ => {
-                        error!("Mavlink IO Error receiving message: {:?}", io_err);
-                        tokio::time::sleep(Duration::from_secs(1)).await;
-                        continue;
-                    }
-                },
-                e => {
-                    error!("Mavlink Non-IO Error receiving message: {:?}", e);
-                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+                MavMessage::RADIO_STATUS(data) => {
+                    info!("Received radio status on {}: {:?}", stats.name, data);
+                    let rssi_val = data.rssi as i64;
+                    let ts = Utc::now().timestamp();
+                    let conn_clone = db_connection.clone();
+                    let name = stats.name.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = savers::radio::save_radio_metrics(
+                            &conn_clone,
+                            ts,
+                            &name,
+                            Some(rssi_val),
+                            None,
+                        )
+                        .await
+                        {
+                            error!("Failed to save radio status: {:?}", e);
+                        }
+                    });
+                }
+                other => {
+                    error!("Received an unexpected message type {:?}", other);
                     continue;
                 }
-            },
+            };
         }
 
         let should_save_batch = !message_buffer.is_empty()
@@ -120,6 +194,31 @@ pub async fn handle_messages(
             });
             message_buffer.reserve(BATCH_SIZE);
         }
+
+        if last_stats_time.elapsed() >= GATEWAY_STATS_INTERVAL {
+            last_stats_time = Instant::now();
+            let ts = Utc::now().timestamp();
+            for stats in gateways.iter_mut() {
+                let (received, duplicate) = (stats.frames_received, stats.frames_duplicate);
+                stats.frames_received = 0;
+                stats.frames_duplicate = 0;
+                let conn_clone = db_connection.clone();
+                let name = stats.name.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = savers::radio::save_gateway_frames(
+                        &conn_clone,
+                        ts,
+                        &name,
+                        received,
+                        duplicate,
+                    )
+                    .await
+                    {
+                        error!("Failed to save gateway frame counts: {:?}", e);
+                    }
+                });
+            }
+        }
     }
 
     Ok(())
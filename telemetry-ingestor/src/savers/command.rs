@@ -125,3 +125,164 @@ pub async fn save_command(transaction: &Transaction, command: &cmd::Command) ->
 
     Ok(command_id)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cmd::command::Data;
+    use libsql::{Builder, Connection, Value};
+    use messages_prost::common::Node;
+    use messages_prost::radio::{radio_frame::Payload, RadioFrame};
+    use prost::Message as _;
+
+    // Every variant, so a new one cannot go without a case below
+    fn data_type(data: &Data) -> &'static str {
+        match data {
+            Data::DeployDrogue(_) => "DeployDrogue",
+            Data::DeployMain(_) => "DeployMain",
+            Data::PowerDown(_) => "PowerDown",
+            Data::PowerUpCamera(_) => "PowerUpCamera",
+            Data::PowerDownCamera(_) => "PowerDownCamera",
+            Data::RadioRateChange(_) => "RadioRateChange",
+            Data::Online(_) => "Online",
+            Data::Ping(_) => "Ping",
+            Data::Pong(_) => "Pong",
+        }
+    }
+
+    const DATA_TYPES: [&str; 9] = [
+        "DeployDrogue",
+        "DeployMain",
+        "PowerDown",
+        "PowerUpCamera",
+        "PowerDownCamera",
+        "RadioRateChange",
+        "Online",
+        "Ping",
+        "Pong",
+    ];
+
+    // A command, the subtype column it is stored in, and the stored value
+    fn cases() -> Vec<(Data, &'static str, Value)> {
+        vec![
+            (
+                Data::DeployDrogue(cmd::DeployDrogue { val: true }),
+                "val",
+                Value::Integer(1),
+            ),
+            (
+                Data::DeployMain(cmd::DeployMain { val: false }),
+                "val",
+                Value::Integer(0),
+            ),
+            (
+                Data::PowerDown(cmd::PowerDown {
+                    board: Node::StrainBoard as i32,
+                }),
+                "board",
+                Value::Text("StrainBoard".to_string()),
+            ),
+            (
+                Data::PowerUpCamera(cmd::PowerUpCamera {}),
+                "id",
+                Value::Integer(1),
+            ),
+            (
+                Data::PowerDownCamera(cmd::PowerDownCamera {}),
+                "id",
+                Value::Integer(1),
+            ),
+            (
+                Data::RadioRateChange(cmd::RadioRateChange {
+                    rate: cmd::RadioRate::RateMedium as i32,
+                }),
+                "rate",
+                Value::Text("RateMedium".to_string()),
+            ),
+            (
+                Data::Online(cmd::Online { online: true }),
+                "online",
+                Value::Integer(1),
+            ),
+            (
+                Data::Ping(cmd::Ping { id: u32::MAX }),
+                "ping_id",
+                Value::Integer(u32::MAX as i64),
+            ),
+            (
+                Data::Pong(cmd::Pong { id: 42 }),
+                "pong_id",
+                Value::Integer(42),
+            ),
+        ]
+    }
+
+    async fn database() -> Connection {
+        let db = Builder::new_local(":memory:").build().await.unwrap();
+        let conn = db.connect().unwrap();
+        conn.execute_batch(include_str!("../../../db/seed.sql"))
+            .await
+            .unwrap();
+        conn
+    }
+
+    // As the dispatcher frames an uplink command
+    fn uplink_bytes(data: Data) -> Vec<u8> {
+        RadioFrame::encode_length_delimited_to_vec(&RadioFrame {
+            node: Node::GroundStation as i32,
+            payload: Some(Payload::Command(cmd::Command {
+                node: Node::Phoenix as i32,
+                data: Some(data),
+            })),
+            millis_since_start: 0,
+        })
+    }
+
+    #[tokio::test]
+    async fn every_command_is_saved_with_its_fields() {
+        let cases = cases();
+        for expected in DATA_TYPES {
+            assert!(
+                cases.iter().any(|(data, _, _)| data_type(data) == expected),
+                "no case for {}",
+                expected
+            );
+        }
+
+        let conn = database().await;
+        for (data, column, value) in cases {
+            let bytes = uplink_bytes(data);
+            let Some(Payload::Command(command)) = RadioFrame::decode_length_delimited(&bytes[..])
+                .unwrap()
+                .payload
+            else {
+                panic!("{:?} did not decode as a command", data);
+            };
+            let transaction = conn.transaction().await.unwrap();
+            let command_id = save_command(&transaction, &command).await.unwrap();
+            transaction.commit().await.unwrap();
+
+            let mut rows = conn
+                .query(
+                    "SELECT data_type, data_id FROM Command WHERE id = ?",
+                    params![command_id],
+                )
+                .await
+                .unwrap();
+            let row = rows.next().await.unwrap().unwrap();
+            let stored_type: String = row.get(0).unwrap();
+            let data_id: i64 = row.get(1).unwrap();
+            assert_eq!(stored_type, data_type(&data));
+
+            let mut rows = conn
+                .query(
+                    &format!("SELECT {} FROM {} WHERE id = ?", column, stored_type),
+                    params![data_id],
+                )
+                .await
+                .unwrap();
+            let row = rows.next().await.unwrap().unwrap();
+            assert_eq!(row.get_value(0).unwrap(), value, "{}", stored_type);
+        }
+    }
+}
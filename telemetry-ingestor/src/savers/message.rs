@@ -19,12 +19,19 @@ use super::{
     state::{save_argus_state, save_phoenix_state},
 };
 
+// When a frame was produced on the node and when it reached the ground station
+#[derive(Clone, Copy)]
+struct FrameTiming {
+    millis_since_start: Option<i64>,
+    received_at_ms: i64,
+}
+
 async fn insert_radio_message(
     transaction: &Transaction,
     node: i32,
     data_type: &str,
     data_id: i64,
-    millis_since_start: Option<i64>,
+    timing: FrameTiming,
 ) -> Result<i64> {
     let time_str = Utc::now().to_rfc3339();
     let time_epoch = Utc::now().timestamp();
@@ -34,8 +41,8 @@ async fn insert_radio_message(
 
     transaction
         .execute(
-            "INSERT INTO RadioFrame (timestamp, timestamp_epoch, node, data_type, data_id, millis_since_start) VALUES (?, ?, ?, ?, ?, ?)",
-            params![time_str, time_epoch, node_name, data_type, data_id, millis_since_start],
+            "INSERT INTO RadioFrame (timestamp, timestamp_epoch, node, data_type, data_id, millis_since_start, received_at_ms) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            params![time_str, time_epoch, node_name, data_type, data_id, timing.millis_since_start, timing.received_at_ms],
         )
         .await?;
     Ok(transaction.last_insert_rowid())
@@ -43,7 +50,7 @@ async fn insert_radio_message(
 
 pub async fn save_messages_batch(
     db_connection: &Connection,
-    message_bytes_list: Vec<Vec<u8>>,
+    message_bytes_list: Vec<(Vec<u8>, i64)>,
 ) -> Result<()> {
     if message_bytes_list.is_empty() {
         return Ok(());
@@ -55,15 +62,18 @@ pub async fn save_messages_batch(
         message_bytes_list.len()
     );
 
-    for message_bytes in message_bytes_list.iter() {
+    for (message_bytes, received_at_ms) in message_bytes_list.iter() {
         match RadioFrame::decode_length_delimited(&message_bytes[..]) {
             Ok(frame) => {
                 let node = frame.node;
-                let millis = Some(frame.millis_since_start as i64);
+                let timing = FrameTiming {
+                    millis_since_start: Some(frame.millis_since_start as i64),
+                    received_at_ms: *received_at_ms,
+                };
                 match frame.payload {
                     Some(radio::radio_frame::Payload::Barometer(m)) => {
                         let data_id = save_barometer(&transaction, &m).await?;
-                        insert_radio_message(&transaction, node, "Barometer", data_id, millis)
+                        insert_radio_message(&transaction, node, "Barometer", data_id, timing)
                             .await?;
                     }
                     Some(radio::radio_frame::Payload::Sbg(sbg)) => {
@@ -78,7 +88,7 @@ pub async fn save_messages_batch(
                                 sbg_data::Data::Air(_) => "SbgAir",
                             };
                             let data_id = save_sbg(&transaction, &sbg).await?;
-                            insert_radio_message(&transaction, node, data_type, data_id, millis)
+                            insert_radio_message(&transaction, node, data_type, data_id, timing)
                                 .await?;
                         }
                     }
@@ -90,40 +100,40 @@ pub async fn save_messages_batch(
                     }
                     Some(radio::radio_frame::Payload::Madgwick(m)) => {
                         let data_id = save_madgwick(&transaction, &m).await?;
-                        insert_radio_message(&transaction, node, "Madgwick", data_id, millis)
+                        insert_radio_message(&transaction, node, "Madgwick", data_id, timing)
                             .await?;
                     }
                     Some(radio::radio_frame::Payload::Iim20670(m)) => {
                         let data_id = save_imu(&transaction, &m).await?;
-                        insert_radio_message(&transaction, node, "Imu", data_id, millis).await?;
+                        insert_radio_message(&transaction, node, "Imu", data_id, timing).await?;
                     }
                     Some(radio::radio_frame::Payload::Log(m)) => {
                         let data_id = save_log(&transaction, &m).await?;
-                        insert_radio_message(&transaction, node, "Log", data_id, millis).await?;
+                        insert_radio_message(&transaction, node, "Log", data_id, timing).await?;
                     }
                     Some(radio::radio_frame::Payload::PhoenixState(s)) => {
                         let data_id = save_phoenix_state(&transaction, s).await?;
-                        insert_radio_message(&transaction, node, "PhoenixState", data_id, millis)
+                        insert_radio_message(&transaction, node, "PhoenixState", data_id, timing)
                             .await?;
                     }
                     Some(radio::radio_frame::Payload::PhoenixEvent(e)) => {
                         let data_id = save_phoenix_event(&transaction, e).await?;
-                        insert_radio_message(&transaction, node, "PhoenixEvent", data_id, millis)
+                        insert_radio_message(&transaction, node, "PhoenixEvent", data_id, timing)
                             .await?;
                     }
                     Some(radio::radio_frame::Payload::ArgusState(s)) => {
                         let data_id = save_argus_state(&transaction, s).await?;
-                        insert_radio_message(&transaction, node, "ArgusState", data_id, millis)
+                        insert_radio_message(&transaction, node, "ArgusState", data_id, timing)
                             .await?;
                     }
                     Some(radio::radio_frame::Payload::ArgusEvent(e)) => {
                         let data_id = save_argus_event(&transaction, e).await?;
-                        insert_radio_message(&transaction, node, "ArgusEvent", data_id, millis)
+                        insert_radio_message(&transaction, node, "ArgusEvent", data_id, timing)
                             .await?;
                     }
                     Some(radio::radio_frame::Payload::ArgusPressure(m)) => {
                         let data_id = save_argus_pressure(&transaction, &m).await?;
-                        insert_radio_message(&transaction, node, "ArgusPressure", data_id, millis)
+                        insert_radio_message(&transaction, node, "ArgusPressure", data_id, timing)
                             .await?;
                     }
                     Some(radio::radio_frame::Payload::ArgusTemperature(m)) => {
@@ -133,18 +143,18 @@ pub async fn save_messages_batch(
                             node,
                             "ArgusTemperature",
                             data_id,
-                            millis,
+                            timing,
                         )
                         .await?;
                     }
                     Some(radio::radio_frame::Payload::ArgusStrain(m)) => {
                         let data_id = save_argus_strain(&transaction, &m).await?;
-                        insert_radio_message(&transaction, node, "ArgusStrain", data_id, millis)
+                        insert_radio_message(&transaction, node, "ArgusStrain", data_id, timing)
                             .await?;
                     }
                     Some(radio::radio_frame::Payload::Command(m)) => {
                         let data_id = save_command(&transaction, &m).await?;
-                        insert_radio_message(&transaction, node, "Command", data_id, millis)
+                        insert_radio_message(&transaction, node, "Command", data_id, timing)
                             .await?;
                     }
                     None => {
@@ -3,12 +3,30 @@ use libsql::{params, Connection, Result};
 pub async fn save_radio_metrics(
     conn: &Connection,
     timestamp: i64,
+    gateway: &str,
     rssi: Option<i64>,
     packets_lost: Option<i64>,
 ) -> Result<()> {
     conn.execute(
-        "INSERT INTO RadioMetrics (timestamp, rssi, packets_lost) VALUES (?, ?, ?)",
-        params![timestamp, rssi, packets_lost],
+        "INSERT INTO RadioMetrics (timestamp, gateway, rssi, packets_lost) VALUES (?, ?, ?, ?)",
+        params![timestamp, gateway, rssi, packets_lost],
+    )
+    .await?;
+    Ok(())
+}
+
+/// Frames one gateway delivered since its last row, and how many of them another gateway
+/// had already delivered.
+pub async fn save_gateway_frames(
+    conn: &Connection,
+    timestamp: i64,
+    gateway: &str,
+    frames_received: i64,
+    frames_duplicate: i64,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO RadioMetrics (timestamp, gateway, frames_received, frames_duplicate) VALUES (?, ?, ?, ?)",
+        params![timestamp, gateway, frames_received, frames_duplicate],
     )
     .await?;
     Ok(())
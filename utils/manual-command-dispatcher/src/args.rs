@@ -11,6 +11,20 @@ pub struct Args {
     )]
     pub gateway_connection_string: String,
 
+    #[arg(
+        long,
+        default_value_t = 255,
+        help = "MAVLink system id of outbound frames"
+    )]
+    pub mavlink_system_id: u8,
+
+    #[arg(
+        long,
+        default_value_t = 191,
+        help = "MAVLink component id of outbound frames"
+    )]
+    pub mavlink_component_id: u8,
+
     #[arg(long, default_value_t = Node::GroundStation as i32, help = "Default command origin node as numeric enum value")]
     pub origin_node: i32,
 
@@ -22,7 +22,7 @@ use std::io;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use tracing::{error, info};
-use transport::{spawn_receiver, spawn_sender};
+use transport::{spawn_receiver, spawn_sender, POSTCARD_PAYLOAD_LEN};
 
 // state, args, and menu items moved to modules
 
@@ -58,7 +58,11 @@ async fn main() -> Result<(), Box<dyn Error>> {
     );
 
     // Spawn IO threads so UI is not blocked
-    let (tx_out, _send_handle) = spawn_sender(args.gateway_connection_string.clone());
+    let (tx_out, _send_handle) = spawn_sender(
+        args.gateway_connection_string.clone(),
+        args.mavlink_system_id,
+        args.mavlink_component_id,
+    );
     let (rx_in, _recv_handle) = spawn_receiver(args.gateway_connection_string.clone());
 
     // Setup TUI
@@ -120,6 +124,21 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
                         let bytes = encode_message(&frame);
                         info!("Encoded command {:?} into {} bytes", item, bytes.len());
+                        if bytes.len() > POSTCARD_PAYLOAD_LEN {
+                            error!(
+                                "Frame for {:?} is {} bytes, exceeds the {}-byte POSTCARD payload",
+                                item,
+                                bytes.len(),
+                                POSTCARD_PAYLOAD_LEN
+                            );
+                            state.left_log_lines.push(format!(
+                                "Not sent: {:?} is {} bytes (max {})",
+                                item,
+                                bytes.len(),
+                                POSTCARD_PAYLOAD_LEN
+                            ));
+                            continue;
+                        }
                         info!("Queueing frame to sender thread...");
                         let send_len = bytes.len();
                         match tx_out.send(bytes) {
@@ -5,21 +5,55 @@ use std::sync::mpsc;
 use std::thread;
 use tracing::{debug, error, info, warn};
 
+/// Size of the fixed `message` buffer in a MAVLink `POSTCARD_MESSAGE`.
+pub const POSTCARD_PAYLOAD_LEN: usize = 255;
+
+/// Identity stamped on outbound frames, with the sequence counter of the current connection.
+#[derive(Debug, Clone, Copy)]
+pub struct UplinkHeader {
+    pub system_id: u8,
+    pub component_id: u8,
+    pub sequence: u8,
+}
+
+impl UplinkHeader {
+    /// Header for the next frame. Advances the sequence, wrapping at 255.
+    fn next(&mut self) -> MavHeader {
+        let header = MavHeader {
+            system_id: self.system_id,
+            component_id: self.component_id,
+            sequence: self.sequence,
+        };
+        self.sequence = self.sequence.wrapping_add(1);
+        header
+    }
+}
+
 pub fn send_over_mavlink(
     conn: &mut Box<dyn MavConnection<MavMessage> + Send + Sync>,
+    header: &MavHeader,
     bytes: &[u8],
 ) -> Result<(), Box<dyn Error>> {
-    let mut fixed = [0u8; 255];
-    let len = bytes.len().min(255);
-    fixed[..len].copy_from_slice(&bytes[..len]);
+    if bytes.len() > POSTCARD_PAYLOAD_LEN {
+        return Err(format!(
+            "frame is {} bytes, exceeds the {}-byte POSTCARD payload",
+            bytes.len(),
+            POSTCARD_PAYLOAD_LEN
+        )
+        .into());
+    }
+    let mut fixed = [0u8; POSTCARD_PAYLOAD_LEN];
+    fixed[..bytes.len()].copy_from_slice(bytes);
     let msg =
         MavMessage::POSTCARD_MESSAGE(mavlink::uorocketry::POSTCARD_MESSAGE_DATA { message: fixed });
-    conn.send(&MavHeader::default(), &msg)
-        .map(|_| ())
-        .map_err(|e| e.into())
+    conn.send(header, &msg).map(|_| ()).map_err(|e| e.into())
 }
 
-pub fn spawn_sender(connection_string: String) -> (mpsc::Sender<Vec<u8>>, thread::JoinHandle<()>) {
+pub fn spawn_sender(
+    connection_string: String,
+    system_id: u8,
+    component_id: u8,
+) -> (mpsc::Sender<Vec<u8>>, thread::JoinHandle<()>) {
     let (tx, rx) = mpsc::channel::<Vec<u8>>();
     let handle = thread::spawn(move || {
         loop {
@@ -34,14 +68,31 @@ pub fn spawn_sender(connection_string: String) -> (mpsc::Sender<Vec<u8>>, thread
             };
 
             info!("Sender connected. Waiting for outbound frames...");
+            // Each connection numbers its frames from 0
+            let mut uplink = UplinkHeader {
+                system_id,
+                component_id,
+                sequence: 0,
+            };
             // Drain and send until error or channel closed
             loop {
                 match rx.recv() {
                     Ok(bytes) => {
-                        if let Err(e) = send_over_mavlink(&mut conn, &bytes) {
-                            error!("Sender send error: {}. Reconnecting...", e);
+                        let header = uplink.next();
+                        if let Err(e) = send_over_mavlink(&mut conn, &header, &bytes) {
+                            error!(
+                                "Sender send error (sequence {}): {}. Reconnecting...",
+                                header.sequence, e
+                            );
                             break; // reconnect
                         }
+                        info!(
+                            "Sent frame: system {}, component {}, sequence {} ({} bytes)",
+                            header.system_id,
+                            header.component_id,
+                            header.sequence,
+                            bytes.len()
+                        );
                     }
                     Err(_) => {
                         info!("Sender channel closed. Exiting sender thread.");
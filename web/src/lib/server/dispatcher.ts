@@ -0,0 +1,14 @@
+const TRIGGER_URL = process.env.COMMAND_DISPATCHER_TRIGGER_URL; // e.g. http://command-dispatcher:5657/trigger
+
+/**
+ * Wakes the command dispatcher so a newly queued command is sent immediately.
+ * Best effort: if the call fails, the dispatcher's fallback poll still picks the command up.
+ */
+export async function notifyDispatcher(): Promise<void> {
+	if (!TRIGGER_URL) return;
+	try {
+		await fetch(TRIGGER_URL, { method: 'POST', signal: AbortSignal.timeout(500) });
+	} catch (e) {
+		console.warn('Failed to notify command dispatcher:', e);
+	}
+}
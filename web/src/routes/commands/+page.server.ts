@@ -1,5 +1,7 @@
 // This file will handle loading existing commands and processing the dispatch form action.
+import { randomInt } from 'node:crypto';
 import { getDbClient } from '$lib/server/db';
+import { notifyDispatcher } from '$lib/server/dispatcher';
 import { fail } from '@sveltejs/kit';
 import type { Actions, PageServerLoad } from './$types';
 
@@ -53,6 +55,13 @@ export const actions: Actions = {
 					// These commands might not need user parameters, set defaults if needed
 					params.val = true; // Example default
 					break;
+				case 'Online':
+					const online = formData.get('param_online') as string;
+					if (online !== 'true' && online !== 'false') {
+						throw new Error('Online parameter must be true or false.');
+					}
+					params.online = online === 'true';
+					break;
 				case 'PowerDown':
 					const board = formData.get('param_board') as string;
 					if (!board) throw new Error('Board parameter is required for PowerDown.');
@@ -70,7 +79,8 @@ export const actions: Actions = {
 					params.rate = rate;
 					break;
 				case 'Ping':
-					// No parameters needed
+					// The dispatcher matches the Pong by this id
+					params.id = randomInt(2 ** 32);
 					break;
 				default:
 					return fail(400, { error: `Unknown command type: ${command_type}` });
@@ -93,6 +103,7 @@ export const actions: Actions = {
 			});
 
 			console.log('Command inserted successfully.');
+			await notifyDispatcher();
 			return { success: true, message: `Command '${command_type}' dispatched.` };
 
 		} catch (e: any) {
@@ -101,6 +112,46 @@ export const actions: Actions = {
 		}
 	},
 
+	// Action to cancel a command the dispatcher has not sent yet
+	cancel: async ({ request }) => {
+		const formData = await request.formData();
+		const idValue = formData.get('id');
+
+		if (!idValue || typeof idValue !== 'string') {
+			return fail(400, { error: 'Invalid or missing command ID for cancellation.' });
+		}
+		const id = idValue as string;
+
+		const db = getDbClient();
+		try {
+			console.log(`Cancelling command with ID: ${id}`);
+			// Audit the transition and apply it atomically; the event is only written if the update applies.
+			const [, result] = await db.batch(
+				[
+					{
+						sql: "INSERT INTO CommandEvent (command_id, at_ms, from_status, to_status, attempt, operator, source_service, error_message, recorded_by) SELECT id, ?, status, 'Cancelled', attempts, operator, source_service, 'Cancelled by operator', 'rgs-web-commands' FROM OutgoingCommand WHERE id = ? AND status IN ('Pending', 'AwaitingConfirmation')",
+						args: [Date.now(), id]
+					},
+					{
+						sql: "UPDATE OutgoingCommand SET status = 'Cancelled', error_message = 'Cancelled by operator' WHERE id = ? AND status IN ('Pending', 'AwaitingConfirmation')",
+						args: [id]
+					}
+				],
+				'write'
+			);
+
+			if (result.rowsAffected === 0) {
+				return fail(409, { error: `Command ${id} is no longer pending and cannot be cancelled.` });
+			}
+
+			console.log(`Command ${id} cancelled.`);
+			return { success: true, message: `Command ${id} cancelled.` };
+		} catch (e: any) {
+			console.error(`Error cancelling command ${id}:`, e);
+			return fail(500, { error: e.message || `Failed to cancel command ${id}.` });
+		}
+	},
+
 	// Action to delete a single command
 	deleteSingle: async ({ request }) => {
 		const formData = await request.formData();
@@ -10,7 +10,7 @@ async function pollAndBroadcast() {
     const db = getDbClient();
     try {
         const metricsResult = await db.execute({
-            sql: `SELECT timestamp, rssi, packets_lost FROM RadioMetrics ORDER BY timestamp DESC LIMIT 20`,
+            sql: `SELECT timestamp, rssi, packets_lost, gateway FROM RadioMetrics WHERE frames_received IS NULL ORDER BY timestamp DESC LIMIT 20`,
             args: []
         });
         // Throughput: frames per second over last 10 seconds
@@ -98,7 +98,7 @@ export const GET: RequestHandler = async ({ url, request }) => {
     // Regular GET request
     const db = getDbClient();
     const metricsResult = await db.execute({
-        sql: `SELECT timestamp, rssi, packets_lost FROM RadioMetrics ORDER BY timestamp DESC LIMIT 20`,
+        sql: `SELECT timestamp, rssi, packets_lost, gateway FROM RadioMetrics WHERE frames_received IS NULL ORDER BY timestamp DESC LIMIT 20`,
         args: []
     });
 